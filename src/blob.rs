@@ -19,7 +19,7 @@ pub enum AudioFormat {
 impl AudioFormat {
     fn from_header(data: &[u8]) -> Self {
         match data {
-            d if d.starts_with(&[0xFF, 0xFB]) => Self::Mp3,
+            [0xFF, 0xFB, ..] => Self::Mp3,
             d if d.starts_with(b"RIFF") => Self::Wav,
             d if d.starts_with(b"fLaC") => Self::Flac,
             d if d.starts_with(b"OggS") => Self::Ogg,
@@ -41,7 +41,7 @@ impl AudioFormat {
         }
     }
 
-    fn mime_type(&self) -> &'static str {
+    pub fn mime_type(&self) -> &'static str {
         match self {
             Self::Mp3 => "audio/mpeg",
             Self::Wav => "audio/wav",
@@ -53,6 +53,14 @@ impl AudioFormat {
         }
     }
 
+    /// The FFmpeg muxer writing this format.
+    pub fn muxer(&self) -> &'static str {
+        match self {
+            Self::M4a => "ipod",
+            other => other.extension(),
+        }
+    }
+
     pub fn extension(&self) -> &'static str {
         match self {
             Self::Mp3 => "mp3",
@@ -108,7 +116,7 @@ impl<'de> Deserialize<'de> for AudioFormat {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AudioBuffer {
     data: Bytes,
     format: AudioFormat,
@@ -120,9 +128,9 @@ impl AsRef<[u8]> for AudioBuffer {
     }
 }
 
-impl Into<Bytes> for AudioBuffer {
-    fn into(self) -> Bytes {
-        self.data
+impl From<AudioBuffer> for Bytes {
+    fn from(buffer: AudioBuffer) -> Self {
+        buffer.data
     }
 }
 
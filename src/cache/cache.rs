@@ -1,24 +1,55 @@
 use axum::async_trait;
-use color_eyre::Result;
-use std::time::Duration;
+use bytes::{BufMut, Bytes, BytesMut};
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
 
 use crate::config::{CacheSettings, FilesystemCacheSettings};
 
-use super::{fs::FileSystemCache, redis::RedisCache};
+use super::{
+    fs::{CacheLimits, FileSystemCache},
+    memory::MemoryCache,
+    redis::RedisCache,
+    tiered::TieredCache,
+};
 
 #[derive(Debug, Clone)]
 pub enum Cache {
     Redis(RedisCache),
     Filesystem(FileSystemCache),
+    InMemory(MemoryCache),
+    Tiered(TieredCache),
 }
 
 impl Cache {
-    pub fn new(config: CacheSettings) -> Result<Self> {
+    pub fn new(config: CacheSettings, limits: CacheLimits) -> Result<Self> {
         match config {
             CacheSettings::Redis { uri } => Ok(Cache::Redis(RedisCache::new(&uri)?)),
-            CacheSettings::Filesystem(FilesystemCacheSettings { base_dir }) => {
-                Ok(Cache::Filesystem(FileSystemCache::new(base_dir)?))
-            }
+            CacheSettings::Filesystem(FilesystemCacheSettings { base_dir, .. }) => Ok(
+                Cache::Filesystem(FileSystemCache::with_limits(base_dir, limits)?),
+            ),
+            CacheSettings::InMemory => Ok(Cache::InMemory(MemoryCache::new(limits.max_memory))),
+            CacheSettings::Tiered(backend) => match *backend {
+                CacheSettings::InMemory | CacheSettings::Tiered(_) => {
+                    Err(eyre!("a tiered cache needs a Redis or Filesystem backend"))
+                }
+                backend => Ok(Cache::Tiered(TieredCache::new(
+                    MemoryCache::new(limits.max_memory),
+                    Cache::new(backend, limits)?,
+                ))),
+            },
+        }
+    }
+
+    /// Starts sweeping the filesystem cache in the background. Redis expires
+    /// entries itself, and memory is bounded as it is written, so there is
+    /// nothing to do for them.
+    pub fn spawn_sweeper(&self, interval: Duration) -> Option<JoinHandle<()>> {
+        match self {
+            Cache::Filesystem(cache) if !interval.is_zero() => Some(cache.spawn_sweeper(interval)),
+            Cache::Tiered(cache) => cache.backend().spawn_sweeper(interval),
+            _ => None,
         }
     }
 }
@@ -28,6 +59,78 @@ pub trait AudioCache: Send + Sync {
     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
     async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()>;
     async fn delete(&self, key: &str) -> Result<()>;
+
+    /// Gets a cached response. Values that aren't an entry, e.g. ones
+    /// written by older versions, are treated as misses.
+    async fn get_entry(&self, key: &str) -> Result<Option<CacheEntry>> {
+        Ok(self
+            .get(key)
+            .await?
+            .and_then(|value| CacheEntry::decode(value.into())))
+    }
+
+    async fn set_entry(&self, key: &str, entry: &CacheEntry, ttl: Option<Duration>) -> Result<()> {
+        self.set(key, &entry.encode(), ttl).await
+    }
+}
+
+const ENTRY_MAGIC: &[u8; 4] = b"CPE1";
+
+/// A cached response: the body along with what's needed to serve it the way
+/// it was first served.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub status: u16,
+    pub content_type: String,
+    /// Response headers worth replaying, e.g. `ETag` and `Cache-Control`
+    pub headers: Vec<(String, String)>,
+    /// Seconds since the Unix epoch
+    pub created_at: u64,
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+impl CacheEntry {
+    pub fn new(
+        status: u16,
+        content_type: String,
+        headers: Vec<(String, String)>,
+        body: Bytes,
+    ) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Self {
+            status,
+            content_type,
+            headers,
+            created_at,
+            body,
+        }
+    }
+
+    /// Lays the entry out as the magic bytes, the length of the JSON
+    /// header, the header and then the body.
+    pub fn encode(&self) -> Bytes {
+        let header = serde_json::to_vec(self).expect("cache entry header is serializable");
+        let mut buf =
+            BytesMut::with_capacity(ENTRY_MAGIC.len() + 4 + header.len() + self.body.len());
+        buf.put_slice(ENTRY_MAGIC);
+        buf.put_u32(header.len() as u32);
+        buf.put_slice(&header);
+        buf.put_slice(&self.body);
+        buf.freeze()
+    }
+
+    pub fn decode(value: Bytes) -> Option<Self> {
+        let rest = value.strip_prefix(ENTRY_MAGIC)?;
+        let header_len = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?) as usize;
+        let header = rest.get(4..4 + header_len)?;
+
+        let mut entry: CacheEntry = serde_json::from_slice(header).ok()?;
+        entry.body = value.slice(ENTRY_MAGIC.len() + 4 + header_len..);
+        Some(entry)
+    }
 }
 
 #[async_trait]
@@ -36,6 +139,8 @@ impl AudioCache for Cache {
         match self {
             Cache::Redis(cache) => cache.get(key).await,
             Cache::Filesystem(cache) => cache.get(key).await,
+            Cache::InMemory(cache) => cache.get(key).await,
+            Cache::Tiered(cache) => cache.get(key).await,
         }
     }
 
@@ -43,6 +148,8 @@ impl AudioCache for Cache {
         match self {
             Cache::Redis(cache) => cache.set(key, value, ttl).await,
             Cache::Filesystem(cache) => cache.set(key, value, ttl).await,
+            Cache::InMemory(cache) => cache.set(key, value, ttl).await,
+            Cache::Tiered(cache) => cache.set(key, value, ttl).await,
         }
     }
 
@@ -50,6 +157,56 @@ impl AudioCache for Cache {
         match self {
             Cache::Redis(cache) => cache.delete(key).await,
             Cache::Filesystem(cache) => cache.delete(key).await,
+            Cache::InMemory(cache) => cache.delete(key).await,
+            Cache::Tiered(cache) => cache.delete(key).await,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fs::FileSystemCache;
+
+    fn entry() -> CacheEntry {
+        CacheEntry::new(
+            200,
+            "audio/ogg".to_string(),
+            vec![("etag".to_string(), "\"abc\"".to_string())],
+            Bytes::from_static(b"OggS"),
+        )
+    }
+
+    #[test]
+    fn test_entry_round_trip() {
+        let entry = entry();
+        assert_eq!(CacheEntry::decode(entry.encode()), Some(entry));
+    }
+
+    #[test]
+    fn test_decode_rejects_other_values() {
+        assert_eq!(CacheEntry::decode(Bytes::from_static(b"OggS")), None);
+        assert_eq!(
+            CacheEntry::decode(Bytes::from_static(b"CPE1\0\0\0\xff{}")),
+            None
+        );
+
+        let mut truncated = entry().encode().to_vec();
+        truncated.truncate(10);
+        assert_eq!(CacheEntry::decode(truncated.into()), None);
+    }
+
+    #[tokio::test]
+    async fn test_entries_through_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileSystemCache::new(dir.path()).unwrap();
+
+        let entry = entry();
+        cache.set_entry("key", &entry, None).await.unwrap();
+        assert_eq!(cache.get_entry("key").await.unwrap(), Some(entry));
+
+        // Raw values from before entries existed are misses
+        cache.set("raw", b"OggS", None).await.unwrap();
+        assert_eq!(cache.get_entry("raw").await.unwrap(), None);
+    }
+}
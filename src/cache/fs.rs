@@ -2,21 +2,81 @@ use super::cache::AudioCache;
 use axum::async_trait;
 use color_eyre::Result;
 use std::fs;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::time::{Duration, SystemTime};
 use tokio::fs as tokio_fs;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use crate::config::ProcessorSettings;
+
+const META_EXTENSION: &str = ".meta";
+
+/// Memory used by in-memory caches when `max_cache_mem` isn't set.
+const DEFAULT_MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// Bounds on what the caches keep. For the filesystem cache zero means
+/// unbounded; memory is always bounded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheLimits {
+    pub max_files: usize,
+    pub max_bytes: u64,
+    pub max_memory: usize,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self {
+            max_files: 0,
+            max_bytes: 0,
+            max_memory: DEFAULT_MAX_MEMORY,
+        }
+    }
+}
+
+impl From<&ProcessorSettings> for CacheLimits {
+    fn from(settings: &ProcessorSettings) -> Self {
+        Self {
+            max_files: settings.max_cache_files.max(0) as usize,
+            max_bytes: settings.max_cache_size.max(0) as u64 * 1024 * 1024,
+            max_memory: match settings.max_cache_mem {
+                mb if mb > 0 => mb as usize * 1024 * 1024,
+                _ => DEFAULT_MAX_MEMORY,
+            },
+        }
+    }
+}
+
+/// What a sweep removed.
+#[derive(Debug, Default, PartialEq)]
+pub struct SweepStats {
+    pub expired: usize,
+    pub evicted: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct FileSystemCache {
     base_path: PathBuf,
+    limits: CacheLimits,
+}
+
+struct CachedFile {
+    path: PathBuf,
+    size: u64,
+    last_used: SystemTime,
 }
 
 impl FileSystemCache {
     pub fn new<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        Self::with_limits(base_path, CacheLimits::default())
+    }
+
+    pub fn with_limits<P: AsRef<Path>>(base_path: P, limits: CacheLimits) -> Result<Self> {
         let base_path = base_path.as_ref().to_path_buf();
         // Create directory if it doesn't exist
         fs::create_dir_all(&base_path)?;
-        Ok(FileSystemCache { base_path })
+        Ok(FileSystemCache { base_path, limits })
     }
 
     fn get_file_path(&self, key: &str) -> PathBuf {
@@ -24,12 +84,10 @@ impl FileSystemCache {
     }
 
     fn get_meta_path(&self, key: &str) -> PathBuf {
-        self.base_path.join(format!("{}.meta", key))
+        self.base_path.join(format!("{}{}", key, META_EXTENSION))
     }
 
-    async fn is_expired(&self, key: &str) -> Result<bool> {
-        let meta_path = self.get_meta_path(key);
-
+    async fn is_expired(&self, meta_path: &Path) -> Result<bool> {
         if !meta_path.exists() {
             return Ok(false);
         }
@@ -44,6 +102,83 @@ impl FileSystemCache {
             Ok(false)
         }
     }
+
+    /// Deletes expired entries and orphaned `.meta` files, then evicts the
+    /// least recently used entries until the cache is within its limits.
+    pub async fn sweep(&self) -> Result<SweepStats> {
+        let mut stats = SweepStats::default();
+        let mut files = Vec::new();
+
+        for path in list_files(&self.base_path).await? {
+            let name = path.to_string_lossy();
+            if let Some(data_path) = name.strip_suffix(META_EXTENSION) {
+                if !Path::new(data_path).exists() {
+                    remove_if_exists(&path).await?;
+                }
+                continue;
+            }
+
+            let meta_path = PathBuf::from(format!("{}{}", name, META_EXTENSION));
+            if self.is_expired(&meta_path).await? {
+                remove_if_exists(&path).await?;
+                remove_if_exists(&meta_path).await?;
+                stats.expired += 1;
+                continue;
+            }
+
+            let metadata = match tokio_fs::metadata(&path).await {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            files.push(CachedFile {
+                path,
+                size: metadata.len(),
+                last_used: metadata.modified()?,
+            });
+        }
+
+        let mut count = files.len();
+        let mut total: u64 = files.iter().map(|f| f.size).sum();
+        let over = |count: usize, total: u64| {
+            (self.limits.max_files > 0 && count > self.limits.max_files)
+                || (self.limits.max_bytes > 0 && total > self.limits.max_bytes)
+        };
+
+        files.sort_by_key(|f| f.last_used);
+        for file in files {
+            if !over(count, total) {
+                break;
+            }
+            remove_if_exists(&file.path).await?;
+            remove_if_exists(Path::new(&format!(
+                "{}{}",
+                file.path.to_string_lossy(),
+                META_EXTENSION
+            )))
+            .await?;
+            count -= 1;
+            total -= file.size;
+            stats.evicted += 1;
+        }
+
+        Ok(stats)
+    }
+
+    /// Sweeps the cache every `interval` until the task is aborted.
+    pub fn spawn_sweeper(&self, interval: Duration) -> JoinHandle<()> {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                match cache.sweep().await {
+                    Ok(stats) => debug!(?stats, "swept filesystem cache"),
+                    Err(e) => warn!("Failed to sweep filesystem cache: {}", e),
+                }
+            }
+        })
+    }
 }
 
 #[async_trait]
@@ -52,47 +187,194 @@ impl AudioCache for FileSystemCache {
         let file_path = self.get_file_path(key);
 
         // Check if file exists and isn't expired
-        if !file_path.exists() || self.is_expired(key).await? {
+        if !file_path.exists() {
+            return Ok(None);
+        }
+        if self.is_expired(&self.get_meta_path(key)).await? {
+            self.delete(key).await?;
             return Ok(None);
         }
 
         // Read file contents
         let contents = tokio_fs::read(&file_path).await?;
+
+        // The modification time doubles as the last access time for eviction,
+        // since access times are often not kept
+        let touched = tokio::task::spawn_blocking(move || {
+            fs::File::options()
+                .write(true)
+                .open(&file_path)?
+                .set_modified(SystemTime::now())
+        })
+        .await?;
+        if let Err(e) = touched {
+            debug!("Failed to touch cache entry [{}]: {}", key, e);
+        }
+
         Ok(Some(contents))
     }
 
     async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
         let file_path = self.get_file_path(key);
+        if let Some(parent) = file_path.parent() {
+            tokio_fs::create_dir_all(parent).await?;
+        }
 
         // Write the actual data
         tokio_fs::write(&file_path, value).await?;
 
         // If TTL is specified, write the expiration time
+        let meta_path = self.get_meta_path(key);
         if let Some(duration) = ttl {
-            let meta_path = self.get_meta_path(key);
             let expiry = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)?
                 .as_secs()
                 + duration.as_secs();
 
             tokio_fs::write(&meta_path, expiry.to_string()).await?;
+        } else {
+            remove_if_exists(&meta_path).await?;
         }
 
         Ok(())
     }
 
     async fn delete(&self, key: &str) -> Result<()> {
-        let file_path = self.get_file_path(key);
-        let meta_path = self.get_meta_path(key);
-
         // Delete both the data file and meta file if they exist
-        if file_path.exists() {
-            tokio_fs::remove_file(&file_path).await?;
+        remove_if_exists(&self.get_file_path(key)).await?;
+        remove_if_exists(&self.get_meta_path(key)).await?;
+
+        Ok(())
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match tokio_fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Lists every file under `dir`, however deeply nested.
+async fn list_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio_fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn age(cache: &FileSystemCache, key: &str, secs: u64) {
+        fs::File::options()
+            .write(true)
+            .open(cache.get_file_path(key))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    fn expire(cache: &FileSystemCache, key: &str) {
+        fs::write(cache.get_meta_path(key), "1").unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_expired_entries_are_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileSystemCache::new(dir.path()).unwrap();
+
+        cache
+            .set("a", b"a", Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        cache
+            .set("b", b"b", Some(Duration::from_secs(60)))
+            .await
+            .unwrap();
+        cache.set("c", b"c", None).await.unwrap();
+        expire(&cache, "a");
+        expire(&cache, "b");
+        fs::write(dir.path().join("orphan.meta"), "1").unwrap();
+
+        // Reading an expired entry deletes it too
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert!(!cache.get_file_path("a").exists());
+        assert!(!cache.get_meta_path("a").exists());
+
+        let stats = cache.sweep().await.unwrap();
+        assert_eq!(
+            stats,
+            SweepStats {
+                expired: 1,
+                evicted: 0
+            }
+        );
+        assert!(!cache.get_meta_path("b").exists());
+        assert!(!dir.path().join("orphan.meta").exists());
+        assert_eq!(cache.get("c").await.unwrap(), Some(b"c".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileSystemCache::with_limits(
+            dir.path(),
+            CacheLimits {
+                max_files: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for (key, secs) in [("old", 300), ("older", 400), ("new", 100)] {
+            cache
+                .set(key, b"x", Some(Duration::from_secs(60)))
+                .await
+                .unwrap();
+            age(&cache, key, secs);
         }
-        if meta_path.exists() {
-            tokio_fs::remove_file(&meta_path).await?;
+        // Reading counts as a use
+        cache.get("older").await.unwrap();
+
+        assert_eq!(cache.sweep().await.unwrap().evicted, 1);
+        assert_eq!(cache.get("old").await.unwrap(), None);
+        assert!(!cache.get_meta_path("old").exists());
+        assert!(cache.get("older").await.unwrap().is_some());
+        assert!(cache.get("new").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_evicts_down_to_byte_budget() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileSystemCache::with_limits(
+            dir.path(),
+            CacheLimits {
+                max_bytes: 10,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        for (key, secs) in [("a/1", 300), ("a/2", 200), ("b", 100)] {
+            cache.set(key, &[0; 4], None).await.unwrap();
+            age(&cache, key, secs);
         }
 
-        Ok(())
+        assert_eq!(cache.sweep().await.unwrap().evicted, 1);
+        assert_eq!(cache.get("a/1").await.unwrap(), None);
+        assert!(cache.get("a/2").await.unwrap().is_some());
+        assert!(cache.get("b").await.unwrap().is_some());
     }
 }
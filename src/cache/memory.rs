@@ -0,0 +1,193 @@
+use super::cache::AudioCache;
+use axum::async_trait;
+use color_eyre::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// An in-process cache holding at most `max_bytes` of keys and values,
+/// evicting the least recently used entries first.
+#[derive(Debug, Clone)]
+pub struct MemoryCache {
+    inner: Arc<Mutex<Lru>>,
+}
+
+#[derive(Debug)]
+struct Lru {
+    entries: HashMap<String, Entry>,
+    /// Keys by when they were last used, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    weight: usize,
+    max_weight: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+    used: u64,
+}
+
+impl Lru {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used);
+        self.weight -= weight(key, &entry.value);
+        Some(entry)
+    }
+}
+
+fn weight(key: &str, value: &[u8]) -> usize {
+    key.len() + value.len()
+}
+
+impl MemoryCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Lru {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                weight: 0,
+                max_weight: max_bytes,
+            })),
+        }
+    }
+
+    /// Bytes currently held.
+    pub fn weight(&self) -> usize {
+        self.inner.lock().unwrap().weight
+    }
+}
+
+#[async_trait]
+impl AudioCache for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let mut lru = self.inner.lock().unwrap();
+
+        let expired = match lru.entries.get(key) {
+            None => return Ok(None),
+            Some(entry) => entry.expires_at.is_some_and(|at| at <= Instant::now()),
+        };
+        if expired {
+            lru.remove(key);
+            return Ok(None);
+        }
+
+        let used = lru.tick();
+        let entry = lru.entries.get_mut(key).expect("entry was just found");
+        let last_used = std::mem::replace(&mut entry.used, used);
+        let value = entry.value.clone();
+        lru.recency.remove(&last_used);
+        lru.recency.insert(used, key.to_string());
+
+        Ok(Some(value))
+    }
+
+    async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        let mut lru = self.inner.lock().unwrap();
+        lru.remove(key);
+
+        // Storing it would mean evicting everything else and still not fit
+        let entry_weight = weight(key, value);
+        if entry_weight > lru.max_weight {
+            return Ok(());
+        }
+
+        while lru.weight + entry_weight > lru.max_weight {
+            let Some((_, oldest)) = lru.recency.pop_first() else {
+                break;
+            };
+            lru.remove(&oldest);
+        }
+
+        let used = lru.tick();
+        lru.entries.insert(
+            key.to_string(),
+            Entry {
+                value: value.to_vec(),
+                expires_at: ttl.map(|ttl| Instant::now() + ttl),
+                used,
+            },
+        );
+        lru.recency.insert(used, key.to_string());
+        lru.weight += entry_weight;
+
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.inner.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_used() {
+        // Room for three one-byte keys with three-byte values
+        let cache = MemoryCache::new(12);
+        cache.set("a", b"aaa", None).await.unwrap();
+        cache.set("b", b"bbb", None).await.unwrap();
+        cache.set("c", b"ccc", None).await.unwrap();
+        assert_eq!(cache.weight(), 12);
+
+        // Using `a` makes `b` the oldest
+        assert!(cache.get("a").await.unwrap().is_some());
+        cache.set("d", b"ddd", None).await.unwrap();
+
+        assert_eq!(cache.get("b").await.unwrap(), None);
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"aaa".to_vec()));
+        assert_eq!(cache.get("d").await.unwrap(), Some(b"ddd".to_vec()));
+        assert_eq!(cache.weight(), 12);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_by_weight() {
+        let cache = MemoryCache::new(12);
+        cache.set("a", b"aaa", None).await.unwrap();
+        cache.set("b", b"bbb", None).await.unwrap();
+        cache.set("c", b"ccc", None).await.unwrap();
+
+        // One large entry pushes out the two oldest
+        cache.set("e", b"eeeeeee", None).await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.get("b").await.unwrap(), None);
+        assert!(cache.get("c").await.unwrap().is_some());
+        assert_eq!(cache.weight(), 12);
+
+        // Entries that can never fit aren't stored, and evict nothing
+        cache.set("f", &[0; 20], None).await.unwrap();
+        assert_eq!(cache.get("f").await.unwrap(), None);
+        assert!(cache.get("c").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_replace_expire_and_delete() {
+        let cache = MemoryCache::new(100);
+        cache.set("a", b"old", None).await.unwrap();
+        cache.set("a", b"newer", None).await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"newer".to_vec()));
+        assert_eq!(cache.weight(), 6);
+
+        cache
+            .set("b", b"b", Some(Duration::from_millis(10)))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(cache.get("b").await.unwrap(), None);
+
+        cache.delete("a").await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        assert_eq!(cache.weight(), 0);
+    }
+}
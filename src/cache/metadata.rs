@@ -0,0 +1,135 @@
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::{sync::Arc, time::Duration};
+
+use super::cache::AudioCache;
+use crate::{blob::AudioBuffer, cyberpunkpath::params::Params, routes::meta::AudioMetadata};
+
+const META_CACHE_KEY_PREFIX: &str = "meta_cache:";
+
+/// `/meta` results, kept apart from cached audio with their own TTL. Each
+/// entry remembers a validator for the source it was probed from, like its
+/// ETag or modification time, so it is dropped once the source changes.
+#[derive(Clone)]
+pub struct MetadataCache {
+    cache: Arc<dyn AudioCache>,
+    ttl: Duration,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MetadataEntry {
+    /// Validator of the source audio
+    source: String,
+    metadata: AudioMetadata,
+}
+
+impl MetadataCache {
+    pub fn new(cache: Arc<dyn AudioCache>, ttl: Duration) -> Self {
+        Self { cache, ttl }
+    }
+
+    /// Gets the metadata for `params` if it was probed from the source
+    /// `source` validates.
+    pub async fn get(&self, params: &Params, source: &str) -> Result<Option<AudioMetadata>> {
+        let key = cache_key(params);
+        let Some(value) = self.cache.get(&key).await? else {
+            return Ok(None);
+        };
+
+        match serde_json::from_slice::<MetadataEntry>(&value) {
+            Ok(entry) if entry.source == source => Ok(Some(entry.metadata)),
+            // The source has changed or the entry is unreadable
+            _ => {
+                self.cache.delete(&key).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn set(&self, params: &Params, source: &str, metadata: &AudioMetadata) -> Result<()> {
+        let entry = MetadataEntry {
+            source: source.to_string(),
+            metadata: metadata.clone(),
+        };
+        self.cache
+            .set(
+                &cache_key(params),
+                &serde_json::to_vec(&entry)?,
+                Some(self.ttl),
+            )
+            .await
+    }
+
+    pub async fn invalidate(&self, params: &Params) -> Result<()> {
+        self.cache.delete(&cache_key(params)).await
+    }
+}
+
+fn cache_key(params: &Params) -> String {
+    let digest = Sha1::digest(params.to_string().as_bytes());
+    format!("{}{}", META_CACHE_KEY_PREFIX, hex::encode(digest))
+}
+
+/// A validator for sources whose loader can't give one without loading them.
+pub fn fingerprint(source: &AudioBuffer) -> String {
+    format!("sha1:{}", hex::encode(Sha1::digest(source.as_ref())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fs::FileSystemCache;
+    use std::collections::HashMap;
+
+    fn metadata() -> AudioMetadata {
+        AudioMetadata {
+            format: "ogg".to_string(),
+            duration: Some(1.5),
+            bit_rate: None,
+            sample_rate: Some(44100),
+            channels: Some(2),
+            codec: Some("vorbis".to_string()),
+            size: None,
+            tags: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metadata_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = MetadataCache::new(
+            Arc::new(FileSystemCache::new(dir.path()).unwrap()),
+            Duration::from_secs(60),
+        );
+        let params: Params = "song.mp3?format=ogg".parse().unwrap();
+        let source = fingerprint(&AudioBuffer::from_bytes(b"source".to_vec()));
+
+        assert_eq!(cache.get(&params, &source).await.unwrap(), None);
+        cache.set(&params, &source, &metadata()).await.unwrap();
+        assert_eq!(cache.get(&params, &source).await.unwrap(), Some(metadata()));
+
+        let other: Params = "song.mp3?format=mp3".parse().unwrap();
+        assert_eq!(cache.get(&other, &source).await.unwrap(), None);
+
+        cache.invalidate(&params).await.unwrap();
+        assert_eq!(cache.get(&params, &source).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_changed_source_invalidates() {
+        let dir = tempfile::tempdir().unwrap();
+        let audio_cache = Arc::new(FileSystemCache::new(dir.path()).unwrap());
+        let cache = MetadataCache::new(audio_cache.clone(), Duration::from_secs(60));
+        let params: Params = "song.mp3".parse().unwrap();
+
+        let original = "etag:\"1\"";
+        cache.set(&params, original, &metadata()).await.unwrap();
+
+        let changed = "etag:\"2\"";
+        assert_eq!(cache.get(&params, changed).await.unwrap(), None);
+        // The stale entry is gone, even for the original source
+        assert_eq!(cache.get(&params, original).await.unwrap(), None);
+        assert_eq!(audio_cache.get(&cache_key(&params)).await.unwrap(), None);
+    }
+}
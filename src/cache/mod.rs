@@ -1,3 +1,7 @@
+#[allow(clippy::module_inception)]
 pub mod cache;
 pub mod fs;
+pub mod memory;
+pub mod metadata;
 pub mod redis;
+pub mod tiered;
@@ -22,6 +22,35 @@ impl RedisCache {
             .await
             .map_err(Into::into)
     }
+
+    /// Sets `key` to `token` unless it's already set, expiring after `ttl`.
+    /// Returns whether the lock was taken.
+    pub async fn try_lock(&self, key: &str, token: &str, ttl: Duration) -> Result<bool> {
+        let mut conn = self.get_connection().await?;
+        let res: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(token)
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.as_millis() as u64)
+            .query_async(&mut conn)
+            .await?;
+        Ok(res.is_some())
+    }
+
+    /// Deletes `key` if it still holds `token`, so that a lock which expired
+    /// and was taken by someone else isn't released.
+    pub async fn unlock(&self, key: &str, token: &str) -> Result<()> {
+        let mut conn = self.get_connection().await?;
+        let _: i32 = redis::Script::new(
+            r#"if redis.call("get", KEYS[1]) == ARGV[1] then return redis.call("del", KEYS[1]) else return 0 end"#,
+        )
+        .key(key)
+        .arg(token)
+        .invoke_async(&mut conn)
+        .await?;
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,99 @@
+use super::cache::{AudioCache, Cache};
+use super::memory::MemoryCache;
+use axum::async_trait;
+use color_eyre::Result;
+use std::time::Duration;
+
+/// How long entries read from the backend stay in memory. Their remaining
+/// TTL in the backend isn't known, so they're only kept briefly.
+const PROMOTED_TTL: Duration = Duration::from_secs(60);
+
+/// An in-memory tier in front of another cache. Writes go to both, and
+/// reads that miss in memory are copied there from the backend.
+#[derive(Debug, Clone)]
+pub struct TieredCache {
+    memory: MemoryCache,
+    backend: Box<Cache>,
+}
+
+impl TieredCache {
+    pub fn new(memory: MemoryCache, backend: Cache) -> Self {
+        Self {
+            memory,
+            backend: Box::new(backend),
+        }
+    }
+
+    pub fn backend(&self) -> &Cache {
+        &self.backend
+    }
+}
+
+#[async_trait]
+impl AudioCache for TieredCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.memory.get(key).await? {
+            return Ok(Some(value));
+        }
+
+        let value = self.backend.get(key).await?;
+        if let Some(value) = &value {
+            self.memory.set(key, value, Some(PROMOTED_TTL)).await?;
+        }
+        Ok(value)
+    }
+
+    async fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        self.backend.set(key, value, ttl).await?;
+        self.memory.set(key, value, ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.memory.delete(key).await?;
+        self.backend.delete(key).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::fs::FileSystemCache;
+
+    fn tiered(dir: &std::path::Path) -> (TieredCache, MemoryCache, FileSystemCache) {
+        let memory = MemoryCache::new(1024);
+        let fs = FileSystemCache::new(dir).unwrap();
+        let cache = TieredCache::new(memory.clone(), Cache::Filesystem(fs.clone()));
+        (cache, memory, fs)
+    }
+
+    #[tokio::test]
+    async fn test_writes_go_to_both_tiers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, memory, fs) = tiered(dir.path());
+
+        cache.set("key", b"value", None).await.unwrap();
+        assert_eq!(memory.get("key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(fs.get("key").await.unwrap(), Some(b"value".to_vec()));
+
+        cache.delete("key").await.unwrap();
+        assert_eq!(memory.get("key").await.unwrap(), None);
+        assert_eq!(fs.get("key").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_backend_hits_are_promoted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cache, memory, fs) = tiered(dir.path());
+
+        fs.set("key", b"value", None).await.unwrap();
+        assert_eq!(memory.get("key").await.unwrap(), None);
+
+        assert_eq!(cache.get("key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(memory.get("key").await.unwrap(), Some(b"value".to_vec()));
+
+        // Served from memory even once the backend has lost it
+        fs.delete("key").await.unwrap();
+        assert_eq!(cache.get("key").await.unwrap(), Some(b"value".to_vec()));
+        assert_eq!(cache.get("missing").await.unwrap(), None);
+    }
+}
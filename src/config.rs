@@ -1,27 +1,33 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use secrecy::SecretString;
 use serde::Deserialize;
 use serde_aux::prelude::deserialize_number_from_string;
 use tracing::error;
 
+use crate::cyberpunkpath::hasher::ResultKeyer;
 use crate::cyberpunkpath::normalize::SafeCharsType;
+use crate::cyberpunkpath::signer::SignerType;
 
 #[derive(serde::Deserialize, Clone, Default)]
 #[serde(default)]
 pub struct Settings {
-    // TODO: add config in the config to allow/disallow fetching audios from the internet
-    // TODO: add config for unsafe URLs vs force the hash
-    // TODO: add secret key or somehow hash with API key?
     #[serde(alias="PORT", deserialize_with="deserialize_number_from_string", default = "default_port")]
     pub port: u16,
     pub application: ApplicationSettings,
     pub custom_tags: HashMap<String, String>,
     pub processor: ProcessorSettings,
-
-    // TODO: save audio to result bucket (diff from storage bucket)
+    pub loader: LoaderSettings,
     pub storage: StorageSettings,
+    /// Where processed audio is saved; defaults to `storage`
+    pub result_storage: Option<StorageSettings>,
+    /// How processed audio is keyed in result storage and the cache
+    pub result_key: ResultKeyer,
     pub cache: CacheSettings,
+    pub metadata_cache: MetadataCacheSettings,
+    pub coalescing: CoalescingSettings,
+    pub jobs: JobSettings,
 }
 
 #[derive(serde::Deserialize, Clone)]
@@ -30,6 +36,16 @@ pub struct ApplicationSettings {
     #[serde(alias="HOST")]
     pub host: String,
     pub hmac_secret: SecretString,
+    /// Active signing keys, newest first; replaces `hmac_secret` when set
+    pub hmac_keys: Vec<HmacKeySettings>,
+    /// Hash used to sign URLs with `hmac_secret`
+    pub signer_type: SignerType,
+    /// Length signatures are truncated to; 0 keeps the full signature
+    pub signer_truncate: usize,
+    /// Accept `/unsafe/` URLs that skip signature verification
+    pub allow_unsafe: bool,
+    /// `Cache-Control` sent with processed audio
+    pub cache_control: String,
 }
 
 impl Default for ApplicationSettings {
@@ -37,21 +53,163 @@ impl Default for ApplicationSettings {
         Self {
             host: String::from("127.0.0.1"),                                 // default host
             hmac_secret: SecretString::from("this-is-a-secret".to_string()), // empty secret
+            hmac_keys: Vec::new(),
+            signer_type: SignerType::default(),
+            signer_truncate: 0,
+            allow_unsafe: true,
+            cache_control: String::from("no-cache"),
         }
     }
 }
 
-#[derive(serde::Deserialize, Clone, Default)]
+#[derive(serde::Deserialize, Clone)]
+pub struct HmacKeySettings {
+    pub id: String,
+    pub secret: SecretString,
+}
+
+#[derive(serde::Deserialize, Clone)]
 #[serde(default)]
 pub struct ProcessorSettings {
     pub disabled_filters: Vec<String>,
     pub max_filter_ops: usize,
+    /// FFmpeg filters allowed in `filter_*` params; empty uses the built-in list
+    pub allowed_filters: Vec<String>,
+    /// FFmpeg flags allowed in `option_*` params; empty uses the built-in list
+    pub allowed_options: Vec<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub concurrency: Option<usize>,
+    /// Entries the filesystem cache keeps; 0 is unlimited
     pub max_cache_files: i32,
     pub max_cache_mem: i32,
+    /// Size of the filesystem cache in MB; 0 is unlimited
     pub max_cache_size: i32,
+    /// Seconds processed audio stays cached; 0 keeps it until evicted
+    pub cache_ttl: u64,
+    /// Seconds an FFmpeg run may take; 0 is unlimited
+    pub timeout: u64,
+    /// Longest source audio accepted in seconds, checked with ffprobe; 0 is
+    /// unlimited
+    pub max_input_duration: u64,
+    /// CPU seconds an FFmpeg process may use; 0 is unlimited
+    pub max_cpu_time: u64,
+    /// Address space of an FFmpeg process in MB; 0 is unlimited
+    pub max_address_space: u64,
+    /// Largest output in MB; processing producing more is stopped, as the
+    /// output is held in memory to be cached and stored. 0 is unlimited
+    pub max_output_size: u64,
+    /// Handle simple conversions in-process rather than with FFmpeg
+    pub native: bool,
+    /// Requests waiting for a processing slot before more are turned away
+    /// with a 429; 0 is unlimited
+    pub max_queue_depth: usize,
+    /// Seconds a request may wait for a processing slot; 0 is unlimited
+    pub max_queue_wait: u64,
+    /// `Retry-After` seconds sent with a 429
+    pub queue_retry_after: u64,
+}
+
+impl Default for ProcessorSettings {
+    fn default() -> Self {
+        Self {
+            disabled_filters: Vec::new(),
+            max_filter_ops: 0,
+            allowed_filters: Vec::new(),
+            allowed_options: Vec::new(),
+            concurrency: None,
+            max_cache_files: 0,
+            max_cache_mem: 0,
+            max_cache_size: 0,
+            cache_ttl: 3600,
+            timeout: 300,
+            max_input_duration: 0,
+            max_cpu_time: 0,
+            max_address_space: 0,
+            max_output_size: 1024,
+            native: true,
+            max_queue_depth: 0,
+            max_queue_wait: 0,
+            queue_retry_after: 5,
+        }
+    }
+}
+
+/// Where source audio is loaded from, and limits on fetching it.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct LoaderSettings {
+    /// Loaders tried in order for each key
+    pub chain: Vec<LoaderKind>,
+    pub file: Option<FileLoaderSettings>,
+    pub s3: Option<S3LoaderSettings>,
+    /// Fetch remote audio at all
+    pub allow_remote: bool,
+    /// Host globs that may be fetched, e.g. `*.example.com`; empty allows any host
+    pub allowed_hosts: Vec<String>,
+    /// Host globs that may never be fetched; checked before `allowed_hosts`
+    pub denied_hosts: Vec<String>,
+    /// Allow loopback, link-local and private network addresses
+    pub allow_private_addresses: bool,
+    /// Maximum source size in MB
+    pub max_body_size: usize,
+    pub max_redirects: usize,
+    /// Total request timeout in seconds
+    pub timeout: u64,
+    /// Connect timeout in seconds
+    pub connect_timeout: u64,
+    /// Seconds remote sources are kept in the cache for reuse across params;
+    /// 0 turns the source cache off
+    pub source_cache_ttl: u64,
+}
+
+impl Default for LoaderSettings {
+    fn default() -> Self {
+        Self {
+            chain: vec![LoaderKind::Http, LoaderKind::Storage],
+            file: None,
+            s3: None,
+            allow_remote: true,
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            allow_private_addresses: false,
+            max_body_size: 100,
+            max_redirects: 5,
+            timeout: 30,
+            connect_timeout: 5,
+            source_cache_ttl: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LoaderKind {
+    /// `http://` and `https://` URLs
+    Http,
+    /// Plain keys, read from the configured storage
+    Storage,
+    /// `file://` paths under `loader.file.base_dir`
+    File,
+    /// `s3://bucket/key`
+    S3,
+    /// `gs://bucket/key`
+    Gcs,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FileLoaderSettings {
+    pub base_dir: String,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct S3LoaderSettings {
+    pub region: String,
+
+    #[serde(default = "default_s3_endpoint")]
+    pub endpoint: String,
+    pub access_key: SecretString,
+    pub secret_key: SecretString,
 }
 
 #[derive(Deserialize, Clone, Default)]
@@ -104,17 +262,91 @@ fn default_base_dir() -> String {
 pub enum CacheSettings {
     Redis { uri: String },
     Filesystem(FilesystemCacheSettings),
+    /// Per process, bounded by `processor.max_cache_mem`
+    InMemory,
+    /// `InMemory` in front of a Redis or Filesystem cache
+    Tiered(Box<CacheSettings>),
 }
 
-#[derive(Deserialize, Clone, Default)]
+#[derive(Deserialize, Clone)]
 #[serde(default)]
 pub struct FilesystemCacheSettings {
-    #[serde(default = "default_cache_base_dir")]
     pub base_dir: String,
+    /// Seconds between sweeps for expired entries and over-limit evictions;
+    /// 0 turns sweeping off
+    pub sweep_interval: u64,
+}
+
+impl Default for FilesystemCacheSettings {
+    fn default() -> Self {
+        Self {
+            base_dir: "cache".to_string(),
+            sweep_interval: 60,
+        }
+    }
+}
+
+/// Concurrent requests for the same result are always processed once per
+/// replica; with Redis they are processed once across replicas too.
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct CoalescingSettings {
+    /// Redis holding the processing locks shared by replicas
+    pub redis_uri: Option<String>,
+    /// Seconds a replica may hold a lock before others stop waiting for it
+    pub lock_ttl: u64,
+}
+
+impl Default for CoalescingSettings {
+    fn default() -> Self {
+        Self {
+            redis_uri: None,
+            lock_ttl: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct JobSettings {
+    /// Redis the jobs are kept in, so any replica can answer for them;
+    /// in memory when unset
+    pub redis_uri: Option<String>,
+    /// Seconds a job is kept after its last update
+    pub ttl: u64,
+}
+
+impl Default for JobSettings {
+    fn default() -> Self {
+        Self {
+            redis_uri: None,
+            ttl: 86400,
+        }
+    }
 }
 
-fn default_cache_base_dir() -> String {
-    "cache".to_string()
+#[derive(Deserialize, Clone)]
+#[serde(default)]
+pub struct MetadataCacheSettings {
+    /// How long `/meta` results are cached, in seconds
+    pub ttl: u64,
+}
+
+impl Default for MetadataCacheSettings {
+    fn default() -> Self {
+        Self { ttl: 86400 }
+    }
+}
+
+impl CacheSettings {
+    /// How often the filesystem cache, if any, is swept.
+    pub fn sweep_interval(&self) -> Duration {
+        match self {
+            CacheSettings::Filesystem(settings) => Duration::from_secs(settings.sweep_interval),
+            CacheSettings::Tiered(backend) => backend.sweep_interval(),
+            CacheSettings::Redis { .. } | CacheSettings::InMemory => Duration::ZERO,
+        }
+    }
 }
 
 impl Default for CacheSettings {
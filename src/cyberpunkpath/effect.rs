@@ -0,0 +1,316 @@
+use std::fmt::{self, Display};
+
+use serde::{Deserialize, Serialize};
+
+use super::validation::{
+    canonical_float, join, FieldParser, ParamsError, FREQUENCY_RANGE, GAIN_RANGE, NON_NEGATIVE,
+    NORMALIZE_LEVEL_RANGE, SPEED_RANGE, VOLUME_RANGE,
+};
+
+pub const CHAIN_SEPARATOR: char = '|';
+
+const EFFECT_NAMES: [&str; 19] = [
+    "speed",
+    "reverse",
+    "volume",
+    "normalize",
+    "lowpass",
+    "highpass",
+    "bandpass",
+    "bass",
+    "treble",
+    "echo",
+    "chorus",
+    "flanger",
+    "phaser",
+    "tremolo",
+    "compressor",
+    "noise_reduction",
+    "fade_in",
+    "fade_out",
+    "cross_fade",
+];
+
+/// A single built-in effect, written as `name` or `name:args` in a chain,
+/// e.g. `fade_in:2` or `echo:0.8:0.9:1000:0.3`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(into = "String", try_from = "String")]
+pub enum Effect {
+    Speed(f64),
+    Reverse,
+    Volume(f64),
+    /// Target integrated loudness, defaulting to -16 LUFS
+    Normalize(Option<f64>),
+    Lowpass(f64),
+    Highpass(f64),
+    Bandpass(String),
+    Bass(f64),
+    Treble(f64),
+    Echo(String),
+    Chorus(String),
+    Flanger(String),
+    Phaser(String),
+    Tremolo(String),
+    Compressor(String),
+    NoiseReduction(String),
+    FadeIn(f64),
+    FadeOut(f64),
+    CrossFade(f64),
+}
+
+impl Effect {
+    /// The query parameter this effect corresponds to.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Effect::Speed(_) => "speed",
+            Effect::Reverse => "reverse",
+            Effect::Volume(_) => "volume",
+            Effect::Normalize(_) => "normalize",
+            Effect::Lowpass(_) => "lowpass",
+            Effect::Highpass(_) => "highpass",
+            Effect::Bandpass(_) => "bandpass",
+            Effect::Bass(_) => "bass",
+            Effect::Treble(_) => "treble",
+            Effect::Echo(_) => "echo",
+            Effect::Chorus(_) => "chorus",
+            Effect::Flanger(_) => "flanger",
+            Effect::Phaser(_) => "phaser",
+            Effect::Tremolo(_) => "tremolo",
+            Effect::Compressor(_) => "compressor",
+            Effect::NoiseReduction(_) => "noise_reduction",
+            Effect::FadeIn(_) => "fade_in",
+            Effect::FadeOut(_) => "fade_out",
+            Effect::CrossFade(_) => "cross_fade",
+        }
+    }
+
+    pub fn to_filter(&self) -> String {
+        match self {
+            Effect::Speed(speed) => format!("atempo={:.3}", speed),
+            Effect::Reverse => "areverse".to_string(),
+            Effect::Volume(volume) => format!("volume={:.2}", volume),
+            Effect::Normalize(level) => format!("loudnorm=I={:.1}", level.unwrap_or(-16.0)),
+            Effect::Lowpass(freq) => format!("lowpass=f={:.1}", freq),
+            Effect::Highpass(freq) => format!("highpass=f={:.1}", freq),
+            Effect::Bandpass(band) => format!("bandpass={}", band),
+            Effect::Bass(gain) => format!("bass=g={:.1}", gain),
+            Effect::Treble(gain) => format!("treble=g={:.1}", gain),
+            Effect::Echo(args) => format!("aecho={}", args),
+            Effect::Chorus(args) => format!("chorus={}", args),
+            Effect::Flanger(args) => format!("flanger={}", args),
+            Effect::Phaser(args) => format!("aphaser={}", args),
+            Effect::Tremolo(args) => format!("tremolo={}", args),
+            Effect::Compressor(args) => format!("acompressor={}", args),
+            Effect::NoiseReduction(args) => format!("anlmdn={}", args),
+            Effect::FadeIn(fade) => format!("afade=t=in:d={:.3}", fade),
+            Effect::FadeOut(fade) => format!("afade=t=out:d={:.3}", fade),
+            Effect::CrossFade(fade) => format!("acrossfade=d={:.3}", fade),
+        }
+    }
+
+    /// Parses one chain step, recording any problems against `key`.
+    pub fn parse(key: &str, step: &str, p: &mut FieldParser) -> Option<Effect> {
+        let (name, args) = match step.split_once(':') {
+            Some((name, args)) => (name, Some(args)),
+            None => (step, None),
+        };
+
+        let number = |p: &mut FieldParser, range: (f64, f64)| match args {
+            Some(args) => p.float(key, args, range),
+            None => {
+                p.reject(key, step, "missing argument", None);
+                None
+            }
+        };
+        let text = |p: &mut FieldParser| match args {
+            Some(args) if !args.is_empty() => Some(args.to_string()),
+            _ => {
+                p.reject(key, step, "missing argument", None);
+                None
+            }
+        };
+
+        let effect = match name {
+            "speed" => Effect::Speed(number(p, SPEED_RANGE)?),
+            "reverse" => {
+                if args.is_some() {
+                    p.reject(key, step, "takes no arguments", None);
+                }
+                Effect::Reverse
+            }
+            "volume" => Effect::Volume(number(p, VOLUME_RANGE)?),
+            "normalize" => match args {
+                Some(_) => Effect::Normalize(Some(number(p, NORMALIZE_LEVEL_RANGE)?)),
+                None => Effect::Normalize(None),
+            },
+            "lowpass" => Effect::Lowpass(number(p, FREQUENCY_RANGE)?),
+            "highpass" => Effect::Highpass(number(p, FREQUENCY_RANGE)?),
+            "bandpass" => Effect::Bandpass(text(p)?),
+            "bass" => Effect::Bass(number(p, GAIN_RANGE)?),
+            "treble" => Effect::Treble(number(p, GAIN_RANGE)?),
+            "echo" => Effect::Echo(text(p)?),
+            "chorus" => Effect::Chorus(text(p)?),
+            "flanger" => Effect::Flanger(text(p)?),
+            "phaser" => Effect::Phaser(text(p)?),
+            "tremolo" => Effect::Tremolo(text(p)?),
+            "compressor" => Effect::Compressor(text(p)?),
+            "noise_reduction" => Effect::NoiseReduction(text(p)?),
+            "fade_in" => Effect::FadeIn(number(p, NON_NEGATIVE)?),
+            "fade_out" => Effect::FadeOut(number(p, NON_NEGATIVE)?),
+            "cross_fade" => Effect::CrossFade(number(p, NON_NEGATIVE)?),
+            _ => {
+                p.reject(key, step, "unknown effect", Some(join(EFFECT_NAMES)));
+                return None;
+            }
+        };
+
+        Some(effect)
+    }
+}
+
+impl Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.name();
+        match self {
+            Effect::Reverse | Effect::Normalize(None) => write!(f, "{}", name),
+            Effect::Speed(v)
+            | Effect::Volume(v)
+            | Effect::Normalize(Some(v))
+            | Effect::Lowpass(v)
+            | Effect::Highpass(v)
+            | Effect::Bass(v)
+            | Effect::Treble(v)
+            | Effect::FadeIn(v)
+            | Effect::FadeOut(v)
+            | Effect::CrossFade(v) => write!(f, "{}:{}", name, canonical_float(*v)),
+            Effect::Bandpass(args)
+            | Effect::Echo(args)
+            | Effect::Chorus(args)
+            | Effect::Flanger(args)
+            | Effect::Phaser(args)
+            | Effect::Tremolo(args)
+            | Effect::Compressor(args)
+            | Effect::NoiseReduction(args) => write!(f, "{}:{}", name, args),
+        }
+    }
+}
+
+impl From<Effect> for String {
+    fn from(effect: Effect) -> Self {
+        effect.to_string()
+    }
+}
+
+impl TryFrom<String> for Effect {
+    type Error = ParamsError;
+
+    fn try_from(step: String) -> Result<Self, Self::Error> {
+        let mut p = FieldParser::default();
+        let effect = Effect::parse("chain", &step, &mut p);
+        let errors = p.into_errors();
+        match effect {
+            Some(effect) if errors.is_empty() => Ok(effect),
+            _ => Err(ParamsError { errors }),
+        }
+    }
+}
+
+/// Parses a `chain` value such as `reverse|echo:0.8:0.9:1000:0.3|fade_in:2`.
+/// Errors are keyed by step, e.g. `chain[1]`.
+pub fn parse_chain(value: &str, p: &mut FieldParser) -> Option<Vec<Effect>> {
+    if value.is_empty() {
+        return None;
+    }
+
+    let effects: Vec<Effect> = value
+        .split(CHAIN_SEPARATOR)
+        .enumerate()
+        .filter_map(|(i, step)| {
+            let key = format!("chain[{}]", i);
+            if step.is_empty() {
+                p.reject(&key, step, "empty step", None);
+                return None;
+            }
+            Effect::parse(&key, step, p)
+        })
+        .collect();
+
+    Some(effects)
+}
+
+pub fn format_chain(effects: &[Effect]) -> String {
+    effects
+        .iter()
+        .map(|e| e.to_string())
+        .collect::<Vec<_>>()
+        .join(&CHAIN_SEPARATOR.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chain_keeps_order() {
+        let mut p = FieldParser::default();
+        let chain = parse_chain("reverse|echo:0.8:0.9:1000:0.3|fade_in:2", &mut p).unwrap();
+
+        assert!(p.into_errors().is_empty());
+        assert_eq!(
+            chain,
+            vec![
+                Effect::Reverse,
+                Effect::Echo("0.8:0.9:1000:0.3".to_string()),
+                Effect::FadeIn(2.0),
+            ]
+        );
+        assert_eq!(
+            chain.iter().map(Effect::to_filter).collect::<Vec<_>>(),
+            vec!["areverse", "aecho=0.8:0.9:1000:0.3", "afade=t=in:d=2.000"]
+        );
+    }
+
+    #[test]
+    fn test_chain_round_trips() {
+        let value = "speed:1.5|normalize|normalize:-14|bandpass:f=1000:width_type=h:w=200";
+        let mut p = FieldParser::default();
+        let chain = parse_chain(value, &mut p).unwrap();
+
+        assert!(p.into_errors().is_empty());
+        assert_eq!(format_chain(&chain), value);
+    }
+
+    #[test]
+    fn test_parse_chain_errors() {
+        let mut p = FieldParser::default();
+        let chain = parse_chain("reverse||wobble:3|speed:200|echo|speed:x", &mut p).unwrap();
+
+        // Out of range values are kept for the lenient parser
+        assert_eq!(chain, vec![Effect::Reverse, Effect::Speed(200.0)]);
+
+        let errors = p.into_errors();
+        let summary: Vec<(&str, &str)> = errors
+            .iter()
+            .map(|e| (e.key.as_str(), e.reason.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("chain[1]", "empty step"),
+                ("chain[2]", "unknown effect"),
+                ("chain[3]", "out of range"),
+                ("chain[4]", "missing argument"),
+                ("chain[5]", "invalid number"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_effect_serde() {
+        let effect: Effect = serde_json::from_str("\"fade_out:3\"").unwrap();
+        assert_eq!(effect, Effect::FadeOut(3.0));
+        assert_eq!(serde_json::to_string(&effect).unwrap(), "\"fade_out:3\"");
+
+        assert!(serde_json::from_str::<Effect>("\"wobble\"").is_err());
+    }
+}
@@ -1,24 +1,64 @@
 use super::params;
-use argon2::{
-    password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher,
-    PasswordVerifier, Version,
-};
-
-use color_eyre::{
-    eyre::{Context, Error},
-    Result,
-};
 use hex;
-use secrecy::{ExposeSecret, SecretBox, SecretString};
+use serde::Deserialize;
 use sha1::{Digest, Sha1};
 
-#[derive(thiserror::Error, Debug)]
-pub enum AuthError {
-    #[error("Invalid credentials")]
-    InvalidCredentials(#[source] Error),
+const TEMPLATE_PLACEHOLDERS: [&str; 5] = ["source_dir", "source_name", "hash", "digest", "ext"];
+
+/// How processed audio is keyed in result storage and the request cache.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultKeyer {
+    /// Sharded digest of the params, e.g. `ab/cd/ef01...`
+    Digest,
+    /// Next to the source, e.g. `audio/song.0a1b2c3d4e5f60718293.ogg`
+    #[default]
+    Suffix,
+    /// A path built from `{source_dir}`, `{source_name}`, `{hash}`,
+    /// `{digest}` and `{ext}`, e.g. `{source_dir}/{hash}.{ext}`
+    Template(String),
+}
+
+impl ResultKeyer {
+    pub fn key(&self, p: &params::Params) -> String {
+        match self {
+            ResultKeyer::Digest => digest_result_storage_hasher(p),
+            ResultKeyer::Suffix => suffix_result_storage_hasher(p),
+            ResultKeyer::Template(template) => template_result_storage_hasher(template, p),
+        }
+    }
+
+    /// Checks that a template only uses known placeholders and includes the
+    /// hash, so that different params never share a key.
+    pub fn validate(&self) -> Result<(), String> {
+        let ResultKeyer::Template(template) = self else {
+            return Ok(());
+        };
+
+        let mut rest = template.as_str();
+        let mut hashed = false;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                return Err(format!("unclosed placeholder in {}", template));
+            };
+            let name = &rest[start + 1..start + len];
+            if !TEMPLATE_PLACEHOLDERS.contains(&name) {
+                return Err(format!(
+                    "unknown placeholder {{{}}} in {}, expected one of {}",
+                    name,
+                    template,
+                    TEMPLATE_PLACEHOLDERS.join(", ")
+                ));
+            }
+            hashed |= name == "hash" || name == "digest";
+            rest = &rest[start + len + 1..];
+        }
 
-    #[error(transparent)]
-    UnexpectedError(#[from] Error),
+        if !hashed {
+            return Err(format!("{} must include {{hash}} or {{digest}}", template));
+        }
+        Ok(())
+    }
 }
 
 fn hex_digest_path(path: &str) -> String {
@@ -41,19 +81,13 @@ pub fn suffix_result_storage_hasher(p: &params::Params) -> String {
     let digest = Sha1::digest(path.as_bytes());
     let hash = format!(".{}", hex::encode(&digest[..10]));
 
-    let audio = if p.key.starts_with("https://") {
-        &p.key[8..].to_string()
-    } else if p.key.starts_with("http://") {
-        &p.key[7..].to_string()
-    } else {
-        &p.key
-    };
+    let audio = source_path(&p.key);
 
     let dot_idx = audio.rfind('.');
     let slash_idx = audio.rfind('/');
 
     if let Some(dot_idx) = dot_idx {
-        if slash_idx.map_or(true, |idx| idx < dot_idx) {
+        if slash_idx.is_none_or(|idx| idx < dot_idx) {
             let ext = if let Some(format) = &p.format {
                 format!(".{}", format.to_string().to_lowercase())
             } else {
@@ -65,81 +99,47 @@ pub fn suffix_result_storage_hasher(p: &params::Params) -> String {
     format!("{}{}", audio, hash)
 }
 
-#[tracing::instrument(name = "Verify path hash", skip(expected_path_hash, path_candidate))]
-pub fn verify_hash(
-    expected_path_hash: SecretString,
-    path_candidate: SecretString,
-) -> Result<(), AuthError> {
-    let expected_path_hash = PasswordHash::new(expected_path_hash.expose_secret())
-        .context("Failed to parse hash in PHC string format.")?;
-
-    Argon2::default()
-        .verify_password(
-            path_candidate.expose_secret().as_bytes(),
-            &expected_path_hash,
-        )
-        .context("Invalid hash")
-        .map_err(AuthError::InvalidCredentials)
-}
+pub fn template_result_storage_hasher(template: &str, p: &params::Params) -> String {
+    let path = p.to_string();
+    let digest = hex::encode(Sha1::digest(path.as_bytes()));
 
-#[tracing::instrument(name = "Compute path hash", skip(path))]
-pub fn compute_hash(path: String) -> Result<SecretString> {
-    let salt = SaltString::generate(&mut rand::thread_rng());
-    let hash_password = Argon2::new(
-        Algorithm::Argon2id,
-        Version::V0x13,
-        Params::new(15_000, 2, 1, None).unwrap(),
-    )
-    .hash_password(path.as_bytes(), &salt);
+    let audio = source_path(&p.key);
+    let (source_dir, file) = audio.rsplit_once('/').unwrap_or(("", audio));
+    let (source_name, source_ext) = match file.rsplit_once('.') {
+        Some((name, ext)) if !name.is_empty() => (name, ext),
+        _ => (file, ""),
+    };
+    let ext = match &p.format {
+        Some(format) => format.to_string().to_lowercase(),
+        None => source_ext.to_string(),
+    };
 
-    let password_hash = hash_password?.to_string();
+    let key = template
+        .replace("{source_dir}", source_dir)
+        .replace("{source_name}", source_name)
+        .replace("{hash}", &digest[..20])
+        .replace("{digest}", &hex_digest_path(&path))
+        .replace("{ext}", &ext);
+
+    // Placeholders that render empty can leave stray separators behind
+    key.split('/')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+        .trim_end_matches('.')
+        .to_string()
+}
 
-    Ok(SecretBox::from(password_hash))
+/// The key without its URL scheme, e.g. `bucket/song.mp3` for `s3://bucket/song.mp3`.
+fn source_path(key: &str) -> &str {
+    key.split_once("://").map_or(key, |(_, path)| path)
 }
+
 #[cfg(test)]
 mod tests {
     use super::params::Params;
     use super::*;
     use crate::blob::AudioFormat;
-    use color_eyre::Result;
-
-    #[test]
-    fn test_compute_and_verify_hash() -> Result<()> {
-        let test_path = "my/test/path".to_string();
-        let hash = compute_hash(test_path.clone())?;
-        verify_hash(hash, SecretString::from(test_path))?;
-        Ok(())
-    }
-
-    #[test]
-    fn test_verify_hash_with_invalid_input() {
-        let test_path = "my/test/path".to_string();
-        let hash = compute_hash(test_path).unwrap();
-        let result = verify_hash(hash, SecretString::from("wrong/path".to_string()));
-        assert!(result.is_err());
-        if let Err(e) = result {
-            assert!(matches!(e, AuthError::InvalidCredentials(_)));
-        }
-    }
-
-    #[test]
-    fn test_verify_hash_with_invalid_hash_format() {
-        let result = verify_hash(
-            SecretString::from("not-a-valid-hash-format".to_string()),
-            SecretString::from("some/path".to_string()),
-        );
-        assert!(result.is_err());
-    }
-
-    #[test]
-    fn test_hash_consistency() -> Result<()> {
-        let test_path = "consistent/test/path".to_string();
-        let hash1 = compute_hash(test_path.clone())?;
-        let hash2 = compute_hash(test_path.clone())?;
-        verify_hash(hash1, SecretString::from(test_path.clone()))?;
-        verify_hash(hash2, SecretString::from(test_path))?;
-        Ok(())
-    }
 
     #[test]
     fn test_digest_result_storage_hasher() {
@@ -214,6 +214,70 @@ mod tests {
         assert!(parts[1].chars().all(|c| c.is_ascii_hexdigit()));
     }
 
+    #[test]
+    fn test_template_result_storage_hasher() {
+        let p = Params {
+            key: "https://example.com/audio/song.mp3".to_string(),
+            format: Some(AudioFormat::Ogg),
+            ..Default::default()
+        };
+        let hash = &hex::encode(Sha1::digest(p.to_string().as_bytes()))[..20];
+
+        assert_eq!(
+            template_result_storage_hasher("{source_dir}/{hash}.{ext}", &p),
+            format!("example.com/audio/{}.ogg", hash)
+        );
+        assert_eq!(
+            template_result_storage_hasher("results/{source_name}-{hash}.{ext}", &p),
+            format!("results/song-{}.ogg", hash)
+        );
+
+        let p = Params {
+            key: "song".to_string(),
+            ..Default::default()
+        };
+        let hash = &hex::encode(Sha1::digest(p.to_string().as_bytes()))[..20];
+        assert_eq!(
+            template_result_storage_hasher("{source_dir}/{hash}.{ext}", &p),
+            hash
+        );
+    }
+
+    #[test]
+    fn test_result_keyer() {
+        let p = Params {
+            key: "test.mp3".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            ResultKeyer::Digest.key(&p),
+            digest_result_storage_hasher(&p)
+        );
+        assert_eq!(
+            ResultKeyer::default().key(&p),
+            suffix_result_storage_hasher(&p)
+        );
+
+        assert!(
+            ResultKeyer::Template("{source_dir}/{hash}.{ext}".to_string())
+                .validate()
+                .is_ok()
+        );
+        assert!(ResultKeyer::Template("{digest}".to_string())
+            .validate()
+            .is_ok());
+        assert!(ResultKeyer::Template("{source_name}.{ext}".to_string())
+            .validate()
+            .is_err());
+        assert!(ResultKeyer::Template("{hash}.{extension}".to_string())
+            .validate()
+            .is_err());
+        assert!(ResultKeyer::Template("{hash".to_string())
+            .validate()
+            .is_err());
+    }
+
     #[test]
     fn test_suffix_result_storage_hasher_with_filters() {
         let p = Params {
@@ -1,3 +1,7 @@
+pub mod effect;
 pub mod hasher;
 pub mod normalize;
 pub mod params;
+pub mod segment;
+pub mod signer;
+pub mod validation;
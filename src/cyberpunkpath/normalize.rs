@@ -104,6 +104,14 @@ pub fn normalize(key: &str, safe_chars: &SafeCharsType) -> String {
 
     escape(path, |c| safe_chars.should_escape(c))
 }
+
+/// Whether `key` stays under the directory it is joined onto: it has no `..`
+/// segments, no leading `/` and no NUL bytes.
+pub fn is_contained(key: &str) -> bool {
+    !key.starts_with('/')
+        && !key.contains('\0')
+        && !key.split(['/', '\\']).any(|segment| segment == "..")
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,4 +250,17 @@ mod tests {
         let result = normalize("hello\u{2028}world", &SafeCharsType::Default);
         assert_eq!(result, "helloworld");
     }
+
+    #[test]
+    fn test_is_contained() {
+        assert!(is_contained("song.mp3"));
+        assert!(is_contained("dir/song..mp3"));
+        assert!(is_contained("https://example.com/song.mp3"));
+
+        assert!(!is_contained("../secret.mp3"));
+        assert!(!is_contained("dir/../../secret.mp3"));
+        assert!(!is_contained("dir\\..\\secret.mp3"));
+        assert!(!is_contained("/etc/secret.mp3"));
+        assert!(!is_contained("song.mp3\0.txt"));
+    }
 }
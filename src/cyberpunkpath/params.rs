@@ -1,14 +1,10 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     fmt::{self, Display},
     str::FromStr,
 };
 
-use axum::{
-    async_trait,
-    extract::FromRequestParts,
-    http::{request::Parts, StatusCode},
-};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
 use color_eyre::{eyre, Result};
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
@@ -17,13 +13,46 @@ use url::form_urlencoded;
 
 use crate::blob::AudioFormat;
 
+use super::effect::{format_chain, parse_chain, Effect};
+use super::normalize::is_contained;
+use super::segment::{self, encode};
+use super::signer::{Signer, EXPIRES_PARAM};
+use super::validation::{
+    canonical_float, join, FieldError, FieldParser, ParamsError, FREQUENCY_RANGE, GAIN_RANGE,
+    NON_NEGATIVE, NORMALIZE_LEVEL_RANGE, SPEED_RANGE, SUPPORTED_BIT_DEPTHS, SUPPORTED_SAMPLE_RATES,
+    VOLUME_RANGE,
+};
+
+const SUPPORTED_FORMATS: [AudioFormat; 6] = [
+    AudioFormat::Mp3,
+    AudioFormat::Wav,
+    AudioFormat::Flac,
+    AudioFormat::Ogg,
+    AudioFormat::M4a,
+    AudioFormat::Opus,
+];
+
 #[derive(Debug)]
 pub struct CyberpunkPath {
     pub path: String,
 }
 
-pub trait Signer {
-    fn sign(&self, path: &str) -> String;
+/// A single FFmpeg filter along with the query parameter that produced it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterOp {
+    pub param: &'static str,
+    pub filter: String,
+}
+
+impl FilterOp {
+    fn new(param: &'static str, filter: String) -> Self {
+        Self { param, filter }
+    }
+
+    /// The FFmpeg filter name, e.g. `aecho` for `aecho=0.8:0.9:1000:0.3`.
+    pub fn name(&self) -> &str {
+        self.filter.split(['=', '@']).next().unwrap_or(&self.filter)
+    }
 }
 
 #[async_trait]
@@ -31,7 +60,7 @@ impl<S> FromRequestParts<S> for Params
 where
     S: Send + Sync,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = ParamsError;
 
     #[tracing::instrument(skip(parts, _state))]
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
@@ -48,14 +77,7 @@ where
                 .into_owned()
                 .collect();
 
-        let params = Params::from_path(path.to_string(), query_params).map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("Failed to parse params: {}", e),
-            )
-        })?;
-
-        Ok(params)
+        Params::from_path_strict(path.to_string(), query_params)
     }
 }
 
@@ -142,6 +164,11 @@ pub struct Params {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cross_fade: Option<f64>,
 
+    /// Effects applied in the order given, after the fixed-order effects above
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<Vec<String>>, example = json!(["reverse", "echo:0.8:0.9:1000:0.3", "fade_in:2"]))]
+    pub chain: Option<Vec<Effect>>,
+
     // Advanced
     #[serde(skip_serializing_if = "Option::is_none")]
     pub custom_filters: Option<Vec<String>>,
@@ -152,21 +179,33 @@ pub struct Params {
     pub tags: Option<HashMap<String, String>>,
 }
 
+/// Orders `filter_N`/`option_N` values by their suffix, numerically where
+/// possible, since FFmpeg filters and options are order sensitive.
+fn sorted_by_suffix(mut values: Vec<(String, String)>) -> Option<Vec<String>> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(|(a, _), (b, _)| (a.parse::<u64>().ok(), a).cmp(&(b.parse::<u64>().ok(), b)));
+    Some(values.into_iter().map(|(_, v)| v).collect())
+}
+
+/// The canonical `key?query` form used for result storage hashes, cache keys
+/// and URL signatures, so equal params always serialize the same way.
 impl Display for Params {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let query_params = self.to_query();
         let query_str = query_params
             .iter()
-            .map(|(k, v)| {
+            .flat_map(|(k, v)| {
                 v.iter()
                     .map(|val| format!("{}={}", k, urlencoding::encode(val)))
                     .collect::<Vec<_>>()
             })
-            .flatten()
             .collect::<Vec<_>>()
             .join("&");
 
-        write!(f, "{}?{}", self.key, query_str)
+        write!(f, "{}?{}", encode(&self.key), query_str)
     }
 }
 
@@ -179,93 +218,166 @@ impl FromStr for Params {
 
         info!("Path: {} - {:?}", path, parts);
 
-        // Split path into components and take the last one as audio id
-        let path_components: Vec<&str> = path.split('/').collect();
-        let audio = path_components.last().unwrap_or(&"").to_string();
-
         if parts.len() <= 1 {
-            return Self::from_path(audio, HashMap::new());
+            return Self::from_path(path.to_string(), HashMap::new());
         }
 
         let query_params: HashMap<String, String> = form_urlencoded::parse(parts[1].as_bytes())
             .into_owned()
             .collect();
 
-        Self::from_path(audio, query_params)
+        Self::from_path(path.to_string(), query_params)
     }
 }
 
 impl Params {
     pub fn from_path(path: String, query: HashMap<String, String>) -> Result<Self> {
-        let mut params = Self::default();
+        let (params, _) = Self::parse(path, query)?;
+        Ok(params)
+    }
 
-        params.key = path
-            .split("/")
-            .last()
-            .ok_or(eyre::eyre!("Invalid audio path"))?
-            .to_string();
+    /// Like [`Params::from_path`], but rejects unknown keys and values that
+    /// do not parse or are out of range instead of silently dropping them.
+    pub fn from_path_strict(
+        path: String,
+        query: HashMap<String, String>,
+    ) -> Result<Self, ParamsError> {
+        let (params, errors) = Self::parse(path, query).map_err(|e| ParamsError {
+            errors: vec![FieldError {
+                key: "path".to_string(),
+                value: String::new(),
+                reason: e.to_string(),
+                allowed: None,
+            }],
+        })?;
 
-        for (key, value) in query {
-            match key.as_str() {
+        if !errors.is_empty() {
+            return Err(ParamsError { errors });
+        }
+
+        Ok(params)
+    }
+
+    /// The last path component is the (percent-decoded) audio key; earlier
+    /// components such as `format:ogg` are parsed as operations and merged
+    /// with the query string.
+    fn parse(path: String, query: HashMap<String, String>) -> Result<(Self, Vec<FieldError>)> {
+        let (key, segments) = path
+            .split('/')
+            .collect::<Vec<_>>()
+            .split_last()
+            .map(|(key, segments)| (*key, segments.to_vec()))
+            .ok_or(eyre::eyre!("Invalid audio path"))?;
+
+        let key = urlencoding::decode(key)
+            .map(|key| key.into_owned())
+            .unwrap_or_else(|_| key.to_string());
+        // Checked after decoding, since `..%2F` only becomes `../` here
+        if !is_contained(&key) {
+            return Err(eyre::eyre!(
+                "Invalid audio key {:?}: must not contain '..' segments, a leading '/' or NUL bytes",
+                key
+            ));
+        }
+
+        let mut params = Self {
+            key,
+            ..Default::default()
+        };
+
+        let mut p = FieldParser::default();
+        let mut pairs = Vec::new();
+        for segment in segments {
+            match segment::parse(segment) {
+                Some(Ok(segment)) => pairs.extend(segment.into_pairs()),
+                Some(Err(reason)) => p.reject("path", segment, reason, None),
+                None => {}
+            }
+        }
+
+        let mut seen = HashSet::new();
+        for (key, value) in &pairs {
+            if key == EXPIRES_PARAM {
+                p.reject(key, value, "must be given in the query string", None);
+            } else if !seen.insert(key.clone()) || query.contains_key(key) {
+                p.reject(key, value, "duplicate parameter", None);
+            }
+        }
+
+        let mut custom_filters = Vec::new();
+        let mut custom_options = Vec::new();
+        for (key, value) in pairs.into_iter().chain(query) {
+            let k = key.as_str();
+            let v = value.as_str();
+            match k {
                 "format" => {
-                    params.format = Some(value.parse::<AudioFormat>().unwrap_or(AudioFormat::Mp3))
+                    params.format = match v.parse::<AudioFormat>() {
+                        Ok(format) if format != AudioFormat::Unknown => Some(format),
+                        _ => {
+                            p.reject(k, v, "unknown format", Some(join(SUPPORTED_FORMATS)));
+                            Some(AudioFormat::Mp3)
+                        }
+                    }
                 }
-                "codec" => params.codec = Some(value.to_string()),
-                "sample_rate" => params.sample_rate = value.parse().ok(),
-                "channels" => params.channels = value.parse().ok(),
-                "bit_rate" => params.bit_rate = value.parse().ok(),
-                "bit_depth" => params.bit_depth = value.parse().ok(),
-                "quality" => params.quality = value.parse().ok(),
-                "compression_level" => params.compression_level = value.parse().ok(),
-                "start_time" => params.start_time = value.parse().ok(),
-                "duration" => params.duration = value.parse().ok(),
-                "speed" => params.speed = value.parse().ok(),
-                "reverse" => params.reverse = Some(value == "true" || value == "1"),
-                "volume" => params.volume = value.parse().ok(),
-                "normalize" => params.normalize = Some(value == "true" || value == "1"),
-                "normalize_level" => params.normalize_level = value.parse().ok(),
-                "lowpass" => params.lowpass = value.parse().ok(),
-                "highpass" => params.highpass = value.parse().ok(),
-                "bandpass" => params.bandpass = Some(value.to_string()),
-                "bass" => params.bass = value.parse().ok(),
-                "treble" => params.treble = value.parse().ok(),
-                "echo" => params.echo = Some(value.to_string()),
-                "chorus" => params.chorus = Some(value.to_string()),
-                "flanger" => params.flanger = Some(value.to_string()),
-                "phaser" => params.phaser = Some(value.to_string()),
-                "tremolo" => params.tremolo = Some(value.to_string()),
-                "compressor" => params.compressor = Some(value.to_string()),
-                "noise_reduction" => params.noise_reduction = Some(value.to_string()),
-                "fade_in" => params.fade_in = value.parse().ok(),
-                "fade_out" => params.fade_out = value.parse().ok(),
-                "cross_fade" => params.cross_fade = value.parse().ok(),
+                "codec" => params.codec = p.identifier(k, v),
+                "sample_rate" => params.sample_rate = p.one_of(k, v, SUPPORTED_SAMPLE_RATES),
+                "channels" => params.channels = p.int(k, v, 1, 8),
+                "bit_rate" => params.bit_rate = p.int(k, v, 8, 640),
+                "bit_depth" => params.bit_depth = p.one_of(k, v, SUPPORTED_BIT_DEPTHS),
+                "quality" => params.quality = p.float(k, v, (0.0, 10.0)),
+                "compression_level" => params.compression_level = p.int(k, v, 0, 12),
+                "start_time" => params.start_time = p.float(k, v, NON_NEGATIVE),
+                "duration" => params.duration = p.float(k, v, NON_NEGATIVE),
+                "speed" => params.speed = p.float(k, v, SPEED_RANGE),
+                "reverse" => params.reverse = p.boolean(k, v),
+                "volume" => params.volume = p.float(k, v, VOLUME_RANGE),
+                "normalize" => params.normalize = p.boolean(k, v),
+                "normalize_level" => params.normalize_level = p.float(k, v, NORMALIZE_LEVEL_RANGE),
+                "lowpass" => params.lowpass = p.float(k, v, FREQUENCY_RANGE),
+                "highpass" => params.highpass = p.float(k, v, FREQUENCY_RANGE),
+                "bandpass" => params.bandpass = Some(value),
+                "bass" => params.bass = p.float(k, v, GAIN_RANGE),
+                "treble" => params.treble = p.float(k, v, GAIN_RANGE),
+                "echo" => params.echo = Some(value),
+                "chorus" => params.chorus = Some(value),
+                "flanger" => params.flanger = Some(value),
+                "phaser" => params.phaser = Some(value),
+                "tremolo" => params.tremolo = Some(value),
+                "compressor" => params.compressor = Some(value),
+                "noise_reduction" => params.noise_reduction = Some(value),
+                "fade_in" => params.fade_in = p.float(k, v, NON_NEGATIVE),
+                "fade_out" => params.fade_out = p.float(k, v, NON_NEGATIVE),
+                "cross_fade" => params.cross_fade = p.float(k, v, NON_NEGATIVE),
+                "chain" => params.chain = parse_chain(v, &mut p),
+                // Checked along with the URL signature
+                EXPIRES_PARAM => {}
                 _ => {
-                    if key.starts_with("tag_") {
-                        let tag_key = key.trim_start_matches("tag_").to_string();
+                    if let Some(tag_key) = k.strip_prefix("tag_") {
                         params
                             .tags
                             .get_or_insert_with(HashMap::new)
-                            .insert(tag_key, value.to_string());
-                    } else if key.starts_with("filter_") {
-                        params
-                            .custom_filters
-                            .get_or_insert_with(Vec::new)
-                            .push(value);
-                    } else if key.starts_with("option_") {
-                        params
-                            .custom_options
-                            .get_or_insert_with(Vec::new)
-                            .push(value);
+                            .insert(tag_key.to_string(), value);
+                    } else if let Some(suffix) = k.strip_prefix("filter_") {
+                        custom_filters.push((suffix.to_string(), value));
+                    } else if let Some(suffix) = k.strip_prefix("option_") {
+                        custom_options.push((suffix.to_string(), value));
+                    } else {
+                        p.reject(k, v, "unknown parameter", None);
                     }
                 }
             }
         }
 
-        Ok(params)
+        params.custom_filters = sorted_by_suffix(custom_filters);
+        params.custom_options = sorted_by_suffix(custom_options);
+
+        Ok((params, p.into_errors()))
     }
 
-    pub fn to_query(&self) -> HashMap<String, Vec<String>> {
-        let mut query: HashMap<String, Vec<String>> = HashMap::new();
+    /// The params as query parameters, keyed in sorted order with numbers
+    /// and booleans in a canonical form.
+    pub fn to_query(&self) -> BTreeMap<String, Vec<String>> {
+        let mut query: BTreeMap<String, Vec<String>> = BTreeMap::new();
 
         if let Some(format) = &self.format {
             query.insert("format".to_string(), vec![format.to_string()]);
@@ -286,46 +398,46 @@ impl Params {
             query.insert("bit_depth".to_string(), vec![depth.to_string()]);
         }
         if let Some(quality) = self.quality {
-            query.insert("quality".to_string(), vec![quality.to_string()]);
+            query.insert("quality".to_string(), vec![canonical_float(quality)]);
         }
         if let Some(level) = self.compression_level {
             query.insert("compression_level".to_string(), vec![level.to_string()]);
         }
         if let Some(time) = self.start_time {
-            query.insert("start_time".to_string(), vec![time.to_string()]);
+            query.insert("start_time".to_string(), vec![canonical_float(time)]);
         }
         if let Some(duration) = self.duration {
-            query.insert("duration".to_string(), vec![duration.to_string()]);
+            query.insert("duration".to_string(), vec![canonical_float(duration)]);
         }
         if let Some(speed) = self.speed {
-            query.insert("speed".to_string(), vec![speed.to_string()]);
+            query.insert("speed".to_string(), vec![canonical_float(speed)]);
         }
         if let Some(reverse) = self.reverse {
             query.insert("reverse".to_string(), vec![reverse.to_string()]);
         }
         if let Some(volume) = self.volume {
-            query.insert("volume".to_string(), vec![volume.to_string()]);
+            query.insert("volume".to_string(), vec![canonical_float(volume)]);
         }
         if let Some(normalize) = self.normalize {
             query.insert("normalize".to_string(), vec![normalize.to_string()]);
         }
         if let Some(level) = self.normalize_level {
-            query.insert("normalize_level".to_string(), vec![level.to_string()]);
+            query.insert("normalize_level".to_string(), vec![canonical_float(level)]);
         }
         if let Some(freq) = self.lowpass {
-            query.insert("lowpass".to_string(), vec![freq.to_string()]);
+            query.insert("lowpass".to_string(), vec![canonical_float(freq)]);
         }
         if let Some(freq) = self.highpass {
-            query.insert("highpass".to_string(), vec![freq.to_string()]);
+            query.insert("highpass".to_string(), vec![canonical_float(freq)]);
         }
         if let Some(band) = &self.bandpass {
             query.insert("bandpass".to_string(), vec![band.clone()]);
         }
         if let Some(bass) = self.bass {
-            query.insert("bass".to_string(), vec![bass.to_string()]);
+            query.insert("bass".to_string(), vec![canonical_float(bass)]);
         }
         if let Some(treble) = self.treble {
-            query.insert("treble".to_string(), vec![treble.to_string()]);
+            query.insert("treble".to_string(), vec![canonical_float(treble)]);
         }
         if let Some(echo) = &self.echo {
             query.insert("echo".to_string(), vec![echo.clone()]);
@@ -349,19 +461,22 @@ impl Params {
             query.insert("noise_reduction".to_string(), vec![nr.clone()]);
         }
         if let Some(fade) = self.fade_in {
-            query.insert("fade_in".to_string(), vec![fade.to_string()]);
+            query.insert("fade_in".to_string(), vec![canonical_float(fade)]);
         }
         if let Some(fade) = self.fade_out {
-            query.insert("fade_out".to_string(), vec![fade.to_string()]);
+            query.insert("fade_out".to_string(), vec![canonical_float(fade)]);
         }
         if let Some(fade) = self.cross_fade {
-            query.insert("cross_fade".to_string(), vec![fade.to_string()]);
+            query.insert("cross_fade".to_string(), vec![canonical_float(fade)]);
         }
-        if let Some(filters) = &self.custom_filters {
-            query.insert("custom_filters".to_string(), filters.clone());
+        if let Some(chain) = &self.chain {
+            query.insert("chain".to_string(), vec![format_chain(chain)]);
         }
-        if let Some(options) = &self.custom_options {
-            query.insert("custom_options".to_string(), options.clone());
+        for (i, filter) in self.custom_filters.iter().flatten().enumerate() {
+            query.insert(format!("filter_{}", i), vec![filter.clone()]);
+        }
+        for (i, option) in self.custom_options.iter().flatten().enumerate() {
+            query.insert(format!("option_{}", i), vec![option.clone()]);
         }
         if let Some(tags) = &self.tags {
             for (key, value) in tags {
@@ -376,7 +491,7 @@ impl Params {
         let mut args = Vec::new();
 
         if let Some(format) = &self.format {
-            args.extend_from_slice(&["-f".to_string(), format.to_string()]);
+            args.extend_from_slice(&["-f".to_string(), format.muxer().to_string()]);
         }
         if let Some(codec) = &self.codec {
             args.extend_from_slice(&["-c:a".to_string(), codec.clone()]);
@@ -416,76 +531,154 @@ impl Params {
     }
 
     fn collect_filters(&self) -> Vec<String> {
-        let mut filters = Vec::new();
+        let mut filters: Vec<String> = self
+            .collect_filter_ops()
+            .into_iter()
+            .map(|op| op.filter)
+            .collect();
+
+        if let Some(custom_filters) = &self.custom_filters {
+            filters.extend(custom_filters.clone());
+        }
+
+        filters
+    }
+
+    /// Built-in effects as FFmpeg filters, in the order they are applied.
+    /// Custom `filter_*` values are not included.
+    pub fn collect_filter_ops(&self) -> Vec<FilterOp> {
+        self.effects()
+            .into_iter()
+            .map(|effect| FilterOp::new(effect.name(), effect.to_filter()))
+            .collect()
+    }
+
+    /// The fixed-order effects set by individual query parameters, followed
+    /// by the `chain` steps as written.
+    pub fn effects(&self) -> Vec<Effect> {
+        let mut effects = Vec::new();
 
         if let Some(speed) = self.speed {
             if speed != 1.0 {
-                filters.push(format!("atempo={:.3}", speed));
+                effects.push(Effect::Speed(speed));
             }
         }
         if let Some(true) = self.reverse {
-            filters.push("areverse".to_string());
+            effects.push(Effect::Reverse);
         }
         if let Some(volume) = self.volume {
             if volume != 1.0 {
-                filters.push(format!("volume={:.2}", volume));
+                effects.push(Effect::Volume(volume));
             }
         }
         if let Some(true) = self.normalize {
-            let level = self.normalize_level.unwrap_or(-16.0);
-            filters.push(format!("loudnorm=I={:.1}", level));
+            effects.push(Effect::Normalize(self.normalize_level));
         }
         if let Some(freq) = self.lowpass {
-            filters.push(format!("lowpass=f={:.1}", freq));
+            effects.push(Effect::Lowpass(freq));
         }
         if let Some(freq) = self.highpass {
-            filters.push(format!("highpass=f={:.1}", freq));
+            effects.push(Effect::Highpass(freq));
         }
         if let Some(band) = &self.bandpass {
-            filters.push(format!("bandpass={}", band));
+            effects.push(Effect::Bandpass(band.clone()));
         }
         if let Some(bass) = self.bass {
-            filters.push(format!("bass=g={:.1}", bass));
+            effects.push(Effect::Bass(bass));
         }
         if let Some(treble) = self.treble {
-            filters.push(format!("treble=g={:.1}", treble));
+            effects.push(Effect::Treble(treble));
         }
         if let Some(echo) = &self.echo {
-            filters.push(format!("aecho={}", echo));
+            effects.push(Effect::Echo(echo.clone()));
         }
         if let Some(chorus) = &self.chorus {
-            filters.push(format!("chorus={}", chorus));
+            effects.push(Effect::Chorus(chorus.clone()));
         }
         if let Some(flanger) = &self.flanger {
-            filters.push(format!("flanger={}", flanger));
+            effects.push(Effect::Flanger(flanger.clone()));
         }
         if let Some(phaser) = &self.phaser {
-            filters.push(format!("aphaser={}", phaser));
+            effects.push(Effect::Phaser(phaser.clone()));
         }
         if let Some(tremolo) = &self.tremolo {
-            filters.push(format!("tremolo={}", tremolo));
+            effects.push(Effect::Tremolo(tremolo.clone()));
         }
         if let Some(compressor) = &self.compressor {
-            filters.push(format!("acompressor={}", compressor));
+            effects.push(Effect::Compressor(compressor.clone()));
         }
         if let Some(nr) = &self.noise_reduction {
-            filters.push(format!("anlmdn={}", nr));
+            effects.push(Effect::NoiseReduction(nr.clone()));
         }
         if let Some(fade) = self.fade_in {
-            filters.push(format!("afade=t=in:d={:.3}", fade));
+            effects.push(Effect::FadeIn(fade));
         }
         if let Some(fade) = self.fade_out {
-            filters.push(format!("afade=t=out:d={:.3}", fade));
+            effects.push(Effect::FadeOut(fade));
         }
         if let Some(fade) = self.cross_fade {
-            filters.push(format!("acrossfade=d={:.3}", fade));
+            effects.push(Effect::CrossFade(fade));
+        }
+        if let Some(chain) = &self.chain {
+            effects.extend(chain.iter().cloned());
         }
 
-        if let Some(custom_filters) = &self.custom_filters {
-            filters.extend(custom_filters.clone());
+        effects
+    }
+
+    /// Renders the params as path segments, e.g.
+    /// `trim:0:10/filters:reverse():fade_in(2)/format:ogg/song.mp3`.
+    pub fn to_path(&self) -> String {
+        let mut query = self.to_query();
+        let mut segments = Vec::new();
+
+        if let Some(start) = self.start_time {
+            query.remove("start_time");
+            match self.duration {
+                Some(duration) => {
+                    query.remove("duration");
+                    segments.push(format!(
+                        "trim:{}:{}",
+                        canonical_float(start),
+                        canonical_float(duration)
+                    ));
+                }
+                None => segments.push(format!("trim:{}", canonical_float(start))),
+            }
         }
 
-        filters
+        if let Some(chain) = self.chain.as_ref().filter(|chain| !chain.is_empty()) {
+            query.remove("chain");
+            let filters = chain
+                .iter()
+                .map(|effect| {
+                    let step = effect.to_string();
+                    let args = step.split_once(':').map_or("", |(_, args)| args);
+                    format!("{}({})", effect.name(), encode(args))
+                })
+                .collect::<Vec<_>>();
+            segments.push(format!("filters:{}", filters.join(":")));
+        }
+
+        // `key:` is not an operation segment, so empty values stay in the query
+        let mut empty = Vec::new();
+        for (key, values) in query {
+            for value in values {
+                if value.is_empty() {
+                    empty.push(format!("{}=", key));
+                } else {
+                    segments.push(format!("{}:{}", key, encode(&value)));
+                }
+            }
+        }
+
+        segments.push(encode(&self.key));
+        let path = segments.join("/");
+        if empty.is_empty() {
+            path
+        } else {
+            format!("{}?{}", path, empty.join("&"))
+        }
     }
 
     pub fn to_unsafe_string(p: &Params) -> String {
@@ -501,6 +694,7 @@ impl Params {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
     use std::collections::HashMap;
 
     #[test]
@@ -692,4 +886,336 @@ mod tests {
         assert_eq!(tags.get("artist").unwrap(), "Test Artist");
         assert_eq!(tags.get("album").unwrap(), "Test Album");
     }
+
+    #[test]
+    fn test_from_path_strict() {
+        let mut query = HashMap::new();
+        query.insert("format".to_string(), "ogg".to_string());
+        query.insert("speed".to_string(), "1.5".to_string());
+        query.insert("sample_rate".to_string(), "48000".to_string());
+        query.insert("filter_1".to_string(), "vibrato=f=5".to_string());
+        query.insert("tag_artist".to_string(), "Test Artist".to_string());
+
+        let params = Params::from_path_strict("test.mp3".to_string(), query).unwrap();
+
+        assert_eq!(params.format, Some(AudioFormat::Ogg));
+        assert_eq!(params.speed, Some(1.5));
+        assert_eq!(params.sample_rate, Some(48000));
+    }
+
+    #[test]
+    fn test_from_path_strict_rejects_invalid_values() {
+        let mut query = HashMap::new();
+        query.insert("speed".to_string(), "fast".to_string());
+        query.insert("sample_rate".to_string(), "44000".to_string());
+        query.insert("format".to_string(), "aiff".to_string());
+        query.insert("colour".to_string(), "blue".to_string());
+        query.insert("reverse".to_string(), "true".to_string());
+
+        let err = Params::from_path_strict("test.mp3".to_string(), query.clone()).unwrap_err();
+
+        let mut keys: Vec<&str> = err.errors.iter().map(|e| e.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, vec!["colour", "format", "sample_rate", "speed"]);
+
+        let speed = err.errors.iter().find(|e| e.key == "speed").unwrap();
+        assert_eq!(speed.value, "fast");
+        assert_eq!(speed.allowed.as_deref(), Some("0.5 to 100"));
+
+        // The lenient parser keeps its previous behaviour
+        let params = Params::from_path("test.mp3".to_string(), query).unwrap();
+        assert_eq!(params.speed, None);
+        assert_eq!(params.sample_rate, Some(44000));
+        assert_eq!(params.format, Some(AudioFormat::Mp3));
+        assert_eq!(params.reverse, Some(true));
+    }
+
+    #[test]
+    fn test_chain_order_is_preserved() {
+        let parse = |chain: &str| {
+            let mut query = HashMap::new();
+            query.insert("chain".to_string(), chain.to_string());
+            query.insert("volume".to_string(), "0.5".to_string());
+            Params::from_path_strict("test.mp3".to_string(), query).unwrap()
+        };
+
+        let echo_first = parse("echo:0.8:0.9:1000:0.3|reverse");
+        let reverse_first = parse("reverse|echo:0.8:0.9:1000:0.3");
+
+        assert_eq!(
+            echo_first.collect_filters(),
+            vec!["volume=0.50", "aecho=0.8:0.9:1000:0.3", "areverse"]
+        );
+        assert_eq!(
+            reverse_first.collect_filters(),
+            vec!["volume=0.50", "areverse", "aecho=0.8:0.9:1000:0.3"]
+        );
+        assert_ne!(echo_first.to_query(), reverse_first.to_query());
+        assert_eq!(
+            reverse_first.to_query().get("chain").unwrap(),
+            &vec!["reverse|echo:0.8:0.9:1000:0.3".to_string()]
+        );
+
+        let round_trip = Params::from_str(&reverse_first.to_string()).unwrap();
+        assert_eq!(round_trip.chain, reverse_first.chain);
+    }
+
+    #[test]
+    fn test_path_segments() {
+        let params =
+            Params::from_str("/unsafe/trim:0:10/filters:reverse():fade_in(2)/format:ogg/song.mp3")
+                .unwrap();
+
+        assert_eq!(params.key, "song.mp3");
+        assert_eq!(params.start_time, Some(0.0));
+        assert_eq!(params.duration, Some(10.0));
+        assert_eq!(params.format, Some(AudioFormat::Ogg));
+        assert_eq!(
+            params.chain,
+            Some(vec![Effect::Reverse, Effect::FadeIn(2.0)])
+        );
+
+        // Equivalent to the query form, so the same result storage hash
+        let query = Params::from_str(
+            "/unsafe/song.mp3?start_time=0&duration=10&chain=reverse|fade_in:2&format=ogg",
+        )
+        .unwrap();
+        assert_eq!(params, query);
+        assert_eq!(params.to_string(), query.to_string());
+    }
+
+    #[test]
+    fn test_keys_may_not_escape_storage() {
+        for path in [
+            "unsafe/..%2F..%2Fsecret.mp3",
+            "unsafe/%2Fetc%2Fsecret.mp3",
+            "unsafe/song.mp3%00.txt",
+        ] {
+            assert!(Params::from_str(path).is_err(), "{}", path);
+            let err = Params::from_path_strict(path.to_string(), HashMap::new()).unwrap_err();
+            assert_eq!(err.errors[0].key, "path");
+        }
+
+        let params = Params::from_str("unsafe/dir%2Fsong.mp3").unwrap();
+        assert_eq!(params.key, "dir/song.mp3");
+    }
+
+    #[test]
+    fn test_path_segments_strict_errors() {
+        let mut query = HashMap::new();
+        query.insert("format".to_string(), "wav".to_string());
+
+        let err = Params::from_path_strict(
+            "unsafe/trim:x/format:ogg/volume:loud/test.mp3".to_string(),
+            query,
+        )
+        .unwrap_err();
+
+        let summary: Vec<(&str, &str)> = err
+            .errors
+            .iter()
+            .map(|e| (e.key.as_str(), e.reason.as_str()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("path", "malformed trim segment"),
+                ("format", "duplicate parameter"),
+                ("volume", "invalid number"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expires_is_not_a_param() {
+        let params = Params::from_str("/test.mp3?expires=1700000000&format=ogg").unwrap();
+        assert_eq!(params.to_string(), "test.mp3?format=ogg");
+
+        let err = Params::from_path_strict(
+            "unsafe/expires:1700000000/test.mp3".to_string(),
+            HashMap::new(),
+        )
+        .unwrap_err();
+        assert_eq!(err.errors[0].reason, "must be given in the query string");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut tags = HashMap::new();
+        tags.insert("artist".to_string(), "Test Artist".to_string());
+        let params = Params {
+            key: "https://example.com/audio/test song.mp3".to_string(),
+            format: Some(AudioFormat::Flac),
+            sample_rate: Some(48000),
+            start_time: Some(1.5),
+            duration: Some(30.0),
+            reverse: Some(false),
+            echo: Some("0.8:0.9:1000:0.3".to_string()),
+            chain: Some(vec![
+                Effect::Normalize(Some(-14.0)),
+                Effect::Bandpass("f=1000:width_type=h:w=200".to_string()),
+            ]),
+            custom_filters: Some(vec!["vibrato=f=5".to_string(), "areverse".to_string()]),
+            custom_options: Some(vec!["-map_metadata".to_string(), "-1".to_string()]),
+            tags: Some(tags),
+            ..Default::default()
+        };
+
+        assert_eq!(Params::from_str(&params.to_string()).unwrap(), params);
+        assert_eq!(Params::from_str(&params.to_path()).unwrap(), params);
+        assert_eq!(
+            Params::from_str(&format!("/unsafe/{}", params.to_path())).unwrap(),
+            params
+        );
+    }
+
+    #[test]
+    fn test_to_path() {
+        let params = Params {
+            key: "song.mp3".to_string(),
+            format: Some(AudioFormat::Ogg),
+            start_time: Some(0.0),
+            duration: Some(10.0),
+            chain: Some(vec![Effect::Reverse, Effect::FadeIn(2.0)]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            params.to_path(),
+            "trim:0:10/filters:reverse():fade_in(2)/format:ogg/song.mp3"
+        );
+    }
+
+    #[test]
+    fn test_display_is_canonical() {
+        let a =
+            Params::from_str("/test.mp3?volume=0.50&reverse=1&format=ogg&tag_b=2&tag_a=1").unwrap();
+        let b = Params::from_str("/test.mp3?tag_a=1&format=ogg&reverse=true&tag_b=2&volume=.5")
+            .unwrap();
+
+        assert_eq!(
+            a.to_string(),
+            "test.mp3?format=ogg&reverse=true&tag_a=1&tag_b=2&volume=0.5"
+        );
+        assert_eq!(a.to_string(), b.to_string());
+
+        let negative_zero = Params {
+            key: "test.mp3".to_string(),
+            bass: Some(-0.0),
+            chain: Some(vec![Effect::Treble(-0.0)]),
+            ..Default::default()
+        };
+        assert_eq!(
+            negative_zero.to_string(),
+            "test.mp3?bass=0&chain=treble%3A0"
+        );
+    }
+
+    #[test]
+    fn test_custom_filters_and_options_keep_suffix_order() {
+        let mut query = HashMap::new();
+        query.insert("option_10".to_string(), "0".to_string());
+        query.insert("option_9".to_string(), "-write_xing".to_string());
+        query.insert("option_2".to_string(), "-1".to_string());
+        query.insert("option_1".to_string(), "-map_metadata".to_string());
+        query.insert("filter_b".to_string(), "areverse".to_string());
+        query.insert("filter_a".to_string(), "volume=2".to_string());
+
+        let params = Params::from_path("test.mp3".to_string(), query).unwrap();
+
+        assert_eq!(
+            params.custom_options.unwrap(),
+            vec!["-map_metadata", "-1", "-write_xing", "0"]
+        );
+        assert_eq!(params.custom_filters.unwrap(), vec!["volume=2", "areverse"]);
+    }
+
+    fn effect() -> impl Strategy<Value = Effect> {
+        let args = "[a-z0-9=:.]{0,12}[a-z0-9=.]";
+        prop_oneof![
+            (0.5..100.0f64).prop_map(Effect::Speed),
+            Just(Effect::Reverse),
+            (0.0..10.0f64).prop_map(Effect::Volume),
+            proptest::option::of(-70.0..-5.0f64).prop_map(Effect::Normalize),
+            (1.0..96000.0f64).prop_map(Effect::Lowpass),
+            (-60.0..60.0f64).prop_map(Effect::Bass),
+            args.prop_map(Effect::Bandpass),
+            args.prop_map(Effect::Echo),
+            args.prop_map(Effect::NoiseReduction),
+            (0.0..600.0f64).prop_map(Effect::FadeOut),
+        ]
+    }
+
+    fn params() -> impl Strategy<Value = Params> {
+        let encoding = (
+            proptest::option::of(proptest::sample::select(SUPPORTED_FORMATS.to_vec())),
+            proptest::option::of("[a-z0-9_]{1,10}"),
+            proptest::option::of(proptest::sample::select(SUPPORTED_SAMPLE_RATES)),
+            proptest::option::of(1..=8i32),
+            proptest::option::of(0.0..10.0f64),
+        );
+        let effects = (
+            proptest::option::of(0.0..3600.0f64),
+            proptest::option::of(0.0..3600.0f64),
+            proptest::option::of(0.5..100.0f64),
+            any::<Option<bool>>(),
+            proptest::option::of(-60.0..60.0f64),
+            proptest::option::of("\\PC{1,16}"),
+            proptest::option::of(proptest::collection::vec(effect(), 1..4)),
+        );
+        let advanced = (
+            "\\PC{1,24}".prop_filter("keys stay under storage", |key| is_contained(key)),
+            proptest::option::of(proptest::collection::vec("\\PC{1,16}", 1..3)),
+            proptest::option::of(proptest::collection::vec("[a-z0-9_-]{1,8}", 1..3)),
+            proptest::option::of(proptest::collection::hash_map(
+                "[a-z0-9_]{1,8}",
+                "\\PC{0,16}",
+                1..3,
+            )),
+        );
+
+        (encoding, effects, advanced).prop_map(
+            |(
+                (format, codec, sample_rate, channels, quality),
+                (start_time, duration, speed, reverse, bass, echo, chain),
+                (key, custom_filters, custom_options, tags),
+            )| Params {
+                key,
+                format,
+                codec,
+                sample_rate,
+                channels,
+                quality,
+                start_time,
+                duration,
+                speed,
+                reverse,
+                bass,
+                echo,
+                chain,
+                custom_filters,
+                custom_options,
+                tags,
+                ..Default::default()
+            },
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_display_round_trips(params in params()) {
+            let serialized = params.to_string();
+            let parsed = Params::from_str(&serialized).unwrap();
+
+            prop_assert_eq!(&parsed, &params);
+            prop_assert_eq!(parsed.to_string(), serialized);
+        }
+
+        #[test]
+        fn prop_path_round_trips(params in params()) {
+            let parsed = Params::from_str(&format!("/unsafe/{}", params.to_path())).unwrap();
+
+            prop_assert_eq!(parsed, params);
+        }
+    }
 }
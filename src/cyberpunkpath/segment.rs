@@ -0,0 +1,197 @@
+//! Thumbor-style path segments, e.g.
+//! `/unsafe/trim:0:10/filters:reverse():fade_in(2)/format:ogg/song.mp3`.
+//!
+//! Each segment is turned into the equivalent query parameters so both forms
+//! go through the same validation.
+
+use std::borrow::Cow;
+
+use nom::{
+    bytes::complete::{take_while, take_while1},
+    character::complete::char,
+    combinator::{all_consuming, opt},
+    multi::separated_list1,
+    number::complete::recognize_float,
+    sequence::{delimited, pair, preceded, terminated},
+    IResult,
+};
+
+/// A parsed path segment, with values still percent-encoded.
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    /// `trim:START[:DURATION]`
+    Trim {
+        start: &'a str,
+        duration: Option<&'a str>,
+    },
+    /// `filters:name(args):name(args)`, applied in order
+    Filters(Vec<(&'a str, &'a str)>),
+    /// `key:value` for any query parameter
+    Param(&'a str, &'a str),
+}
+
+impl Segment<'_> {
+    /// The query parameters this segment stands for.
+    pub fn into_pairs(self) -> Vec<(String, String)> {
+        match self {
+            Segment::Trim { start, duration } => {
+                let mut pairs = vec![("start_time".to_string(), start.to_string())];
+                if let Some(duration) = duration {
+                    pairs.push(("duration".to_string(), duration.to_string()));
+                }
+                pairs
+            }
+            Segment::Filters(filters) => {
+                let chain = filters
+                    .into_iter()
+                    .map(|(name, args)| {
+                        // thumbor separates arguments with commas
+                        let args = decode(args).replace(',', ":");
+                        if args.is_empty() {
+                            name.to_string()
+                        } else {
+                            format!("{}:{}", name, args)
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join("|");
+                vec![("chain".to_string(), chain)]
+            }
+            Segment::Param(key, value) => vec![(key.to_string(), decode(value).into_owned())],
+        }
+    }
+}
+
+/// Parses a single path segment. Returns `None` for segments that are not
+/// operations, such as the hash, `unsafe`, or directories before the key.
+pub fn parse(segment: &str) -> Option<Result<Segment<'_>, String>> {
+    let (body, keyword) = keyword(segment).ok()?;
+    if body.is_empty() {
+        return None;
+    }
+
+    let parsed = match keyword {
+        "trim" => all_consuming(trim)(body).map(|(_, segment)| segment),
+        "filters" => all_consuming(filters)(body).map(|(_, segment)| segment),
+        _ => Ok(Segment::Param(keyword, body)),
+    };
+
+    Some(parsed.map_err(|_| format!("malformed {} segment", keyword)))
+}
+
+fn keyword(input: &str) -> IResult<&str, &str> {
+    terminated(identifier, char(':'))(input)
+}
+
+fn identifier(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')(input)
+}
+
+fn trim(input: &str) -> IResult<&str, Segment<'_>> {
+    let (input, (start, duration)) =
+        pair(recognize_float, opt(preceded(char(':'), recognize_float)))(input)?;
+    Ok((input, Segment::Trim { start, duration }))
+}
+
+fn filters(input: &str) -> IResult<&str, Segment<'_>> {
+    let filter = pair(
+        identifier,
+        delimited(char('('), take_while(|c| c != '(' && c != ')'), char(')')),
+    );
+    let (input, filters) = separated_list1(char(':'), filter)(input)?;
+    Ok((input, Segment::Filters(filters)))
+}
+
+fn decode(value: &str) -> Cow<'_, str> {
+    urlencoding::decode(value).unwrap_or(Cow::Borrowed(value))
+}
+
+/// Percent-encodes a value so it can be used as (part of) a path segment.
+pub fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for b in value.bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~:=,+!*@".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(segment: &str) -> Vec<(String, String)> {
+        parse(segment).unwrap().unwrap().into_pairs()
+    }
+
+    fn pair(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_trim() {
+        assert_eq!(
+            pairs("trim:0:10.5"),
+            vec![pair("start_time", "0"), pair("duration", "10.5")]
+        );
+        assert_eq!(pairs("trim:5"), vec![pair("start_time", "5")]);
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(
+            pairs("filters:reverse():echo(0.8,0.9,1000,0.3):fade_in(2)"),
+            vec![pair("chain", "reverse|echo:0.8:0.9:1000:0.3|fade_in:2")]
+        );
+        assert_eq!(
+            pairs("filters:bandpass(f=1000%3Aw=200)"),
+            vec![pair("chain", "bandpass:f=1000:w=200")]
+        );
+    }
+
+    #[test]
+    fn test_param() {
+        assert_eq!(pairs("format:ogg"), vec![pair("format", "ogg")]);
+        assert_eq!(
+            pairs("tag_artist:Test%20Artist"),
+            vec![pair("tag_artist", "Test Artist")]
+        );
+    }
+
+    #[test]
+    fn test_non_operation_segments() {
+        assert!(parse("unsafe").is_none());
+        assert!(parse("https:").is_none());
+        assert!(parse("audio").is_none());
+        assert!(parse("Song:1.mp3").is_none());
+    }
+
+    #[test]
+    fn test_malformed_segments() {
+        assert_eq!(
+            parse("trim:a:b").unwrap(),
+            Err("malformed trim segment".to_string())
+        );
+        assert_eq!(
+            parse("filters:reverse").unwrap(),
+            Err("malformed filters segment".to_string())
+        );
+        assert_eq!(
+            parse("filters:reverse():").unwrap(),
+            Err("malformed filters segment".to_string())
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(encode("0.8:0.9"), "0.8:0.9");
+        assert_eq!(
+            encode("https://example.com/a b.mp3"),
+            "https:%2F%2Fexample.com%2Fa%20b.mp3"
+        );
+        assert_eq!(decode(&encode("a|b(c)%")), "a|b(c)%");
+    }
+}
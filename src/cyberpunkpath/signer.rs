@@ -0,0 +1,357 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, SecretString};
+use serde::Deserialize;
+use serde_json::json;
+use sha1::Sha1;
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use super::params::Params;
+use crate::config::ApplicationSettings;
+
+/// Reserved query parameter holding a unix timestamp after which a signed
+/// URL is no longer accepted. It is covered by the signature but is not
+/// part of the params.
+pub const EXPIRES_PARAM: &str = "expires";
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum AuthError {
+    #[error("Malformed signature: {0}")]
+    Malformed(String),
+
+    #[error("Signature expired at {0}")]
+    Expired(u64),
+
+    #[error("Signature does not match")]
+    Mismatch,
+
+    #[error("Unsafe URLs are disabled")]
+    UnsafeDisabled,
+}
+
+impl AuthError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Malformed(_) | AuthError::Expired(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Mismatch | AuthError::UnsafeDisabled => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            AuthError::Malformed(_) => "malformed_signature",
+            AuthError::Expired(_) => "signature_expired",
+            AuthError::Mismatch => "invalid_signature",
+            AuthError::UnsafeDisabled => "unsafe_disabled",
+        }
+    }
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+
+        (self.status_code(), Json(body)).into_response()
+    }
+}
+
+pub trait Signer: Send + Sync {
+    fn sign(&self, path: &str) -> String;
+
+    /// Checks `signature` against the signature of `path` in constant time.
+    fn verify(&self, path: &str, signature: &str) -> bool {
+        self.sign(path)
+            .as_bytes()
+            .ct_eq(signature.as_bytes())
+            .into()
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SignerType {
+    #[default]
+    Sha1,
+    Sha256,
+}
+
+/// HMAC URL signer compatible with thumbor/imagor: the signature is the
+/// URL-safe base64 HMAC of the path, optionally truncated.
+#[derive(Clone)]
+pub struct HmacSigner {
+    signer_type: SignerType,
+    secret: SecretString,
+    truncate: usize,
+}
+
+impl HmacSigner {
+    /// A `truncate` of 0 keeps the full signature.
+    pub fn new(signer_type: SignerType, secret: SecretString, truncate: usize) -> Self {
+        Self {
+            signer_type,
+            secret,
+            truncate,
+        }
+    }
+
+    fn digest(&self, path: &str) -> Vec<u8> {
+        let secret = self.secret.expose_secret().as_bytes();
+        match self.signer_type {
+            SignerType::Sha1 => {
+                let mut mac =
+                    Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+                mac.update(path.as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+            SignerType::Sha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(secret)
+                    .expect("HMAC accepts keys of any length");
+                mac.update(path.as_bytes());
+                mac.finalize().into_bytes().to_vec()
+            }
+        }
+    }
+}
+
+impl Signer for HmacSigner {
+    fn sign(&self, path: &str) -> String {
+        let mut signature = URL_SAFE.encode(self.digest(path));
+        if self.truncate > 0 {
+            signature.truncate(self.truncate);
+        }
+        signature
+    }
+}
+
+/// The active signing keys. Signatures are written as `KEY_ID.SIGNATURE`, or
+/// just `SIGNATURE`, in which case every key is tried so that links signed
+/// before a rotation keep working.
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Vec<(Option<String>, HmacSigner)>,
+}
+
+impl KeyRing {
+    /// Uses `hmac_keys` when configured, otherwise `hmac_secret`.
+    pub fn new(config: &ApplicationSettings) -> Self {
+        let signer = |secret: &SecretString| {
+            HmacSigner::new(config.signer_type, secret.clone(), config.signer_truncate)
+        };
+
+        let keys = if config.hmac_keys.is_empty() {
+            vec![(None, signer(&config.hmac_secret))]
+        } else {
+            config
+                .hmac_keys
+                .iter()
+                .map(|key| (Some(key.id.clone()), signer(&key.secret)))
+                .collect()
+        };
+
+        Self { keys }
+    }
+
+    /// Checks `signature` for `paths`, any of which may have been signed.
+    pub fn check(&self, paths: &[&str], signature: &str) -> Result<(), AuthError> {
+        let (id, signature) = match signature.split_once('.') {
+            Some((id, signature)) => (Some(id), signature),
+            None => (None, signature),
+        };
+
+        let valid = !signature.is_empty()
+            && signature
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'='));
+        if !valid {
+            return Err(AuthError::Malformed("expected URL-safe base64".to_string()));
+        }
+
+        let matches = self
+            .keys
+            .iter()
+            .filter(|(key_id, _)| id.is_none() || key_id.as_deref() == id)
+            .any(|(_, signer)| paths.iter().any(|path| signer.verify(path, signature)));
+
+        if matches {
+            Ok(())
+        } else {
+            Err(AuthError::Mismatch)
+        }
+    }
+}
+
+impl Signer for KeyRing {
+    /// Signs with the first key, prefixed with its id if it has one.
+    fn sign(&self, path: &str) -> String {
+        match self.keys.first() {
+            Some((Some(id), signer)) => format!("{}.{}", id, signer.sign(path)),
+            Some((None, signer)) => signer.sign(path),
+            None => String::new(),
+        }
+    }
+
+    fn verify(&self, path: &str, signature: &str) -> bool {
+        self.check(&[path], signature).is_ok()
+    }
+}
+
+/// The canonical string signed for `params`, with the expiry appended.
+pub fn signing_path(params: &Params, expires: Option<u64>) -> String {
+    let path = params.to_string();
+    match expires {
+        Some(expires) if path.ends_with('?') => format!("{}{}={}", path, EXPIRES_PARAM, expires),
+        Some(expires) => format!("{}&{}={}", path, EXPIRES_PARAM, expires),
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HmacKeySettings;
+
+    fn signer(signer_type: SignerType, truncate: usize) -> HmacSigner {
+        HmacSigner::new(
+            signer_type,
+            SecretString::from("my-secret".to_string()),
+            truncate,
+        )
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            signer(SignerType::Sha1, 0).sign("test.mp3?format=ogg"),
+            "4jzE6XtAM-2khIzvFAGErsr9yBo="
+        );
+        assert_eq!(
+            signer(SignerType::Sha256, 0).sign("test.mp3?format=ogg"),
+            "9ekaIHDQaQL1Kd2ppXl_n20gplsFVjH_Q4eibm2GZSE="
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        assert_eq!(
+            signer(SignerType::Sha256, 8).sign("test.mp3?format=ogg"),
+            "9ekaIHDQ"
+        );
+        assert_eq!(
+            signer(SignerType::Sha1, 100).sign("test.mp3?format=ogg"),
+            "4jzE6XtAM-2khIzvFAGErsr9yBo="
+        );
+    }
+
+    #[test]
+    fn test_verify() {
+        let signer = signer(SignerType::Sha256, 0);
+        let signature = signer.sign("test.mp3?format=ogg");
+
+        assert!(signer.verify("test.mp3?format=ogg", &signature));
+        assert!(!signer.verify("test.mp3?format=wav", &signature));
+        assert!(!signer.verify("test.mp3?format=ogg", &signature[..10]));
+        assert!(!signer.verify("test.mp3?format=ogg", ""));
+    }
+
+    fn key_ring(keys: &[(&str, &str)]) -> KeyRing {
+        KeyRing::new(&ApplicationSettings {
+            hmac_keys: keys
+                .iter()
+                .map(|(id, secret)| HmacKeySettings {
+                    id: id.to_string(),
+                    secret: SecretString::from(secret.to_string()),
+                })
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_key_ring_rotation() {
+        let old = key_ring(&[("k1", "old-secret")]);
+        let rotated = key_ring(&[("k2", "new-secret"), ("k1", "old-secret")]);
+
+        let old_signature = old.sign("test.mp3?");
+        assert!(old_signature.starts_with("k1."));
+        assert_eq!(rotated.check(&["test.mp3?"], &old_signature), Ok(()));
+
+        // Without a key id every key is tried
+        let (_, bare) = old_signature.split_once('.').unwrap();
+        assert_eq!(rotated.check(&["test.mp3?"], bare), Ok(()));
+
+        let new_signature = rotated.sign("test.mp3?");
+        assert!(new_signature.starts_with("k2."));
+        assert_eq!(
+            old.check(&["test.mp3?"], &new_signature),
+            Err(AuthError::Mismatch)
+        );
+
+        // The key id must match the key that produced the signature
+        assert_eq!(
+            rotated.check(&["test.mp3?"], &format!("k2.{}", bare)),
+            Err(AuthError::Mismatch)
+        );
+    }
+
+    #[test]
+    fn test_key_ring_falls_back_to_hmac_secret() {
+        let ring = KeyRing::new(&ApplicationSettings {
+            hmac_secret: SecretString::from("my-secret".to_string()),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            ring.sign("test.mp3?format=ogg"),
+            "4jzE6XtAM-2khIzvFAGErsr9yBo="
+        );
+    }
+
+    #[test]
+    fn test_malformed_signatures() {
+        let ring = key_ring(&[("k1", "secret")]);
+
+        assert!(matches!(
+            ring.check(&["test.mp3?"], ""),
+            Err(AuthError::Malformed(_))
+        ));
+        assert!(matches!(
+            ring.check(&["test.mp3?"], "k1."),
+            Err(AuthError::Malformed(_))
+        ));
+        assert!(matches!(
+            ring.check(&["test.mp3?"], "abc+def/"),
+            Err(AuthError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_signing_path() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(signing_path(&params, None), "test.mp3?");
+        assert_eq!(
+            signing_path(&params, Some(1700000000)),
+            "test.mp3?expires=1700000000"
+        );
+
+        let params = Params {
+            volume: Some(0.5),
+            ..params
+        };
+        assert_eq!(
+            signing_path(&params, Some(1700000000)),
+            "test.mp3?volume=0.5&expires=1700000000"
+        );
+    }
+}
@@ -0,0 +1,252 @@
+use std::{fmt::Display, str::FromStr};
+
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::json;
+use utoipa::ToSchema;
+
+pub const SUPPORTED_SAMPLE_RATES: &[i32] = &[
+    8000, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+pub const SUPPORTED_BIT_DEPTHS: &[i32] = &[8, 16, 24, 32];
+
+pub const NON_NEGATIVE: (f64, f64) = (0.0, f64::INFINITY);
+/// atempo only accepts 0.5 to 100 per instance
+pub const SPEED_RANGE: (f64, f64) = (0.5, 100.0);
+pub const VOLUME_RANGE: (f64, f64) = (0.0, 10.0);
+/// loudnorm integrated loudness target range
+pub const NORMALIZE_LEVEL_RANGE: (f64, f64) = (-70.0, -5.0);
+pub const FREQUENCY_RANGE: (f64, f64) = (1.0, 96000.0);
+pub const GAIN_RANGE: (f64, f64) = (-60.0, 60.0);
+
+/// A single rejected query parameter.
+#[derive(Serialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct FieldError {
+    pub key: String,
+    pub value: String,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<String>,
+}
+
+#[derive(thiserror::Error, Serialize, Debug, Clone, PartialEq, ToSchema)]
+#[error("Invalid params: {}", .errors.iter().map(|e| format!("{}: {}", e.key, e.reason)).collect::<Vec<_>>().join(", "))]
+pub struct ParamsError {
+    pub errors: Vec<FieldError>,
+}
+
+impl IntoResponse for ParamsError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": "invalid_params",
+            "message": self.to_string(),
+            "errors": self.errors,
+        });
+
+        (StatusCode::BAD_REQUEST, Json(body)).into_response()
+    }
+}
+
+/// Parses raw query values, recording a [`FieldError`] for every value that
+/// does not parse or falls outside its allowed range.
+///
+/// Values that parse but are out of range are still returned so that the
+/// lenient parser keeps its historical behaviour; callers in strict mode
+/// reject the whole request if any error was recorded.
+#[derive(Debug, Default)]
+pub struct FieldParser {
+    errors: Vec<FieldError>,
+}
+
+impl FieldParser {
+    pub fn into_errors(self) -> Vec<FieldError> {
+        self.errors
+    }
+
+    pub fn reject(
+        &mut self,
+        key: &str,
+        value: &str,
+        reason: impl Into<String>,
+        allowed: Option<String>,
+    ) {
+        self.errors.push(FieldError {
+            key: key.to_string(),
+            value: value.to_string(),
+            reason: reason.into(),
+            allowed,
+        });
+    }
+
+    pub fn float(&mut self, key: &str, value: &str, (min, max): (f64, f64)) -> Option<f64> {
+        let allowed = if max.is_finite() {
+            format!("{} to {}", min, max)
+        } else {
+            format!(">= {}", min)
+        };
+
+        let parsed = self.parse::<f64>(key, value, &allowed)?;
+        if !parsed.is_finite() {
+            self.reject(key, value, "must be a finite number", Some(allowed));
+            return None;
+        }
+        if parsed < min || parsed > max {
+            self.reject(key, value, "out of range", Some(allowed));
+        }
+        Some(parsed)
+    }
+
+    pub fn int(&mut self, key: &str, value: &str, min: i32, max: i32) -> Option<i32> {
+        let allowed = format!("{} to {}", min, max);
+        let parsed = self.parse::<i32>(key, value, &allowed)?;
+        if parsed < min || parsed > max {
+            self.reject(key, value, "out of range", Some(allowed));
+        }
+        Some(parsed)
+    }
+
+    pub fn one_of(&mut self, key: &str, value: &str, choices: &[i32]) -> Option<i32> {
+        let allowed = join(choices);
+        let parsed = self.parse::<i32>(key, value, &allowed)?;
+        if !choices.contains(&parsed) {
+            self.reject(key, value, "unsupported value", Some(allowed));
+        }
+        Some(parsed)
+    }
+
+    /// Lenient parsing treats anything other than `true`/`1` as `false`.
+    pub fn boolean(&mut self, key: &str, value: &str) -> Option<bool> {
+        match value {
+            "true" | "1" => Some(true),
+            "false" | "0" => Some(false),
+            _ => {
+                self.reject(
+                    key,
+                    value,
+                    "must be a boolean",
+                    Some(join(["true", "false", "1", "0"])),
+                );
+                Some(false)
+            }
+        }
+    }
+
+    /// Identifiers such as codec names, passed straight to FFmpeg.
+    pub fn identifier(&mut self, key: &str, value: &str) -> Option<String> {
+        let valid = !value.is_empty()
+            && value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !valid {
+            self.reject(
+                key,
+                value,
+                "invalid identifier",
+                Some("[A-Za-z0-9_-]+".to_string()),
+            );
+        }
+        Some(value.to_string())
+    }
+
+    fn parse<T: FromStr>(&mut self, key: &str, value: &str, allowed: &str) -> Option<T> {
+        let parsed = value.parse::<T>().ok();
+        if parsed.is_none() {
+            self.reject(key, value, "invalid number", Some(allowed.to_string()));
+        }
+        parsed
+    }
+}
+
+/// Shortest representation that parses back to the same value, with `-0`
+/// written as `0`.
+pub fn canonical_float(value: f64) -> String {
+    if value == 0.0 {
+        "0".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn join<T: Display>(values: impl IntoIterator<Item = T>) -> String {
+    values
+        .into_iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_float_out_of_range_is_recorded() {
+        let mut parser = FieldParser::default();
+
+        assert_eq!(parser.float("speed", "200", SPEED_RANGE), Some(200.0));
+        assert_eq!(parser.float("speed", "fast", SPEED_RANGE), None);
+        assert_eq!(parser.float("fade_in", "NaN", NON_NEGATIVE), None);
+
+        let errors = parser.into_errors();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].reason, "out of range");
+        assert_eq!(errors[0].allowed.as_deref(), Some("0.5 to 100"));
+        assert_eq!(errors[1].reason, "invalid number");
+        assert_eq!(errors[2].allowed.as_deref(), Some(">= 0"));
+    }
+
+    #[test]
+    fn test_one_of() {
+        let mut parser = FieldParser::default();
+
+        assert_eq!(
+            parser.one_of("sample_rate", "44100", SUPPORTED_SAMPLE_RATES),
+            Some(44100)
+        );
+        assert!(parser.into_errors().is_empty());
+
+        let mut parser = FieldParser::default();
+        assert_eq!(
+            parser.one_of("sample_rate", "44000", SUPPORTED_SAMPLE_RATES),
+            Some(44000)
+        );
+        assert_eq!(parser.into_errors()[0].reason, "unsupported value");
+    }
+
+    #[test]
+    fn test_boolean() {
+        let mut parser = FieldParser::default();
+
+        assert_eq!(parser.boolean("reverse", "1"), Some(true));
+        assert_eq!(parser.boolean("reverse", "false"), Some(false));
+        assert!(parser.into_errors().is_empty());
+
+        let mut parser = FieldParser::default();
+        assert_eq!(parser.boolean("reverse", "yes"), Some(false));
+        assert_eq!(parser.into_errors().len(), 1);
+    }
+
+    #[test]
+    fn test_canonical_float() {
+        assert_eq!(canonical_float(-0.0), "0");
+        assert_eq!(canonical_float(1.0), "1");
+        assert_eq!(canonical_float(0.1 + 0.2), "0.30000000000000004");
+        assert_eq!(canonical_float(-14.5), "-14.5");
+    }
+
+    #[test]
+    fn test_identifier() {
+        let mut parser = FieldParser::default();
+
+        parser.identifier("codec", "pcm_s16le");
+        parser.identifier("codec", "libmp3lame -y");
+
+        let errors = parser.into_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].value, "libmp3lame -y");
+    }
+}
@@ -0,0 +1,95 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use crate::{
+    cyberpunkpath::{signer::AuthError, validation::ParamsError},
+    processor::policy::PolicyError,
+};
+
+#[derive(thiserror::Error, Debug)]
+pub enum JobError {
+    #[error("No job with id {0}")]
+    NotFound(String),
+
+    #[error("Job {0} has already finished")]
+    Finished(String),
+
+    #[error("Missing or invalid job token")]
+    InvalidToken,
+
+    #[error("Job store failed: {0}")]
+    Store(#[from] color_eyre::eyre::Error),
+
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+
+    #[error(transparent)]
+    Params(#[from] ParamsError),
+
+    #[error(transparent)]
+    Policy(#[from] PolicyError),
+}
+
+impl JobError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            JobError::NotFound(_) => StatusCode::NOT_FOUND,
+            JobError::Finished(_) => StatusCode::CONFLICT,
+            JobError::InvalidToken => StatusCode::UNAUTHORIZED,
+            JobError::Store(_) => StatusCode::SERVICE_UNAVAILABLE,
+            JobError::Auth(e) => e.status_code(),
+            JobError::Params(_) | JobError::Policy(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn code(&self) -> &'static str {
+        match self {
+            JobError::NotFound(_) => "job_not_found",
+            JobError::Finished(_) => "job_finished",
+            JobError::InvalidToken => "invalid_job_token",
+            JobError::Store(_) => "job_store_unavailable",
+            JobError::Auth(_) | JobError::Params(_) | JobError::Policy(_) => "invalid_request",
+        }
+    }
+}
+
+/// Signature and params errors are answered the way requests for audio are.
+impl IntoResponse for JobError {
+    fn into_response(self) -> Response {
+        match self {
+            JobError::Auth(e) => e.into_response(),
+            JobError::Params(e) => e.into_response(),
+            JobError::Policy(e) => <(StatusCode, String)>::from(e).into_response(),
+            e => {
+                let body = json!({
+                    "error": e.code(),
+                    "message": e.to_string(),
+                });
+
+                (e.status_code(), Json(body)).into_response()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_responses() {
+        let cases = [
+            (JobError::NotFound("a".to_string()), StatusCode::NOT_FOUND),
+            (JobError::Finished("a".to_string()), StatusCode::CONFLICT),
+            (JobError::InvalidToken, StatusCode::UNAUTHORIZED),
+            (JobError::Auth(AuthError::Mismatch), StatusCode::FORBIDDEN),
+        ];
+        for (err, status) in cases {
+            assert_eq!(err.into_response().status(), status);
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::http::StatusCode;
+use bytes::BytesMut;
+use chrono::{SecondsFormat, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::task::AbortHandle;
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+use super::{error::JobError, store::JobStore};
+use crate::{
+    blob::AudioBuffer,
+    cyberpunkpath::params::Params,
+    processor::{error::ProcessingError, queue::Priority},
+    response::ErrorResponse,
+    state::AppStateDyn,
+};
+
+/// How often a running job saves how far along it is.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+/// How often a running job checks whether another replica cancelled it.
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, JobStatus::Queued | JobStatus::Running)
+    }
+}
+
+/// What a running job is doing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Loading,
+    /// Waiting for a processing slot
+    Waiting,
+    Processing,
+    Saving,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, ToSchema)]
+pub struct Progress {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stage: Option<Stage>,
+    /// Share of the output produced so far, from 0 to 1, when the duration
+    /// of the input is known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fraction: Option<f64>,
+    /// Output produced so far
+    pub output_bytes: u64,
+}
+
+/// Where a finished job's output is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct JobResult {
+    /// Key in result storage
+    pub key: String,
+    /// Path serving the output
+    pub location: String,
+}
+
+/// Why a job failed, as the same request for audio would have said.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct JobFailure {
+    pub error: String,
+    pub message: String,
+}
+
+impl From<ErrorResponse> for JobFailure {
+    fn from(err: ErrorResponse) -> Self {
+        match err {
+            ErrorResponse::Processing(err) => Self {
+                error: err.code().to_string(),
+                message: err.to_string(),
+            },
+            ErrorResponse::Status(status, message) => Self {
+                error: status
+                    .canonical_reason()
+                    .unwrap_or("error")
+                    .to_lowercase()
+                    .replace(' ', "_"),
+                message,
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    /// The canonical params being processed
+    pub params: String,
+    pub progress: Progress,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<JobResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<JobFailure>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl Job {
+    pub fn new(id: String, params: String) -> Self {
+        let now = now();
+        Self {
+            id,
+            status: JobStatus::Queued,
+            params,
+            progress: Progress::default(),
+            result: None,
+            error: None,
+            created_at: now.clone(),
+            updated_at: now,
+        }
+    }
+
+    fn finish(&mut self, status: JobStatus) {
+        self.status = status;
+        self.progress.stage = None;
+        self.updated_at = now();
+    }
+}
+
+/// Tokens are only kept as digests.
+fn digest(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Processing that runs in the background, outliving the request that
+/// started it. The output is saved to result storage, where requests for
+/// the same params find it.
+#[derive(Clone)]
+pub struct Jobs {
+    store: Arc<dyn JobStore>,
+    /// Jobs running in this process
+    running: Arc<Mutex<HashMap<String, AbortHandle>>>,
+}
+
+impl Jobs {
+    pub fn new(store: Arc<dyn JobStore>) -> Self {
+        Self {
+            store,
+            running: Arc::default(),
+        }
+    }
+
+    /// Starts processing `params`, which `location` serves once done.
+    /// Returns the job with the token that gives access to it.
+    pub async fn submit(
+        &self,
+        state: &AppStateDyn,
+        params: Params,
+        location: String,
+    ) -> Result<(Job, String), JobError> {
+        state.processor.validate(&params)?;
+
+        let id = format!("{:032x}", rand::random::<u128>());
+        let token = format!("{:032x}", rand::random::<u128>());
+        let job = Job::new(id.clone(), params.to_string());
+        self.store.put(&job).await?;
+        self.store.set_token(&id, &digest(&token)).await?;
+        info!(id, params = job.params, "Starting job");
+
+        let runner = Runner {
+            state: state.clone(),
+            jobs: self.clone(),
+            job: job.clone(),
+            key: state.result_keyer.key(&params),
+            priority: Priority::for_path(&location),
+            params,
+            location,
+        };
+        // The runner removes itself when done, which mustn't happen first
+        let mut running = self.running.lock().unwrap();
+        let handle = tokio::spawn(runner.run());
+        running.insert(id, handle.abort_handle());
+
+        Ok((job, token))
+    }
+
+    pub async fn get(&self, id: &str, token: &str) -> Result<Job, JobError> {
+        self.authorize(id, token).await?;
+        self.load(id).await
+    }
+
+    /// Stops the job, wherever it runs.
+    pub async fn cancel(&self, id: &str, token: &str) -> Result<Job, JobError> {
+        self.authorize(id, token).await?;
+        let mut job = self.load(id).await?;
+        match job.status {
+            JobStatus::Cancelled => return Ok(job),
+            status if status.is_finished() => return Err(JobError::Finished(id.to_string())),
+            _ => {}
+        }
+
+        self.store.cancel(id).await?;
+        if let Some(handle) = self.running.lock().unwrap().remove(id) {
+            handle.abort();
+        }
+        info!(id, "Cancelled job");
+
+        job.finish(JobStatus::Cancelled);
+        self.store.put(&job).await?;
+        Ok(job)
+    }
+
+    /// Checks `token` against the one the job was created with. Jobs
+    /// without one are treated as unknown.
+    async fn authorize(&self, id: &str, token: &str) -> Result<(), JobError> {
+        match self.store.token(id).await? {
+            Some(expected) if expected == digest(token) => Ok(()),
+            Some(_) => Err(JobError::InvalidToken),
+            None => Err(JobError::NotFound(id.to_string())),
+        }
+    }
+
+    async fn load(&self, id: &str) -> Result<Job, JobError> {
+        let mut job = self
+            .store
+            .get(id)
+            .await?
+            .ok_or_else(|| JobError::NotFound(id.to_string()))?;
+        if !job.status.is_finished() && self.store.is_cancelled(id).await? {
+            job.status = JobStatus::Cancelled;
+            job.progress.stage = None;
+        }
+        Ok(job)
+    }
+}
+
+struct Runner {
+    state: AppStateDyn,
+    jobs: Jobs,
+    job: Job,
+    params: Params,
+    priority: Priority,
+    key: String,
+    location: String,
+}
+
+impl Runner {
+    async fn run(mut self) {
+        let cancelled = watch_cancel(self.jobs.store.clone(), self.job.id.clone());
+        let outcome = tokio::select! {
+            outcome = self.execute() => Some(outcome),
+            _ = cancelled => None,
+        };
+
+        // Output is kept even if the job was cancelled while saving it
+        let cancelled = outcome.is_none()
+            || self
+                .jobs
+                .store
+                .is_cancelled(&self.job.id)
+                .await
+                .unwrap_or(false);
+        match outcome {
+            Some(Ok(result)) if !cancelled => {
+                self.job.result = Some(result);
+                self.job.finish(JobStatus::Succeeded);
+            }
+            Some(Err(err)) if !cancelled => {
+                self.job.error = Some(err.into());
+                self.job.finish(JobStatus::Failed);
+            }
+            _ => self.job.finish(JobStatus::Cancelled),
+        }
+        info!(
+            id = self.job.id,
+            status = ?self.job.status,
+            "Job finished"
+        );
+        self.save().await;
+        self.jobs.running.lock().unwrap().remove(&self.job.id);
+    }
+
+    async fn execute(&mut self) -> Result<JobResult, ErrorResponse> {
+        self.job.status = JobStatus::Running;
+        self.enter(Stage::Loading).await;
+
+        if self.state.result_storage.get(&self.key).await.is_err() {
+            let blob = self.state.loader.load(&self.params.key).await?;
+
+            // Jobs don't mind waiting, so a full queue only delays them
+            self.enter(Stage::Waiting).await;
+            let output = loop {
+                match self
+                    .state
+                    .processor
+                    .process_stream_with_progress(&blob, &self.params, self.priority)
+                    .await
+                {
+                    Err(ProcessingError::Busy { retry_after }) => {
+                        tokio::time::sleep(Duration::from_secs(retry_after.max(1))).await
+                    }
+                    output => break output?,
+                }
+            };
+
+            // Held until it can be stored, which `max_output_size` bounds
+            self.enter(Stage::Processing).await;
+            let (mut body, progress) = (output.body, output.progress);
+            let mut data = BytesMut::new();
+            let mut saved = Instant::now();
+            loop {
+                // Progress is saved even while no output comes, e.g. when
+                // reversing
+                let next = tokio::time::timeout(PROGRESS_INTERVAL, body.next()).await;
+                match next {
+                    Ok(Some(chunk)) => {
+                        data.extend_from_slice(&chunk.map_err(|e| ProcessingError::from_io(&e))?);
+                        self.job.progress.output_bytes = data.len() as u64;
+                    }
+                    Ok(None) => break,
+                    Err(_) => {}
+                }
+                self.job.progress.fraction = progress.as_ref().map(|progress| *progress.borrow());
+                if saved.elapsed() >= PROGRESS_INTERVAL {
+                    self.save().await;
+                    saved = Instant::now();
+                }
+            }
+            self.job.progress.fraction = progress.map(|_| 1.0);
+
+            self.enter(Stage::Saving).await;
+            let blob = AudioBuffer::from_bytes_with_format(data.freeze(), output.format);
+            self.state
+                .result_storage
+                .put(&self.key, &blob)
+                .await
+                .map_err(|e| {
+                    (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        format!("Failed to save result audio: {}", e),
+                    )
+                })?;
+        }
+
+        Ok(JobResult {
+            key: self.key.clone(),
+            location: self.location.clone(),
+        })
+    }
+
+    async fn enter(&mut self, stage: Stage) {
+        self.job.progress.stage = Some(stage);
+        self.save().await;
+    }
+
+    /// Progress is only informative, so failing to save it doesn't stop the
+    /// job.
+    async fn save(&mut self) {
+        self.job.updated_at = now();
+        if let Err(e) = self.jobs.store.put(&self.job).await {
+            warn!("Failed to save job [{}]: {}", self.job.id, e);
+        }
+    }
+}
+
+/// Resolves once the job is cancelled, e.g. by another replica.
+async fn watch_cancel(store: Arc<dyn JobStore>, id: String) {
+    loop {
+        tokio::time::sleep(CANCEL_POLL_INTERVAL).await;
+        if store.is_cancelled(&id).await.unwrap_or(false) {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failures() {
+        let failure = JobFailure::from(ErrorResponse::Processing(ProcessingError::Timeout));
+        assert_eq!(failure.error, "processing_timeout");
+
+        let failure = JobFailure::from(ErrorResponse::Status(
+            StatusCode::NOT_FOUND,
+            "no such audio".to_string(),
+        ));
+        assert_eq!(
+            failure,
+            JobFailure {
+                error: "not_found".to_string(),
+                message: "no such audio".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_job_json() {
+        let mut job = Job::new("a".to_string(), "song.mp3?format=ogg".to_string());
+        job.status = JobStatus::Running;
+        job.progress.stage = Some(Stage::Processing);
+        job.progress.fraction = Some(0.5);
+
+        let json = serde_json::to_value(&job).unwrap();
+        assert_eq!(json["status"], "running");
+        assert_eq!(json["progress"]["stage"], "processing");
+        assert_eq!(json["progress"]["fraction"], 0.5);
+        assert!(json.get("result").is_none());
+        assert_eq!(serde_json::from_value::<Job>(json).unwrap(), job);
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::async_trait;
+use color_eyre::Result;
+
+use super::{jobs::Job, store::JobStore};
+
+/// Jobs kept in this process, forgotten `ttl` after their last update.
+#[derive(Debug, Clone)]
+pub struct MemoryJobStore {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    cancelled: bool,
+    token: Option<String>,
+    expires_at: Instant,
+}
+
+impl MemoryJobStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+        }
+    }
+}
+
+#[async_trait]
+impl JobStore for MemoryJobStore {
+    async fn get(&self, id: &str) -> Result<Option<Job>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(id)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .map(|entry| entry.job.clone()))
+    }
+
+    async fn put(&self, job: &Job) -> Result<()> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.expires_at > now);
+
+        let previous = entries.remove(&job.id);
+        entries.insert(
+            job.id.clone(),
+            Entry {
+                job: job.clone(),
+                cancelled: previous.as_ref().is_some_and(|entry| entry.cancelled),
+                token: previous.and_then(|entry| entry.token),
+                expires_at: now + self.ttl,
+            },
+        );
+        Ok(())
+    }
+
+    async fn cancel(&self, id: &str) -> Result<()> {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.cancelled = true;
+        }
+        Ok(())
+    }
+
+    async fn is_cancelled(&self, id: &str) -> Result<bool> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries.get(id).is_some_and(|entry| entry.cancelled))
+    }
+
+    async fn set_token(&self, id: &str, digest: &str) -> Result<()> {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(id) {
+            entry.token = Some(digest.to_string());
+        }
+        Ok(())
+    }
+
+    async fn token(&self, id: &str) -> Result<Option<String>> {
+        let entries = self.entries.lock().unwrap();
+        Ok(entries
+            .get(id)
+            .filter(|entry| entry.expires_at > Instant::now())
+            .and_then(|entry| entry.token.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::jobs::JobStatus;
+
+    fn job(id: &str) -> Job {
+        Job::new(id.to_string(), "song.mp3?format=ogg".to_string())
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get() {
+        let store = MemoryJobStore::new(Duration::from_secs(60));
+        assert_eq!(store.get("a").await.unwrap(), None);
+
+        let mut job = job("a");
+        store.put(&job).await.unwrap();
+        job.status = JobStatus::Running;
+        store.put(&job).await.unwrap();
+        assert_eq!(store.get("a").await.unwrap(), Some(job));
+    }
+
+    #[tokio::test]
+    async fn test_cancellation_survives_updates() {
+        let store = MemoryJobStore::new(Duration::from_secs(60));
+        store.cancel("a").await.unwrap();
+        assert!(!store.is_cancelled("a").await.unwrap());
+
+        store.put(&job("a")).await.unwrap();
+        store.cancel("a").await.unwrap();
+        store.put(&job("a")).await.unwrap();
+        assert!(store.is_cancelled("a").await.unwrap());
+        assert!(!store.is_cancelled("b").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_token_survives_updates() {
+        let store = MemoryJobStore::new(Duration::from_secs(60));
+        store.put(&job("a")).await.unwrap();
+        assert_eq!(store.token("a").await.unwrap(), None);
+
+        store.set_token("a", "digest").await.unwrap();
+        store.put(&job("a")).await.unwrap();
+        assert_eq!(store.token("a").await.unwrap().as_deref(), Some("digest"));
+        assert_eq!(store.token("b").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_jobs_expire() {
+        let store = MemoryJobStore::new(Duration::from_millis(10));
+        store.put(&job("a")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(store.get("a").await.unwrap(), None);
+
+        store.put(&job("b")).await.unwrap();
+        assert_eq!(store.entries.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod error;
+#[allow(clippy::module_inception)]
+pub mod jobs;
+pub mod memory;
+pub mod redis;
+pub mod store;
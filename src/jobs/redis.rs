@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::async_trait;
+use color_eyre::Result;
+
+use super::{jobs::Job, store::JobStore};
+use crate::cache::{cache::AudioCache, redis::RedisCache};
+
+const JOB_KEY_PREFIX: &str = "job:";
+
+/// Jobs kept in Redis as JSON, so that every replica sees them.
+#[derive(Clone)]
+pub struct RedisJobStore {
+    redis: Arc<dyn AudioCache>,
+    ttl: Duration,
+}
+
+impl RedisJobStore {
+    pub fn new(redis: RedisCache, ttl: Duration) -> Self {
+        Self {
+            redis: Arc::new(redis),
+            ttl,
+        }
+    }
+
+    fn key(id: &str) -> String {
+        format!("{}{}", JOB_KEY_PREFIX, id)
+    }
+
+    fn cancelled_key(id: &str) -> String {
+        format!("{}{}:cancelled", JOB_KEY_PREFIX, id)
+    }
+
+    fn token_key(id: &str) -> String {
+        format!("{}{}:token", JOB_KEY_PREFIX, id)
+    }
+}
+
+#[async_trait]
+impl JobStore for RedisJobStore {
+    async fn get(&self, id: &str) -> Result<Option<Job>> {
+        match self.redis.get(&Self::key(id)).await? {
+            Some(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn put(&self, job: &Job) -> Result<()> {
+        let data = serde_json::to_vec(job)?;
+        self.redis
+            .set(&Self::key(&job.id), &data, Some(self.ttl))
+            .await?;
+
+        // The token has to last as long as the job, however long it runs
+        let token_key = Self::token_key(&job.id);
+        if let Some(digest) = self.redis.get(&token_key).await? {
+            self.redis.set(&token_key, &digest, Some(self.ttl)).await?;
+        }
+        Ok(())
+    }
+
+    async fn cancel(&self, id: &str) -> Result<()> {
+        self.redis
+            .set(&Self::cancelled_key(id), b"1", Some(self.ttl))
+            .await
+    }
+
+    async fn is_cancelled(&self, id: &str) -> Result<bool> {
+        Ok(self.redis.get(&Self::cancelled_key(id)).await?.is_some())
+    }
+
+    async fn set_token(&self, id: &str, digest: &str) -> Result<()> {
+        self.redis
+            .set(&Self::token_key(id), digest.as_bytes(), Some(self.ttl))
+            .await
+    }
+
+    async fn token(&self, id: &str) -> Result<Option<String>> {
+        Ok(self
+            .redis
+            .get(&Self::token_key(id))
+            .await?
+            .map(|data| String::from_utf8_lossy(&data).into_owned()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+
+    #[tokio::test]
+    async fn test_token_outlives_the_original_ttl() {
+        let store = RedisJobStore {
+            redis: Arc::new(MemoryCache::new(1024 * 1024)),
+            ttl: Duration::from_millis(100),
+        };
+        let job = Job::new("a".to_string(), "song.mp3?format=ogg".to_string());
+        store.put(&job).await.unwrap();
+        store.set_token("a", "digest").await.unwrap();
+
+        // Progress updates keep the job, and its token, alive
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            store.put(&job).await.unwrap();
+        }
+        assert_eq!(store.get("a").await.unwrap(), Some(job));
+        assert_eq!(store.token("a").await.unwrap().as_deref(), Some("digest"));
+
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(store.token("a").await.unwrap(), None);
+    }
+}
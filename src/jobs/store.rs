@@ -0,0 +1,18 @@
+use axum::async_trait;
+use color_eyre::Result;
+
+use super::jobs::Job;
+
+/// Where jobs are kept between requests. Cancellations are kept apart from
+/// the job itself, so that a runner saving its progress at the same time
+/// can't undo them, and so are tokens, so that they're never sent back.
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    async fn get(&self, id: &str) -> Result<Option<Job>>;
+    async fn put(&self, job: &Job) -> Result<()>;
+    async fn cancel(&self, id: &str) -> Result<()>;
+    async fn is_cancelled(&self, id: &str) -> Result<bool>;
+    /// Keeps the digest of the token that gives access to a job.
+    async fn set_token(&self, id: &str, digest: &str) -> Result<()>;
+    async fn token(&self, id: &str) -> Result<Option<String>>;
+}
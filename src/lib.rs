@@ -2,12 +2,17 @@ pub mod blob;
 pub mod cache;
 pub mod config;
 pub mod cyberpunkpath;
+pub mod jobs;
+pub mod loader;
 pub mod metrics;
 pub mod middleware;
 pub mod processor;
+pub mod response;
 pub mod routes;
+pub mod singleflight;
 pub mod startup;
 pub mod state;
 pub mod storage;
+pub mod stream;
 pub mod tags;
 pub mod telemetry;
@@ -0,0 +1,180 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use axum::async_trait;
+
+use super::loader::{max_bytes, AudioLoader, LoaderError};
+use crate::{blob::AudioBuffer, config::FileLoaderSettings};
+
+const SCHEME: &str = "file://";
+
+/// Loads `file://` keys from a local directory. Paths are resolved relative
+/// to `base_dir` and may not escape it, including through symlinks.
+#[derive(Debug, Clone)]
+pub struct FileLoader {
+    base_dir: PathBuf,
+    max_body_size: usize,
+}
+
+impl FileLoader {
+    pub fn new(config: &FileLoaderSettings, max_body_size: usize) -> Self {
+        Self {
+            base_dir: PathBuf::from(&config.base_dir),
+            max_body_size: max_bytes(max_body_size),
+        }
+    }
+
+    async fn resolve(&self, key: &str) -> Result<PathBuf, LoaderError> {
+        let relative = key
+            .strip_prefix(SCHEME)
+            .map(|path| path.trim_start_matches('/'))
+            .filter(|path| !path.is_empty())
+            .ok_or_else(|| LoaderError::InvalidUrl(key.to_string()))?;
+
+        let io_error = |e: std::io::Error| match e.kind() {
+            ErrorKind::NotFound => LoaderError::NotFound(key.to_string()),
+            ErrorKind::PermissionDenied => LoaderError::Forbidden(key.to_string()),
+            _ => LoaderError::Upstream(e.to_string()),
+        };
+
+        let base_dir = tokio::fs::canonicalize(&self.base_dir)
+            .await
+            .map_err(|e| LoaderError::Upstream(format!("file loader base_dir: {}", e)))?;
+        let path = tokio::fs::canonicalize(base_dir.join(Path::new(relative)))
+            .await
+            .map_err(io_error)?;
+
+        if !path.starts_with(&base_dir) {
+            return Err(LoaderError::Forbidden(key.to_string()));
+        }
+
+        Ok(path)
+    }
+
+    async fn stat(&self, key: &str) -> Result<(PathBuf, std::fs::Metadata), LoaderError> {
+        let path = self.resolve(key).await?;
+
+        let metadata = tokio::fs::metadata(&path)
+            .await
+            .map_err(|e| LoaderError::Upstream(e.to_string()))?;
+        if !metadata.is_file() {
+            return Err(LoaderError::NotFound(key.to_string()));
+        }
+        Ok((path, metadata))
+    }
+}
+
+#[async_trait]
+impl AudioLoader for FileLoader {
+    fn accepts(&self, key: &str) -> bool {
+        key.starts_with(SCHEME)
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        let (path, metadata) = self.stat(key).await?;
+        if metadata.len() > self.max_body_size as u64 {
+            return Err(LoaderError::TooLarge {
+                max: self.max_body_size,
+            });
+        }
+
+        let data = tokio::fs::read(&path)
+            .await
+            .map_err(|e| LoaderError::Upstream(e.to_string()))?;
+        Ok(AudioBuffer::from_bytes(data))
+    }
+
+    async fn validator(&self, key: &str) -> Result<Option<String>, LoaderError> {
+        let (_, metadata) = self.stat(key).await?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+        Ok(modified.map(|modified| format!("mtime:{}:{}", modified.as_nanos(), metadata.len())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn loader(base_dir: &Path) -> FileLoader {
+        FileLoader::new(
+            &FileLoaderSettings {
+                base_dir: base_dir.to_string_lossy().into_owned(),
+            },
+            1,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_file_loader() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("audio")).unwrap();
+        std::fs::write(dir.path().join("audio/a.mp3"), b"audio").unwrap();
+        let loader = loader(&dir.path().join("audio"));
+
+        assert!(loader.accepts("file:///a.mp3"));
+        assert!(!loader.accepts("a.mp3"));
+
+        assert_eq!(
+            loader.load("file:///a.mp3").await.unwrap().as_ref(),
+            b"audio"
+        );
+        assert_eq!(
+            loader.load("file://a.mp3").await.unwrap().as_ref(),
+            b"audio"
+        );
+
+        let validator = loader.validator("file:///a.mp3").await.unwrap().unwrap();
+        std::fs::write(dir.path().join("audio/a.mp3"), b"changed audio").unwrap();
+        assert_ne!(
+            loader.validator("file:///a.mp3").await.unwrap(),
+            Some(validator)
+        );
+
+        let missing = loader.load("file:///missing.mp3").await.unwrap_err();
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+        let empty = loader.load("file://").await.unwrap_err();
+        assert_eq!(empty.status_code(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_file_loader_stays_in_base_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("audio")).unwrap();
+        std::fs::write(dir.path().join("secret.mp3"), b"secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(
+            dir.path().join("secret.mp3"),
+            dir.path().join("audio/link.mp3"),
+        )
+        .unwrap();
+        let loader = loader(&dir.path().join("audio"));
+
+        assert!(matches!(
+            loader.load("file:///../secret.mp3").await,
+            Err(LoaderError::Forbidden(_))
+        ));
+        #[cfg(unix)]
+        assert!(matches!(
+            loader.load("file:///link.mp3").await,
+            Err(LoaderError::Forbidden(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_file_loader_size_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("big.mp3"), vec![0u8; 1024 * 1024 + 1]).unwrap();
+
+        assert!(matches!(
+            loader(dir.path()).load("file:///big.mp3").await,
+            Err(LoaderError::TooLarge { .. })
+        ));
+    }
+}
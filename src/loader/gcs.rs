@@ -0,0 +1,87 @@
+use axum::async_trait;
+use color_eyre::Result;
+use google_cloud_storage::{
+    client::{Client, ClientConfig},
+    http::{
+        objects::{download::Range, get::GetObjectRequest},
+        Error,
+    },
+};
+
+use super::loader::{max_bytes, split_bucket_url, AudioLoader, LoaderError};
+use crate::blob::AudioBuffer;
+
+/// Loads `gs://bucket/key` sources using the default Google credentials.
+#[derive(Clone)]
+pub struct GcsLoader {
+    client: Client,
+    max_body_size: usize,
+}
+
+impl GcsLoader {
+    pub async fn new(max_body_size: usize) -> Result<Self> {
+        let config = ClientConfig::default().with_auth().await?;
+        Ok(Self {
+            client: Client::new(config),
+            max_body_size: max_bytes(max_body_size),
+        })
+    }
+}
+
+#[async_trait]
+impl AudioLoader for GcsLoader {
+    fn accepts(&self, key: &str) -> bool {
+        key.starts_with("gs://")
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        let (bucket, object) = split_bucket_url("gs", key)?;
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: object.to_string(),
+            ..Default::default()
+        };
+
+        let metadata = self
+            .client
+            .get_object(&request)
+            .await
+            .map_err(|e| client_error(&e, key))?;
+        if metadata.size > self.max_body_size as i64 {
+            return Err(LoaderError::TooLarge {
+                max: self.max_body_size,
+            });
+        }
+
+        let data = self
+            .client
+            .download_object(&request, &Range::default())
+            .await
+            .map_err(|e| client_error(&e, key))?;
+        Ok(AudioBuffer::from_bytes(data))
+    }
+
+    /// The object's generation, which changes whenever it is overwritten.
+    async fn validator(&self, key: &str) -> Result<Option<String>, LoaderError> {
+        let (bucket, object) = split_bucket_url("gs", key)?;
+        let request = GetObjectRequest {
+            bucket: bucket.to_string(),
+            object: object.to_string(),
+            ..Default::default()
+        };
+
+        let metadata = self
+            .client
+            .get_object(&request)
+            .await
+            .map_err(|e| client_error(&e, key))?;
+        Ok(Some(format!("generation:{}", metadata.generation)))
+    }
+}
+
+pub(crate) fn client_error(err: &Error, key: &str) -> LoaderError {
+    match err {
+        Error::Response(response) => LoaderError::from_status(response.code, key),
+        _ => LoaderError::Upstream(err.to_string()),
+    }
+}
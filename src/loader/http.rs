@@ -0,0 +1,714 @@
+use std::{
+    error::Error as StdError,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::async_trait;
+use bytes::Bytes;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    header, redirect, Client, StatusCode, Url,
+};
+use tracing::{instrument, warn};
+
+use super::{
+    loader::{max_bytes, AudioLoader, LoaderError},
+    source_cache::SourceCache,
+};
+use crate::{blob::AudioBuffer, config::LoaderSettings};
+
+impl LoaderError {
+    /// Finds the loader error behind a reqwest error, e.g. one raised by the
+    /// DNS resolver or redirect policy.
+    fn from_reqwest(err: reqwest::Error) -> Self {
+        let mut source = err.source();
+        while let Some(inner) = source {
+            if let Some(loader_err) = inner.downcast_ref::<LoaderError>() {
+                return loader_err.clone();
+            }
+            source = inner.source();
+        }
+
+        if err.is_timeout() {
+            LoaderError::Timeout
+        } else if err.is_redirect() {
+            LoaderError::TooManyRedirects
+        } else {
+            LoaderError::Upstream(err.to_string())
+        }
+    }
+}
+
+/// Which hosts and addresses remote audio may be fetched from.
+#[derive(Debug, Clone)]
+struct HostPolicy {
+    allowed_hosts: Vec<String>,
+    denied_hosts: Vec<String>,
+    allow_private_addresses: bool,
+}
+
+impl HostPolicy {
+    fn check_url(&self, url: &Url) -> Result<(), LoaderError> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(LoaderError::InvalidUrl(url.to_string()));
+        }
+
+        let host = url
+            .host_str()
+            .ok_or_else(|| LoaderError::InvalidUrl(url.to_string()))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase();
+
+        if self.denied_hosts.iter().any(|p| glob_match(p, &host))
+            || !(self.allowed_hosts.is_empty()
+                || self.allowed_hosts.iter().any(|p| glob_match(p, &host)))
+        {
+            return Err(LoaderError::HostNotAllowed(host));
+        }
+
+        // IP literals never reach the resolver
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            self.check_ip(ip)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_ip(&self, ip: IpAddr) -> Result<(), LoaderError> {
+        if !self.allow_private_addresses && is_private(ip) {
+            return Err(LoaderError::AddressNotAllowed(ip.to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Resolves hosts with the system resolver, dropping addresses the policy
+/// does not allow so that a public name cannot point at an internal service.
+struct GuardedResolver {
+    policy: Arc<HostPolicy>,
+}
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.policy.clone();
+        Box::pin(async move {
+            let host = name.as_str().to_string();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| policy.check_ip(addr.ip()).is_ok())
+                .collect();
+
+            if addrs.is_empty() {
+                return Err(Box::new(LoaderError::AddressNotAllowed(host)) as _);
+            }
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Fetches `http(s)://` audio within the limits of the `loader` config.
+#[derive(Clone)]
+pub struct HttpLoader {
+    client: Client,
+    policy: Arc<HostPolicy>,
+    allow_remote: bool,
+    max_body_size: usize,
+    source_cache: Option<SourceCache>,
+}
+
+impl HttpLoader {
+    pub fn new(config: &LoaderSettings) -> color_eyre::Result<Self> {
+        let policy = Arc::new(HostPolicy {
+            allowed_hosts: normalize_hosts(&config.allowed_hosts),
+            denied_hosts: normalize_hosts(&config.denied_hosts),
+            allow_private_addresses: config.allow_private_addresses,
+        });
+
+        let redirect_policy = {
+            let policy = policy.clone();
+            let max_redirects = config.max_redirects;
+            redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error(LoaderError::TooManyRedirects);
+                }
+                match policy.check_url(attempt.url()) {
+                    Ok(()) => attempt.follow(),
+                    Err(e) => attempt.error(e),
+                }
+            })
+        };
+
+        let client = Client::builder()
+            // A proxy would resolve hosts itself, bypassing the resolver checks
+            .no_proxy()
+            .dns_resolver(Arc::new(GuardedResolver {
+                policy: policy.clone(),
+            }))
+            .redirect(redirect_policy)
+            .timeout(Duration::from_secs(config.timeout))
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .build()?;
+
+        Ok(Self {
+            client,
+            policy,
+            allow_remote: config.allow_remote,
+            max_body_size: max_bytes(config.max_body_size),
+            source_cache: None,
+        })
+    }
+
+    pub fn with_source_cache(mut self, source_cache: SourceCache) -> Self {
+        self.source_cache = Some(source_cache);
+        self
+    }
+
+    pub fn is_remote(key: &str) -> bool {
+        key.starts_with("https://") || key.starts_with("http://")
+    }
+
+    #[instrument(skip(self))]
+    pub async fn fetch(&self, url: &str) -> Result<AudioBuffer, LoaderError> {
+        if !self.allow_remote {
+            return Err(LoaderError::RemoteDisabled);
+        }
+
+        let url = Url::parse(url).map_err(|_| LoaderError::InvalidUrl(url.to_string()))?;
+        self.policy.check_url(&url)?;
+
+        let cached = match &self.source_cache {
+            Some(source_cache) => source_cache.get(url.as_str()).await,
+            None => None,
+        };
+        if let Some(cached) = cached.as_ref().filter(|cached| cached.is_fresh()) {
+            return Ok(AudioBuffer::from_bytes(cached.body()));
+        }
+
+        let mut request = self.client.get(url.clone());
+        if let Some(cached) = &cached {
+            request = request.headers(cached.validators());
+        }
+        let mut response = request.send().await.map_err(LoaderError::from_reqwest)?;
+
+        let status = response.status();
+        if let (StatusCode::NOT_MODIFIED, Some(source_cache), Some(cached)) =
+            (status, &self.source_cache, cached)
+        {
+            let body = source_cache
+                .revalidated(url.as_str(), cached, response.headers())
+                .await;
+            return Ok(AudioBuffer::from_bytes(body));
+        }
+        if !status.is_success() {
+            warn!("unexpected status fetching {}: {}", url, status);
+            return Err(LoaderError::from_status(status.as_u16(), url.as_str()));
+        }
+
+        let too_large = LoaderError::TooLarge {
+            max: self.max_body_size,
+        };
+        if response
+            .content_length()
+            .is_some_and(|len| len > self.max_body_size as u64)
+        {
+            return Err(too_large);
+        }
+
+        let headers = response.headers().clone();
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(LoaderError::from_reqwest)? {
+            if body.len() + chunk.len() > self.max_body_size {
+                return Err(too_large);
+            }
+            body.extend_from_slice(&chunk);
+        }
+
+        let body = Bytes::from(body);
+        if let Some(source_cache) = &self.source_cache {
+            source_cache.put(url.as_str(), &headers, body.clone()).await;
+        }
+        Ok(AudioBuffer::from_bytes(body))
+    }
+}
+
+#[async_trait]
+impl AudioLoader for HttpLoader {
+    fn accepts(&self, key: &str) -> bool {
+        Self::is_remote(key)
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        self.fetch(key).await
+    }
+
+    /// The source's `ETag`, or else its `Last-Modified` and length, from a
+    /// `HEAD` request under the same policy as fetching it.
+    async fn validator(&self, key: &str) -> Result<Option<String>, LoaderError> {
+        if !self.allow_remote {
+            return Err(LoaderError::RemoteDisabled);
+        }
+
+        let url = Url::parse(key).map_err(|_| LoaderError::InvalidUrl(key.to_string()))?;
+        self.policy.check_url(&url)?;
+
+        let response = self
+            .client
+            .head(url.clone())
+            .send()
+            .await
+            .map_err(LoaderError::from_reqwest)?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(LoaderError::from_status(status.as_u16(), url.as_str()));
+        }
+
+        let get = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+        };
+        Ok(match (get(header::ETAG), get(header::LAST_MODIFIED)) {
+            (Some(etag), _) => Some(format!("etag:{}", etag)),
+            (None, Some(last_modified)) => Some(format!(
+                "last-modified:{}:{}",
+                last_modified,
+                get(header::CONTENT_LENGTH).unwrap_or_default()
+            )),
+            (None, None) => None,
+        })
+    }
+}
+
+fn normalize_hosts(hosts: &[String]) -> Vec<String> {
+    hosts.iter().map(|h| h.trim().to_lowercase()).collect()
+}
+
+/// Matches `*` against any run of characters, so `*.example.com` matches
+/// `cdn.example.com` but not `example.com`.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+/// Loopback, link-local, private, shared and other non-public addresses.
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_private_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_private_v4(ip),
+            None => is_private_v6(ip),
+        },
+    }
+}
+
+fn is_private_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || a == 0
+        // 100.64.0.0/10 carrier-grade NAT
+        || (a == 100 && (64..128).contains(&b))
+        // 192.0.0.0/24 IETF protocol assignments
+        || (a == 192 && b == 0 && c == 0)
+        // 198.18.0.0/15 benchmarking
+        || (a == 198 && (b & 0xfe) == 18)
+        // 240.0.0.0/4 reserved
+        || a >= 240
+}
+
+fn is_private_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // fc00::/7 unique local
+        || (first & 0xfe00) == 0xfc00
+        // fe80::/10 link-local
+        || (first & 0xffc0) == 0xfe80
+}
+
+/// The IPv4 address an IPv6 address reaches, for ranges that embed one:
+/// IPv4-mapped `::ffff:a.b.c.d`, IPv4-compatible `::a.b.c.d`, NAT64
+/// `64:ff9b::/96` and 6to4 `2002::/16`.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let last = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match ip.segments() {
+        [0, 0, 0, 0, 0, 0xffff, _, _] => Some(last),
+        // `::` and `::1` are the IPv6 unspecified and loopback addresses
+        [0, 0, 0, 0, 0, 0, _, _] if !ip.is_unspecified() && !ip.is_loopback() => Some(last),
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::memory::MemoryCache;
+    use axum::http::StatusCode;
+    use wiremock::{
+        matchers::{header, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    fn loader(configure: impl FnOnce(&mut LoaderSettings)) -> HttpLoader {
+        let mut config = LoaderSettings::default();
+        configure(&mut config);
+        HttpLoader::new(&config).unwrap()
+    }
+
+    fn local_loader() -> HttpLoader {
+        loader(|c| c.allow_private_addresses = true)
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("example.com", "example.com"));
+        assert!(glob_match("*.example.com", "cdn.example.com"));
+        assert!(glob_match("*.example.com", "a.b.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        assert!(!glob_match("*.example.com", "example.com.evil.net"));
+        assert!(glob_match("cdn*.example.com", "cdn2.example.com"));
+        assert!(glob_match("*", "anything"));
+    }
+
+    #[test]
+    fn test_is_private() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "192.0.0.8",
+            "198.18.0.1",
+            "198.19.255.255",
+            "240.0.0.1",
+            "255.255.255.254",
+            // NAT64, 6to4 and IPv4-compatible forms of private addresses
+            "64:ff9b::10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+            "::127.0.0.1",
+            "::169.254.169.254",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in [
+            "8.8.8.8",
+            "172.32.0.1",
+            "100.128.0.1",
+            "192.0.1.1",
+            "198.20.0.1",
+            "2606:4700::1111",
+            "64:ff9b::8.8.8.8",
+            "2002:808:808::1",
+            "::8.8.8.8",
+        ] {
+            assert!(!is_private(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_host_lists() {
+        let policy = HostPolicy {
+            allowed_hosts: vec!["*.example.com".to_string()],
+            denied_hosts: vec!["private.example.com".to_string()],
+            allow_private_addresses: false,
+        };
+        let check = |url: &str| policy.check_url(&Url::parse(url).unwrap());
+
+        assert!(check("https://cdn.example.com/a.mp3").is_ok());
+        assert!(matches!(
+            check("https://private.example.com/a.mp3"),
+            Err(LoaderError::HostNotAllowed(_))
+        ));
+        assert!(matches!(
+            check("https://evil.net/a.mp3"),
+            Err(LoaderError::HostNotAllowed(_))
+        ));
+        assert!(matches!(
+            check("file:///etc/passwd"),
+            Err(LoaderError::InvalidUrl(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_private_addresses_are_blocked() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 16]))
+            .mount(&server)
+            .await;
+
+        let loader = loader(|_| {});
+        let ip_url = format!("{}/a.mp3", server.uri());
+        assert!(matches!(
+            loader.fetch(&ip_url).await,
+            Err(LoaderError::AddressNotAllowed(_))
+        ));
+
+        // Names are checked after resolution
+        let name_url = ip_url.replace("127.0.0.1", "localhost");
+        assert!(matches!(
+            loader.fetch(&name_url).await,
+            Err(LoaderError::AddressNotAllowed(_))
+        ));
+
+        assert!(local_loader().fetch(&ip_url).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limit() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![0u8; 1024 * 1024 + 1]))
+            .mount(&server)
+            .await;
+
+        let loader = loader(|c| {
+            c.allow_private_addresses = true;
+            c.max_body_size = 1;
+        });
+
+        assert!(matches!(
+            loader.fetch(&format!("{}/a.mp3", server.uri())).await,
+            Err(LoaderError::TooLarge { max: 1048576 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_limited_and_checked() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/loop"))
+            .respond_with(ResponseTemplate::new(302).insert_header("Location", "/loop"))
+            .mount(&server)
+            .await;
+        let evil = format!("{}/a.mp3", server.uri()).replace("127.0.0.1", "localhost");
+        Mock::given(method("GET"))
+            .and(path("/elsewhere"))
+            .respond_with(ResponseTemplate::new(302).insert_header("Location", evil.as_str()))
+            .mount(&server)
+            .await;
+
+        assert!(matches!(
+            local_loader()
+                .fetch(&format!("{}/loop", server.uri()))
+                .await,
+            Err(LoaderError::TooManyRedirects)
+        ));
+
+        let loader = loader(|c| {
+            c.allow_private_addresses = true;
+            c.allowed_hosts = vec!["127.0.0.1".to_string()];
+        });
+        assert!(matches!(
+            loader.fetch(&format!("{}/elsewhere", server.uri())).await,
+            Err(LoaderError::HostNotAllowed(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_upstream_errors() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/missing.mp3"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/broken.mp3"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/private.mp3"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+
+        let local = local_loader();
+        let missing = local
+            .fetch(&format!("{}/missing.mp3", server.uri()))
+            .await
+            .unwrap_err();
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+
+        let broken = local
+            .fetch(&format!("{}/broken.mp3", server.uri()))
+            .await
+            .unwrap_err();
+        assert_eq!(broken.status_code(), StatusCode::BAD_GATEWAY);
+
+        let private = local
+            .fetch(&format!("{}/private.mp3", server.uri()))
+            .await
+            .unwrap_err();
+        assert_eq!(private.status_code(), StatusCode::FORBIDDEN);
+
+        let disabled = loader(|c| c.allow_remote = false)
+            .fetch("https://example.com/a.mp3")
+            .await
+            .unwrap_err();
+        assert_eq!(disabled.status_code(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_validators_come_from_head_requests() {
+        let server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .and(path("/tagged.mp3"))
+            .respond_with(ResponseTemplate::new(200).insert_header("ETag", "\"v1\""))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/dated.mp3"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/plain.mp3"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/missing.mp3"))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(0)
+            .mount(&server)
+            .await;
+
+        let local = local_loader();
+        let url = |name: &str| format!("{}/{}", server.uri(), name);
+        assert_eq!(
+            local
+                .validator(&url("tagged.mp3"))
+                .await
+                .unwrap()
+                .as_deref(),
+            Some("etag:\"v1\"")
+        );
+        let dated = local.validator(&url("dated.mp3")).await.unwrap().unwrap();
+        assert!(dated.starts_with("last-modified:Wed, 21 Oct 2015 07:28:00 GMT"));
+        assert_eq!(local.validator(&url("plain.mp3")).await.unwrap(), None);
+        let missing = local.validator(&url("missing.mp3")).await.unwrap_err();
+        assert_eq!(missing.status_code(), StatusCode::NOT_FOUND);
+
+        // HEAD requests are checked like fetches
+        assert!(matches!(
+            loader(|_| {}).validator(&url("tagged.mp3")).await,
+            Err(LoaderError::AddressNotAllowed(_))
+        ));
+    }
+
+    fn caching_loader() -> HttpLoader {
+        local_loader().with_source_cache(SourceCache::new(
+            Arc::new(MemoryCache::new(1024 * 1024)),
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_fresh_sources_are_reused() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Cache-Control", "max-age=60")
+                    .set_body_bytes(b"source".to_vec()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let loader = caching_loader();
+        let url = format!("{}/a.mp3", server.uri());
+        for _ in 0..3 {
+            assert_eq!(loader.fetch(&url).await.unwrap().as_ref(), b"source");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stale_sources_are_revalidated() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("If-None-Match", "\"v1\""))
+            .respond_with(ResponseTemplate::new(304).insert_header("ETag", "\"v1\""))
+            .expect(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .insert_header("Cache-Control", "no-cache")
+                    .set_body_bytes(b"source".to_vec()),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let loader = caching_loader();
+        let url = format!("{}/a.mp3", server.uri());
+        for _ in 0..3 {
+            assert_eq!(loader.fetch(&url).await.unwrap().as_ref(), b"source");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_uncacheable_sources_are_fetched_again() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v1\"")
+                    .insert_header("Cache-Control", "no-store")
+                    .set_body_bytes(b"source".to_vec()),
+            )
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let loader = caching_loader();
+        let url = format!("{}/a.mp3", server.uri());
+        for _ in 0..2 {
+            assert_eq!(loader.fetch(&url).await.unwrap().as_ref(), b"source");
+        }
+    }
+}
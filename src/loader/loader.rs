@@ -0,0 +1,305 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{async_trait, http::StatusCode};
+use color_eyre::{eyre::eyre, Result};
+use tracing::{debug, info};
+
+use super::{
+    file::FileLoader, gcs::GcsLoader, http::HttpLoader, s3::S3Loader, source_cache::SourceCache,
+    storage::StorageLoader,
+};
+use crate::{
+    blob::AudioBuffer,
+    cache::cache::AudioCache,
+    config::{LoaderKind, LoaderSettings},
+    storage::storage::AudioStorage,
+};
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum LoaderError {
+    #[error("Fetching remote audio is disabled")]
+    RemoteDisabled,
+
+    #[error("Invalid source URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("No loader for source: {0}")]
+    Unsupported(String),
+
+    #[error("Host is not allowed: {0}")]
+    HostNotAllowed(String),
+
+    #[error("Address is not allowed: {0}")]
+    AddressNotAllowed(String),
+
+    #[error("Access to source is forbidden: {0}")]
+    Forbidden(String),
+
+    #[error("Source is larger than {max} bytes")]
+    TooLarge { max: usize },
+
+    #[error("Too many redirects")]
+    TooManyRedirects,
+
+    #[error("Source not found: {0}")]
+    NotFound(String),
+
+    #[error("Timed out fetching source")]
+    Timeout,
+
+    #[error("Failed to fetch source: {0}")]
+    Upstream(String),
+}
+
+impl LoaderError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            LoaderError::InvalidUrl(_) | LoaderError::Unsupported(_) => StatusCode::BAD_REQUEST,
+            LoaderError::RemoteDisabled
+            | LoaderError::HostNotAllowed(_)
+            | LoaderError::AddressNotAllowed(_)
+            | LoaderError::Forbidden(_) => StatusCode::FORBIDDEN,
+            LoaderError::NotFound(_) => StatusCode::NOT_FOUND,
+            LoaderError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            LoaderError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            LoaderError::TooManyRedirects | LoaderError::Upstream(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// Maps an HTTP status returned by an upstream service for `source`.
+    pub fn from_status(status: u16, source: &str) -> Self {
+        match status {
+            404 | 410 => LoaderError::NotFound(source.to_string()),
+            401 | 403 => LoaderError::Forbidden(source.to_string()),
+            _ => LoaderError::Upstream(format!("{} returned {}", source, status)),
+        }
+    }
+}
+
+impl From<LoaderError> for (StatusCode, String) {
+    fn from(err: LoaderError) -> Self {
+        (err.status_code(), err.to_string())
+    }
+}
+
+#[async_trait]
+pub trait AudioLoader: Send + Sync {
+    /// Whether this loader handles `key`, usually decided by its scheme.
+    fn accepts(&self, key: &str) -> bool;
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError>;
+
+    /// Something that changes whenever the source at `key` does, like its
+    /// ETag or modification time, found without loading it. `None` if the
+    /// loader can't tell.
+    async fn validator(&self, _key: &str) -> Result<Option<String>, LoaderError> {
+        Ok(None)
+    }
+}
+
+/// Loaders tried in order. A loader that does not find the source falls
+/// through to the next one accepting the key; any other error is returned.
+#[derive(Clone, Default)]
+pub struct LoaderChain {
+    loaders: Vec<Arc<dyn AudioLoader>>,
+}
+
+impl LoaderChain {
+    pub fn new(loaders: Vec<Arc<dyn AudioLoader>>) -> Self {
+        Self { loaders }
+    }
+
+    /// Builds the loaders listed in `config.chain`, in that order.
+    pub async fn from_config(
+        config: &LoaderSettings,
+        storage: Arc<dyn AudioStorage>,
+        cache: Arc<dyn AudioCache>,
+    ) -> Result<Self> {
+        let mut loaders: Vec<Arc<dyn AudioLoader>> = Vec::new();
+        for kind in &config.chain {
+            info!("using {:?} loader", kind);
+            let loader: Arc<dyn AudioLoader> = match kind {
+                LoaderKind::Http => {
+                    let loader = HttpLoader::new(config)?;
+                    match config.source_cache_ttl {
+                        0 => Arc::new(loader),
+                        ttl => Arc::new(loader.with_source_cache(SourceCache::new(
+                            cache.clone(),
+                            Duration::from_secs(ttl),
+                        ))),
+                    }
+                }
+                LoaderKind::Storage => Arc::new(StorageLoader::new(storage.clone())),
+                LoaderKind::File => {
+                    let settings = config
+                        .file
+                        .as_ref()
+                        .ok_or_else(|| eyre!("the file loader requires `loader.file`"))?;
+                    Arc::new(FileLoader::new(settings, config.max_body_size))
+                }
+                LoaderKind::S3 => {
+                    let settings = config
+                        .s3
+                        .as_ref()
+                        .ok_or_else(|| eyre!("the s3 loader requires `loader.s3`"))?;
+                    Arc::new(S3Loader::new(settings, config.max_body_size))
+                }
+                LoaderKind::Gcs => Arc::new(GcsLoader::new(config.max_body_size).await?),
+            };
+            loaders.push(loader);
+        }
+
+        Ok(Self::new(loaders))
+    }
+}
+
+#[async_trait]
+impl AudioLoader for LoaderChain {
+    fn accepts(&self, key: &str) -> bool {
+        self.loaders.iter().any(|loader| loader.accepts(key))
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        let mut not_found = None;
+        for loader in self.loaders.iter().filter(|loader| loader.accepts(key)) {
+            match loader.load(key).await {
+                Err(e @ LoaderError::NotFound(_)) => {
+                    debug!("{}, trying the next loader", e);
+                    not_found = Some(e);
+                }
+                result => return result,
+            }
+        }
+
+        Err(not_found.unwrap_or_else(|| LoaderError::Unsupported(key.to_string())))
+    }
+
+    async fn validator(&self, key: &str) -> Result<Option<String>, LoaderError> {
+        let mut not_found = None;
+        for loader in self.loaders.iter().filter(|loader| loader.accepts(key)) {
+            match loader.validator(key).await {
+                Err(e @ LoaderError::NotFound(_)) => not_found = Some(e),
+                result => return result,
+            }
+        }
+
+        Err(not_found.unwrap_or_else(|| LoaderError::Unsupported(key.to_string())))
+    }
+}
+
+/// Byte limit for `max_body_size` given in MB.
+pub(crate) fn max_bytes(max_body_size: usize) -> usize {
+    max_body_size.saturating_mul(1024 * 1024)
+}
+
+/// Splits `scheme://bucket/key` into the bucket and key.
+pub(crate) fn split_bucket_url<'a>(
+    scheme: &str,
+    url: &'a str,
+) -> Result<(&'a str, &'a str), LoaderError> {
+    url.strip_prefix(scheme)
+        .and_then(|rest| rest.strip_prefix("://"))
+        .and_then(|rest| rest.split_once('/'))
+        .filter(|(bucket, key)| !bucket.is_empty() && !key.is_empty())
+        .ok_or_else(|| LoaderError::InvalidUrl(url.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct StubLoader {
+        prefix: &'static str,
+        result: Result<&'static [u8], LoaderError>,
+        calls: AtomicUsize,
+    }
+
+    impl StubLoader {
+        fn new(prefix: &'static str, result: Result<&'static [u8], LoaderError>) -> Arc<Self> {
+            Arc::new(Self {
+                prefix,
+                result,
+                calls: AtomicUsize::new(0),
+            })
+        }
+    }
+
+    #[async_trait]
+    impl AudioLoader for StubLoader {
+        fn accepts(&self, key: &str) -> bool {
+            key.starts_with(self.prefix)
+        }
+
+        async fn load(&self, _key: &str) -> Result<AudioBuffer, LoaderError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.result
+                .clone()
+                .map(|bytes| AudioBuffer::from_bytes(bytes.to_vec()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_chain_falls_through_not_found() {
+        let missing = StubLoader::new("", Err(LoaderError::NotFound("a.mp3".to_string())));
+        let other = StubLoader::new("s3://", Ok(b"s3"));
+        let found = StubLoader::new("", Ok(b"found"));
+        let chain = LoaderChain::new(vec![missing.clone(), other.clone(), found.clone()]);
+
+        let blob = chain.load("a.mp3").await.unwrap();
+        assert_eq!(blob.as_ref(), b"found");
+        assert_eq!(missing.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(other.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chain_stops_on_other_errors() {
+        let forbidden = StubLoader::new("", Err(LoaderError::Forbidden("a.mp3".to_string())));
+        let found = StubLoader::new("", Ok(b"found"));
+        let chain = LoaderChain::new(vec![forbidden, found.clone()]);
+
+        let err = chain.load("a.mp3").await.unwrap_err();
+        assert_eq!(err.status_code(), StatusCode::FORBIDDEN);
+        assert_eq!(found.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_chain_errors() {
+        let missing = StubLoader::new("", Err(LoaderError::NotFound("a.mp3".to_string())));
+        let chain = LoaderChain::new(vec![missing]);
+        assert_eq!(
+            chain.load("a.mp3").await.unwrap_err().status_code(),
+            StatusCode::NOT_FOUND
+        );
+
+        let chain = LoaderChain::new(vec![StubLoader::new("s3://", Ok(b"s3"))]);
+        assert!(matches!(
+            chain.load("gs://bucket/a.mp3").await,
+            Err(LoaderError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_from_status() {
+        for (status, expected) in [
+            (404, StatusCode::NOT_FOUND),
+            (403, StatusCode::FORBIDDEN),
+            (500, StatusCode::BAD_GATEWAY),
+        ] {
+            assert_eq!(
+                LoaderError::from_status(status, "a.mp3").status_code(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_split_bucket_url() {
+        assert_eq!(
+            split_bucket_url("s3", "s3://bucket/dir/a.mp3").unwrap(),
+            ("bucket", "dir/a.mp3")
+        );
+        assert!(split_bucket_url("s3", "s3://bucket").is_err());
+        assert!(split_bucket_url("s3", "s3:///a.mp3").is_err());
+        assert!(split_bucket_url("gs", "s3://bucket/a.mp3").is_err());
+    }
+}
@@ -0,0 +1,8 @@
+pub mod file;
+pub mod gcs;
+pub mod http;
+#[allow(clippy::module_inception)]
+pub mod loader;
+pub mod s3;
+pub mod source_cache;
+pub mod storage;
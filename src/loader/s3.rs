@@ -0,0 +1,120 @@
+use aws_sdk_s3::{
+    config::{Credentials, Region},
+    error::SdkError,
+    operation::{get_object::GetObjectError, head_object::HeadObjectError},
+    Client,
+};
+use axum::async_trait;
+use secrecy::ExposeSecret;
+
+use super::loader::{max_bytes, split_bucket_url, AudioLoader, LoaderError};
+use crate::{blob::AudioBuffer, config::S3LoaderSettings};
+
+/// Loads `s3://bucket/key` sources from any bucket the credentials can read.
+#[derive(Clone)]
+pub struct S3Loader {
+    client: Client,
+    max_body_size: usize,
+}
+
+impl S3Loader {
+    pub fn new(config: &S3LoaderSettings, max_body_size: usize) -> Self {
+        let credentials = Credentials::new(
+            config.access_key.expose_secret(),
+            config.secret_key.expose_secret(),
+            None,
+            None,
+            "loader",
+        );
+
+        let s3_config = aws_sdk_s3::Config::builder()
+            .behavior_version_latest()
+            .region(Region::new(config.region.clone()))
+            .endpoint_url(&config.endpoint)
+            .credentials_provider(credentials)
+            .force_path_style(true)
+            .build();
+
+        Self {
+            client: Client::from_conf(s3_config),
+            max_body_size: max_bytes(max_body_size),
+        }
+    }
+}
+
+#[async_trait]
+impl AudioLoader for S3Loader {
+    fn accepts(&self, key: &str) -> bool {
+        key.starts_with("s3://")
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        let (bucket, object) = split_bucket_url("s3", key)?;
+
+        let output = self
+            .client
+            .get_object()
+            .bucket(bucket)
+            .key(object)
+            .send()
+            .await
+            .map_err(|e| sdk_error(&e, key))?;
+
+        if output
+            .content_length()
+            .is_some_and(|len| len > self.max_body_size as i64)
+        {
+            return Err(LoaderError::TooLarge {
+                max: self.max_body_size,
+            });
+        }
+
+        let data = output
+            .body
+            .collect()
+            .await
+            .map_err(|e| LoaderError::Upstream(e.to_string()))?
+            .into_bytes();
+        Ok(AudioBuffer::from_bytes(data))
+    }
+
+    async fn validator(&self, key: &str) -> Result<Option<String>, LoaderError> {
+        let (bucket, object) = split_bucket_url("s3", key)?;
+
+        let output = self
+            .client
+            .head_object()
+            .bucket(bucket)
+            .key(object)
+            .send()
+            .await
+            .map_err(|e| head_error(&e, key))?;
+        Ok(output.e_tag().map(|etag| format!("etag:{}", etag)))
+    }
+}
+
+fn head_error(err: &SdkError<HeadObjectError>, key: &str) -> LoaderError {
+    if let SdkError::ServiceError(service) = err {
+        if service.err().is_not_found() {
+            return LoaderError::NotFound(key.to_string());
+        }
+    }
+
+    match err.raw_response() {
+        Some(response) => LoaderError::from_status(response.status().as_u16(), key),
+        None => LoaderError::Upstream(err.to_string()),
+    }
+}
+
+pub(crate) fn sdk_error(err: &SdkError<GetObjectError>, key: &str) -> LoaderError {
+    if let SdkError::ServiceError(service) = err {
+        if service.err().is_no_such_key() {
+            return LoaderError::NotFound(key.to_string());
+        }
+    }
+
+    match err.raw_response() {
+        Some(response) => LoaderError::from_status(response.status().as_u16(), key),
+        None => LoaderError::Upstream(err.to_string()),
+    }
+}
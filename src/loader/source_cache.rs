@@ -0,0 +1,326 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+use reqwest::header::{self, HeaderMap};
+use tracing::warn;
+
+use crate::{
+    cache::cache::{AudioCache, CacheEntry},
+    cyberpunkpath::hasher::digest_storage_hasher,
+    response::parse_http_date,
+};
+
+const SOURCE_CACHE_KEY_PREFIX: &str = "source_cache:";
+
+/// Upstream headers kept with a cached source.
+const SOURCE_HEADERS: [header::HeaderName; 4] = [
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::EXPIRES,
+];
+
+/// Remote source audio, kept apart from processed results so that changing
+/// only the params doesn't fetch the source again. Entries are served while
+/// upstream `Cache-Control` says they're fresh, and revalidated with a
+/// conditional GET after that.
+#[derive(Clone)]
+pub struct SourceCache {
+    cache: Arc<dyn AudioCache>,
+    ttl: Duration,
+}
+
+/// A cached source and the upstream headers it came with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedSource {
+    entry: CacheEntry,
+}
+
+impl SourceCache {
+    /// `ttl` bounds how long sources are kept at all, fresh or not.
+    pub fn new(cache: Arc<dyn AudioCache>, ttl: Duration) -> Self {
+        Self { cache, ttl }
+    }
+
+    pub async fn get(&self, url: &str) -> Option<CachedSource> {
+        match self.cache.get_entry(&cache_key(url)).await {
+            Ok(entry) => entry.map(|entry| CachedSource { entry }),
+            Err(e) => {
+                warn!("Failed to get cached source [{}]: {}", url, e);
+                None
+            }
+        }
+    }
+
+    /// Caches a fetched source, unless upstream forbids it or it could never
+    /// be served again without a full fetch.
+    pub async fn put(&self, url: &str, headers: &HeaderMap, body: Bytes) {
+        let headers = kept_headers(headers);
+        let directives = CacheControl::from_headers(&headers);
+        let has_validators = headers
+            .iter()
+            .any(|(k, _)| k == header::ETAG.as_str() || k == header::LAST_MODIFIED.as_str());
+        if directives.no_store || directives.private || !(has_validators || directives.max_age > 0)
+        {
+            return;
+        }
+
+        let entry = CacheEntry::new(200, String::new(), headers, body);
+        self.set(url, &entry).await;
+    }
+
+    /// Marks a cached source as fresh again after upstream answered `304 Not
+    /// Modified`, taking any updated headers from the response.
+    pub async fn revalidated(&self, url: &str, cached: CachedSource, headers: &HeaderMap) -> Bytes {
+        let mut updated = kept_headers(headers);
+        for (name, value) in cached.entry.headers {
+            if !updated.iter().any(|(k, _)| *k == name) {
+                updated.push((name, value));
+            }
+        }
+
+        let entry = CacheEntry::new(200, String::new(), updated, cached.entry.body);
+        self.set(url, &entry).await;
+        entry.body
+    }
+
+    async fn set(&self, url: &str, entry: &CacheEntry) {
+        if let Err(e) = self
+            .cache
+            .set_entry(&cache_key(url), entry, Some(self.ttl))
+            .await
+        {
+            warn!("Failed to cache source [{}]: {}", url, e);
+        }
+    }
+}
+
+impl CachedSource {
+    /// Whether it may be used without asking upstream.
+    pub fn is_fresh(&self) -> bool {
+        let directives = CacheControl::from_headers(&self.entry.headers);
+        if directives.no_cache {
+            return false;
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let lifetime = match (directives.max_age, self.header(header::EXPIRES)) {
+            (0, Some(expires)) => parse_http_date(expires)
+                .map_or(0, |at| at.timestamp().max(0) as u64)
+                .saturating_sub(self.entry.created_at),
+            (max_age, _) => max_age,
+        };
+        // A lifetime too long to add up is as good as forever
+        self.entry
+            .created_at
+            .checked_add(lifetime)
+            .is_none_or(|expires| now < expires)
+    }
+
+    /// Headers turning the next fetch into a conditional GET.
+    pub fn validators(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let validators = [
+            (header::ETAG, header::IF_NONE_MATCH),
+            (header::LAST_MODIFIED, header::IF_MODIFIED_SINCE),
+        ];
+        for (name, conditional) in validators {
+            if let Some(value) = self.header(name).and_then(|v| v.parse().ok()) {
+                headers.insert(conditional, value);
+            }
+        }
+        headers
+    }
+
+    pub fn body(&self) -> Bytes {
+        self.entry.body.clone()
+    }
+
+    fn header(&self, name: header::HeaderName) -> Option<&str> {
+        self.entry
+            .headers
+            .iter()
+            .find(|(k, _)| k == name.as_str())
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The upstream `Cache-Control` directives a source cache cares about.
+#[derive(Debug, Default, PartialEq)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    /// Seconds the source is fresh for, preferring `s-maxage`
+    max_age: u64,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &[(String, String)]) -> Self {
+        let mut directives = Self::default();
+        let mut s_maxage = None;
+        let values = headers
+            .iter()
+            .filter(|(k, _)| k == header::CACHE_CONTROL.as_str())
+            .flat_map(|(_, v)| v.split(','));
+
+        for directive in values {
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, Some(value.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = value.and_then(|v| v.parse().ok()).unwrap_or(0),
+                "s-maxage" => s_maxage = value.and_then(|v| v.parse().ok()),
+                _ => {}
+            }
+        }
+
+        if let Some(s_maxage) = s_maxage {
+            directives.max_age = s_maxage;
+        }
+        directives
+    }
+}
+
+fn kept_headers(headers: &HeaderMap) -> Vec<(String, String)> {
+    SOURCE_HEADERS
+        .iter()
+        .flat_map(|name| {
+            headers
+                .get_all(name)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .map(|v| (name.to_string(), v.to_string()))
+        })
+        .collect()
+}
+
+fn cache_key(url: &str) -> String {
+    format!("{}{}", SOURCE_CACHE_KEY_PREFIX, digest_storage_hasher(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cache::memory::MemoryCache, response::http_date};
+    use chrono::Utc;
+
+    fn now_http_date(offset: chrono::Duration) -> String {
+        http_date(Utc::now() + offset)
+    }
+
+    fn cached(headers: &[(&str, &str)], age: u64) -> CachedSource {
+        let mut entry = CacheEntry::new(
+            200,
+            String::new(),
+            headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            Bytes::from_static(b"source"),
+        );
+        entry.created_at -= age;
+        CachedSource { entry }
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let parse = |value: &str| {
+            CacheControl::from_headers(&[("cache-control".to_string(), value.to_string())])
+        };
+
+        assert_eq!(
+            parse("public, max-age=60"),
+            CacheControl {
+                max_age: 60,
+                ..Default::default()
+            }
+        );
+        assert_eq!(parse("max-age=60, s-maxage=\"120\"").max_age, 120);
+        assert!(parse("No-Store").no_store);
+        assert!(parse("private, max-age=60").private);
+        assert!(parse("no-cache").no_cache);
+        assert_eq!(parse("max-age=soon").max_age, 0);
+    }
+
+    #[test]
+    fn test_freshness() {
+        assert!(cached(&[("cache-control", "max-age=60")], 30).is_fresh());
+        assert!(!cached(&[("cache-control", "max-age=60")], 90).is_fresh());
+        assert!(!cached(&[("cache-control", "max-age=60, no-cache")], 0).is_fresh());
+        assert!(!cached(&[("etag", "\"v1\"")], 0).is_fresh());
+
+        let expires = now_http_date(chrono::Duration::seconds(60));
+        assert!(cached(&[("expires", &expires)], 0).is_fresh());
+        let expired = now_http_date(chrono::Duration::seconds(-60));
+        assert!(!cached(&[("expires", &expired)], 0).is_fresh());
+
+        let forever = format!("max-age={}", u64::MAX);
+        assert!(cached(&[("cache-control", &forever)], 0).is_fresh());
+    }
+
+    #[test]
+    fn test_validators() {
+        let source = cached(
+            &[
+                ("etag", "\"v1\""),
+                ("last-modified", "Mon, 01 Jan 2024 00:00:00 GMT"),
+            ],
+            0,
+        );
+        let validators = source.validators();
+        assert_eq!(validators[header::IF_NONE_MATCH], "\"v1\"");
+        assert_eq!(
+            validators[header::IF_MODIFIED_SINCE],
+            "Mon, 01 Jan 2024 00:00:00 GMT"
+        );
+        assert!(cached(&[], 0).validators().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_only_reusable_sources_are_cached() {
+        let cache = SourceCache::new(Arc::new(MemoryCache::new(1024)), Duration::from_secs(60));
+        let headers = |pairs: &[(header::HeaderName, &str)]| {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.clone(), v.parse().unwrap()))
+                .collect::<HeaderMap>()
+        };
+        let body = Bytes::from_static(b"source");
+
+        for (url, upstream, kept) in [
+            ("https://a/etag", headers(&[(header::ETAG, "\"v1\"")]), true),
+            (
+                "https://a/max-age",
+                headers(&[(header::CACHE_CONTROL, "max-age=60")]),
+                true,
+            ),
+            ("https://a/nothing", headers(&[]), false),
+            (
+                "https://a/no-store",
+                headers(&[
+                    (header::ETAG, "\"v1\""),
+                    (header::CACHE_CONTROL, "no-store"),
+                ]),
+                false,
+            ),
+            (
+                "https://a/private",
+                headers(&[(header::CACHE_CONTROL, "private, max-age=60")]),
+                false,
+            ),
+        ] {
+            cache.put(url, &upstream, body.clone()).await;
+            assert_eq!(cache.get(url).await.is_some(), kept, "{}", url);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::{io::ErrorKind, sync::Arc};
+
+use aws_sdk_s3::{error::SdkError, operation::get_object::GetObjectError};
+use axum::async_trait;
+use color_eyre::Report;
+
+use super::{
+    gcs,
+    loader::{AudioLoader, LoaderError},
+    s3,
+};
+use crate::{
+    blob::AudioBuffer, cyberpunkpath::normalize::is_contained, storage::storage::AudioStorage,
+};
+
+/// Loads plain keys (no `scheme://`) from the configured `AudioStorage`.
+/// Keys may not escape the storage root, whatever the backend.
+#[derive(Clone)]
+pub struct StorageLoader {
+    storage: Arc<dyn AudioStorage>,
+}
+
+impl StorageLoader {
+    pub fn new(storage: Arc<dyn AudioStorage>) -> Self {
+        Self { storage }
+    }
+}
+
+#[async_trait]
+impl AudioLoader for StorageLoader {
+    fn accepts(&self, key: &str) -> bool {
+        !key.contains("://")
+    }
+
+    async fn load(&self, key: &str) -> Result<AudioBuffer, LoaderError> {
+        if !is_contained(key) {
+            return Err(LoaderError::Forbidden(key.to_string()));
+        }
+
+        self.storage
+            .get(key)
+            .await
+            .map_err(|e| storage_error(&e, key))
+    }
+}
+
+/// Recovers the not found and forbidden cases from a storage backend error.
+fn storage_error(err: &Report, key: &str) -> LoaderError {
+    if let Some(io) = err.downcast_ref::<std::io::Error>() {
+        return match io.kind() {
+            ErrorKind::NotFound => LoaderError::NotFound(key.to_string()),
+            ErrorKind::PermissionDenied => LoaderError::Forbidden(key.to_string()),
+            _ => LoaderError::Upstream(io.to_string()),
+        };
+    }
+    if let Some(sdk) = err.downcast_ref::<SdkError<GetObjectError>>() {
+        return s3::sdk_error(sdk, key);
+    }
+    if let Some(gcs) = err.downcast_ref::<google_cloud_storage::http::Error>() {
+        return gcs::client_error(gcs, key);
+    }
+    LoaderError::Upstream(err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cyberpunkpath::normalize::SafeCharsType, storage::file::FileStorage};
+    use axum::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_storage_loader() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = FileStorage::new(
+            dir.path().to_path_buf(),
+            String::new(),
+            SafeCharsType::Default,
+        );
+        storage
+            .put("a.mp3", &AudioBuffer::from_bytes(b"audio".to_vec()))
+            .await
+            .unwrap();
+        let loader = StorageLoader::new(Arc::new(storage));
+
+        assert!(loader.accepts("dir/a.mp3"));
+        assert!(!loader.accepts("https://example.com/a.mp3"));
+
+        assert_eq!(loader.load("a.mp3").await.unwrap().as_ref(), b"audio");
+        assert_eq!(
+            loader.load("missing.mp3").await.unwrap_err().status_code(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keys_escaping_storage_are_forbidden() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("storage")).unwrap();
+        std::fs::write(dir.path().join("secret.mp3"), b"secret").unwrap();
+        let loader = StorageLoader::new(Arc::new(FileStorage::new(
+            dir.path().join("storage"),
+            String::new(),
+            SafeCharsType::Noop,
+        )));
+
+        for key in ["../secret.mp3", "a/../../secret.mp3", "/secret.mp3"] {
+            assert!(matches!(
+                loader.load(key).await,
+                Err(LoaderError::Forbidden(_))
+            ));
+        }
+    }
+}
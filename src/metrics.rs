@@ -4,21 +4,35 @@ use axum::{
     response::IntoResponse,
 };
 use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
 use tokio::time::Instant;
 
+static RECORDER_HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the global Prometheus recorder on first use; later calls (e.g.
+/// several apps spawned by the test suite) share the same handle.
 pub fn setup_metrics_recorder() -> PrometheusHandle {
     const EXPONENTIAL_SECONDS: &[f64] = &[
         0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
     ];
 
-    PrometheusBuilder::new()
-        .set_buckets_for_metric(
-            Matcher::Full("http_requests_duration_seconds".to_string()),
-            EXPONENTIAL_SECONDS,
-        )
-        .unwrap()
-        .install_recorder()
-        .unwrap()
+    RECORDER_HANDLE
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(
+                    Matcher::Full("http_requests_duration_seconds".to_string()),
+                    EXPONENTIAL_SECONDS,
+                )
+                .unwrap()
+                .set_buckets_for_metric(
+                    Matcher::Full("processing_queue_wait_seconds".to_string()),
+                    EXPONENTIAL_SECONDS,
+                )
+                .unwrap()
+                .install_recorder()
+                .unwrap()
+        })
+        .clone()
 }
 
 pub async fn track_metrics(req: Request, next: Next) -> impl IntoResponse {
@@ -1,20 +1,32 @@
-use crate::cyberpunkpath::hasher::{suffix_result_storage_hasher, verify_hash};
+use crate::cache::cache::CacheEntry;
 use crate::cyberpunkpath::params::Params;
+use crate::cyberpunkpath::signer::{signing_path, AuthError, EXPIRES_PARAM};
+use crate::response::{http_date, Representation};
 use crate::state::AppStateDyn;
-use axum::http::{header, HeaderValue, Response, StatusCode};
+use crate::stream::tee;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode};
 use axum::{
-    body::{to_bytes, Body},
+    body::Body,
     extract::{Request, State},
     middleware::Next,
     response::IntoResponse,
 };
-use bytes::Bytes;
-use std::time::Duration;
+use chrono::{DateTime, Utc};
+use std::time::{SystemTime, UNIX_EPOCH};
 use tracing::debug;
+use url::form_urlencoded;
 
 const CACHE_KEY_PREFIX: &str = "req_cache:";
-const META_CACHE_KEY_PREFIX: &str = "meta_cache:";
-const CACHE_TTL: Duration = Duration::from_secs(3600); // 1 hour
+
+/// Response headers kept with cached bodies, besides `Content-Type`
+const CACHED_HEADERS: [HeaderName; 6] = [
+    header::ETAG,
+    header::LAST_MODIFIED,
+    header::CACHE_CONTROL,
+    header::ACCEPT_RANGES,
+    header::ACCESS_CONTROL_ALLOW_ORIGIN,
+    header::CONTENT_DISPOSITION,
+];
 
 #[tracing::instrument(skip(state, req, next))]
 pub async fn cache_middleware(
@@ -23,91 +35,24 @@ pub async fn cache_middleware(
     req: Request,
     next: Next,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let params_hash = suffix_result_storage_hasher(&params);
-
-    let path = params.to_string();
-    let cache_key_prefix = if path.starts_with("/meta") {
-        META_CACHE_KEY_PREFIX
-    } else {
-        CACHE_KEY_PREFIX
-    };
+    // Metadata has its own cache, see `MetadataCache`
+    if req.uri().path().starts_with("/meta/") {
+        return Ok(next.run(req).await);
+    }
 
-    let cache_key = format!("{}:{}:{}", cache_key_prefix, req.method(), params_hash);
+    let params_hash = state.result_keyer.key(&params);
+    let cache_key = cache_key(req.method(), &params_hash);
 
     debug!("Cache key: {}", cache_key);
-    let cache_response = state.cache.get(&cache_key).await.map_err(|e| {
+    let cached = state.cache.get_entry(&cache_key).await.map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Failed to get cache: {}", e),
         )
     })?;
-    if let Some(buf) = cache_response {
-        // Return cached response if available
-        let content_type = infer::get(&buf)
-            .map(|mime| mime.to_string())
-            .unwrap_or("audio/mpeg".to_string());
-        let total_size = buf.len();
-
+    if let Some(entry) = cached {
         debug!("Cache hit key={}", cache_key);
-        let headers = req.headers();
-
-        // Handle range request
-        if let Some(range) = headers.get(header::RANGE) {
-            if let Ok(range_str) = range.to_str() {
-                if let Some(range_val) = range_str.strip_prefix("bytes=") {
-                    let (start, end) = parse_range(range_val, total_size);
-                    let length = end - start + 1;
-
-                    let content = Bytes::copy_from_slice(&buf[start..=end]);
-
-                    let res = Response::builder()
-                        .status(StatusCode::PARTIAL_CONTENT)
-                        .header(header::CONTENT_TYPE, content_type)
-                        .header(header::ACCEPT_RANGES, "bytes")
-                        .header(header::CONTENT_LENGTH, length.to_string())
-                        .header(
-                            header::CONTENT_RANGE,
-                            format!("bytes {}-{}/{}", start, end, total_size),
-                        )
-                        .header(header::CACHE_CONTROL, "no-cache")
-                        .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-                        .header(
-                            header::CONTENT_DISPOSITION,
-                            HeaderValue::from_static("inline"),
-                        )
-                        .body(Body::from(content))
-                        .map_err(|e| {
-                            (
-                                StatusCode::INTERNAL_SERVER_ERROR,
-                                format!("Failed to build response: {}", e),
-                            )
-                        })?;
-
-                    return Ok(res);
-                }
-            }
-        }
-
-        // Return full content if no range request
-        let res = Response::builder()
-            .header(header::CONTENT_TYPE, content_type)
-            .header(header::ACCEPT_RANGES, "bytes")
-            .header(header::CONTENT_LENGTH, total_size.to_string())
-            .header(header::CACHE_CONTROL, "no-cache")
-            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
-            .header(
-                header::CONTENT_DISPOSITION,
-                HeaderValue::from_static("inline"),
-            )
-            .body(Body::from(buf))
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to build response: {}", e),
-                )
-            })?;
-
-        return Ok(res);
+        return cached_response(entry, req.headers());
     }
 
     // If not cached, proceed with the request
@@ -116,60 +61,180 @@ pub async fn cache_middleware(
         return Ok(response);
     }
 
-    // Cache the response
-    let (parts, body) = response.into_parts();
-    let bytes = to_bytes(body, usize::MAX).await.map_err(|e| {
+    // Cache the response once it has been fully streamed to the client
+    let (mut parts, body) = response.into_parts();
+
+    // Audio was last modified when it was produced, which is when its entry
+    // is created
+    let created_at = Utc::now();
+    if parts.headers.contains_key(header::ETAG) && parts.headers.contains_key(header::CACHE_CONTROL)
+    {
+        if let Ok(value) = HeaderValue::from_str(&http_date(created_at)) {
+            parts.headers.insert(header::LAST_MODIFIED, value);
+        }
+    }
+
+    let status = parts.status.as_u16();
+    let content_type = parts
+        .headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let headers: Vec<(String, String)> = CACHED_HEADERS
+        .iter()
+        .filter_map(|name| {
+            let value = parts.headers.get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect();
+
+    let length = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok()?.parse().ok());
+
+    let cache = state.cache.clone();
+    let ttl = state.cache_ttl;
+    let body = tee(body.into_data_stream(), move |bytes| {
+        tokio::spawn(async move {
+            let mut entry = CacheEntry::new(status, content_type, headers, bytes);
+            entry.created_at = created_at.timestamp().max(0) as u64;
+            let _ = cache.set_entry(&cache_key, &entry, ttl).await;
+        });
+    })
+    .with_length(length);
+
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
+
+/// Responses are cached by route, e.g. `req_cache:GET:<params hash>`.
+fn cache_key(method: &Method, params_hash: &str) -> String {
+    format!("{}{}:{}", CACHE_KEY_PREFIX, method, params_hash)
+}
+
+/// Serves a cache entry the way the response it came from was served. Audio
+/// carries an ETag, was last modified when the entry was created, and goes
+/// through range and conditional handling again.
+fn cached_response(
+    entry: CacheEntry,
+    request_headers: &HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let header = |name: &HeaderName| {
+        entry
+            .headers
+            .iter()
+            .find(|(k, _)| k.as_str() == name.as_str())
+            .map(|(_, v)| v.as_str())
+    };
+
+    if let (Some(etag), Some(cache_control)) =
+        (header(&header::ETAG), header(&header::CACHE_CONTROL))
+    {
+        let representation = Representation {
+            content_type: entry.content_type.clone(),
+            etag: Some(etag.to_string()),
+            last_modified: DateTime::from_timestamp(entry.created_at as i64, 0),
+            cache_control: HeaderValue::from_str(cache_control).map_err(|e| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!("Invalid cached header: {}", e),
+                )
+            })?,
+        };
+        return representation.full(request_headers, entry.body);
+    }
+
+    let mut builder = Response::builder()
+        .status(entry.status)
+        .header(header::CONTENT_TYPE, &entry.content_type)
+        .header(header::CONTENT_LENGTH, entry.body.len());
+    for (name, value) in &entry.headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(Body::from(entry.body)).map_err(|e| {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to read response body: {}", e),
+            format!("Failed to build response: {}", e),
         )
-    })?;
-
-    let _ = state
-        .cache
-        .set(&cache_key, bytes.as_ref(), Some(CACHE_TTL))
-        .await;
-
-    Ok(Response::from_parts(parts, Body::from(bytes)))
+    })
 }
 
+/// Verifies the URL signature in the first path segment. The signature may
+/// cover either the canonical params (`Params::to_string`) or the path after
+/// the signature as sent, the way thumbor/imagor clients sign URLs. Either
+/// way an `expires` query parameter is covered too.
 pub async fn auth_middleware(
-    State(_): State<AppStateDyn>,
+    State(state): State<AppStateDyn>,
     params: Params,
     req: Request,
     next: Next,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let path = params.to_string();
+) -> Result<impl IntoResponse, AuthError> {
+    let path = req.uri().path().trim_start_matches("/meta");
+    authorize(&state, &params, path, req.uri().query())?;
+
+    Ok(next.run(req).await)
+}
 
-    let hash = req
-        .uri()
-        .path()
-        .trim_start_matches("/meta")
+/// Checks the signature of `/{signature}/{path}?{query}`, a request for
+/// `params`.
+pub fn authorize(
+    state: &AppStateDyn,
+    params: &Params,
+    path: &str,
+    query: Option<&str>,
+) -> Result<(), AuthError> {
+    let (hash, path) = path
         .strip_prefix("/")
-        .and_then(|s| s.split("/").next())
-        .ok_or((StatusCode::BAD_REQUEST, format!("Failed to parse URI hash")))?;
-
-    if hash != "unsafe" {
-        verify_hash(hash.to_owned().into(), path.to_owned().into()).map_err(|e| {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("Failed to verify hash: {}", e),
-            )
-        })?;
+        .and_then(|s| s.split_once("/"))
+        .ok_or(AuthError::Malformed("missing signature".to_string()))?;
+
+    if hash == "unsafe" {
+        if !state.allow_unsafe {
+            return Err(AuthError::UnsafeDisabled);
+        }
+        return Ok(());
     }
 
-    Ok(next.run(req).await)
+    let expires = query
+        .and_then(|q| {
+            form_urlencoded::parse(q.as_bytes())
+                .find(|(k, _)| k == EXPIRES_PARAM)
+                .map(|(_, v)| v.into_owned())
+        })
+        .map(|v| {
+            v.parse::<u64>()
+                .map_err(|_| AuthError::Malformed(format!("invalid {}: {}", EXPIRES_PARAM, v)))
+        })
+        .transpose()?;
+
+    let raw_path = match query {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    state
+        .signer
+        .check(&[&signing_path(params, expires), &raw_path], hash)?;
+
+    if let Some(expires) = expires {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if now > expires {
+            return Err(AuthError::Expired(expires));
+        }
+    }
+
+    Ok(())
 }
 
-fn parse_range(range: &str, total_size: usize) -> (usize, usize) {
-    let mut parts = range.split('-');
-    let start = parts
-        .next()
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(0);
-    let end = parts
-        .next()
-        .and_then(|s| s.parse::<usize>().ok())
-        .unwrap_or(total_size - 1);
-    (start, end.min(total_size - 1))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_key() {
+        assert_eq!(cache_key(&Method::GET, "ab/cd"), "req_cache:GET:ab/cd");
+        assert_eq!(cache_key(&Method::HEAD, "ab/cd"), "req_cache:HEAD:ab/cd");
+    }
 }
@@ -0,0 +1,347 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+use super::policy::PolicyError;
+
+/// Most of the FFmpeg log that makes it into an error.
+const MAX_EXCERPT_LINES: usize = 5;
+const MAX_EXCERPT_LEN: usize = 500;
+
+/// What FFmpeg logs for each kind of failure, checked in this order.
+const INVALID_FILTER: [&str; 9] = [
+    "error initializing filter",
+    "error reinitializing filter",
+    "error parsing filterchain",
+    "error parsing a filter description",
+    "error applying option",
+    "no such filter",
+    "option not found",
+    "unable to parse option value",
+    "undefined constant or missing",
+];
+
+const UNSUPPORTED: [&str; 10] = [
+    "unknown encoder",
+    "unknown decoder",
+    "encoder not found",
+    "unsupported codec",
+    "could not find tag for codec",
+    "not currently supported in container",
+    "is not a suitable output format",
+    "requested output format",
+    "error while opening encoder",
+    "is not supported",
+];
+
+const INVALID_INPUT: [&str; 9] = [
+    "invalid data found when processing input",
+    "error while decoding",
+    "could not find codec parameters",
+    "moov atom not found",
+    "header missing",
+    "failed to read frame size",
+    "invalid frame",
+    "error opening input",
+    "end of file",
+];
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum ProcessingError {
+    #[error("Invalid filter arguments: {0}")]
+    InvalidFilter(String),
+
+    #[error("Input could not be decoded: {0}")]
+    InvalidInput(String),
+
+    #[error("Unsupported codec or format: {0}")]
+    Unsupported(String),
+
+    #[error("Input is {duration}s long, longer than the maximum of {max}s")]
+    InputTooLong { duration: u64, max: u64 },
+
+    #[error("Output is larger than the maximum of {max} bytes")]
+    OutputTooLarge { max: u64 },
+
+    #[error("Processing timed out")]
+    Timeout,
+
+    #[error("Too many requests are waiting to be processed")]
+    Busy { retry_after: u64 },
+
+    #[error("Processing failed: {0}")]
+    Internal(String),
+}
+
+impl ProcessingError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ProcessingError::InvalidFilter(_) | ProcessingError::Unsupported(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ProcessingError::InvalidInput(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ProcessingError::InputTooLong { .. } | ProcessingError::OutputTooLarge { .. } => {
+                StatusCode::PAYLOAD_TOO_LARGE
+            }
+            ProcessingError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ProcessingError::Busy { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ProcessingError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ProcessingError::InvalidFilter(_) => "invalid_filter",
+            ProcessingError::InvalidInput(_) => "invalid_input",
+            ProcessingError::Unsupported(_) => "unsupported_format",
+            ProcessingError::InputTooLong { .. } => "input_too_long",
+            ProcessingError::OutputTooLarge { .. } => "output_too_large",
+            ProcessingError::Timeout => "processing_timeout",
+            ProcessingError::Busy { .. } => "queue_full",
+            ProcessingError::Internal(_) => "processing_failed",
+        }
+    }
+
+    /// Classifies a failed FFmpeg run by what it logged. The log is reduced
+    /// to a short excerpt, since it ends up in the response.
+    pub fn from_stderr(stderr: &[u8]) -> Self {
+        let log = String::from_utf8_lossy(stderr);
+        let excerpt = excerpt(&log);
+        let log = strip_escapes(&log).to_ascii_lowercase();
+        let matches = |patterns: &[&str]| patterns.iter().any(|p| log.contains(p));
+
+        if matches(&INVALID_FILTER) {
+            ProcessingError::InvalidFilter(excerpt)
+        } else if matches(&UNSUPPORTED) {
+            ProcessingError::Unsupported(excerpt)
+        } else if matches(&INVALID_INPUT) {
+            ProcessingError::InvalidInput(excerpt)
+        } else if excerpt.is_empty() {
+            ProcessingError::Internal("FFmpeg exited without an error message".to_string())
+        } else {
+            ProcessingError::Internal(excerpt)
+        }
+    }
+
+    /// Recovers the error carried by a failed output stream.
+    pub fn from_io(err: &std::io::Error) -> Self {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<ProcessingError>())
+            .cloned()
+            .unwrap_or_else(|| ProcessingError::Internal(err.to_string()))
+    }
+}
+
+impl From<PolicyError> for ProcessingError {
+    fn from(err: PolicyError) -> Self {
+        ProcessingError::InvalidFilter(err.to_string())
+    }
+}
+
+impl From<std::io::Error> for ProcessingError {
+    fn from(err: std::io::Error) -> Self {
+        ProcessingError::from_io(&err)
+    }
+}
+
+impl From<ProcessingError> for std::io::Error {
+    fn from(err: ProcessingError) -> Self {
+        std::io::Error::other(err)
+    }
+}
+
+impl IntoResponse for ProcessingError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": self.code(),
+            "message": self.to_string(),
+        });
+
+        let mut response = (self.status_code(), Json(body)).into_response();
+        if let ProcessingError::Busy { retry_after } = self {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+/// The first few lines of an FFmpeg log, without anything about the host:
+/// file paths become `<path>` and memory addresses are dropped.
+fn excerpt(log: &str) -> String {
+    let mut excerpt = log
+        .lines()
+        .map(sanitize_line)
+        .filter(|line| !line.is_empty())
+        .take(MAX_EXCERPT_LINES)
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if excerpt.len() > MAX_EXCERPT_LEN {
+        let mut end = MAX_EXCERPT_LEN;
+        while !excerpt.is_char_boundary(end) {
+            end -= 1;
+        }
+        excerpt.truncate(end);
+        excerpt.push('…');
+    }
+    excerpt
+}
+
+fn sanitize_line(line: &str) -> String {
+    strip_escapes(line)
+        .split_whitespace()
+        .filter_map(|word| {
+            if is_address(word) {
+                return word.ends_with(']').then(|| "]".to_string());
+            }
+            let path = word.trim_start_matches(['\'', '"']);
+            if path.starts_with('/') || path.starts_with("file:") {
+                let end = path.trim_end_matches(['\'', '"', ':', ',', '.']).len();
+                let prefix = &word[..word.len() - path.len()];
+                Some(format!("{}<path>{}", prefix, &path[end..]))
+            } else {
+                Some(word.to_string())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+        .replace(" @ ]", "]")
+}
+
+/// Removes the ANSI escape sequences FFmpeg colors its log with, as in
+/// `\x1b[0;31m`, and any other control characters.
+fn strip_escapes(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' && chars.next_if_eq(&'[').is_some() {
+            // Parameter and intermediate bytes up to the final byte
+            for c in chars.by_ref() {
+                if ('\x40'..='\x7e').contains(&c) {
+                    break;
+                }
+            }
+        } else if !c.is_control() || c.is_whitespace() {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Whether `word` is a pointer FFmpeg logs to tell contexts apart, as in
+/// `[mp3 @ 0x5581c0a0]`.
+fn is_address(word: &str) -> bool {
+    word.strip_prefix("0x")
+        .map(|hex| hex.trim_end_matches(']'))
+        .is_some_and(|hex| !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classifies_stderr() {
+        let cases = [
+            (
+                "[Parsed_atempo_0 @ 0x55d1] Value 100.000000 for parameter 'tempo' out of range [0.5 - 100]\n\
+                 [AVFilterGraph @ 0x55d2] Error initializing filter 'atempo' with args '100'",
+                StatusCode::BAD_REQUEST,
+                "invalid_filter",
+            ),
+            (
+                "pipe:0: Invalid data found when processing input",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_input",
+            ),
+            (
+                "[mov,mp4,m4a,3gp,3g2,mj2 @ 0x6000] moov atom not found\n/tmp/.tmpA1b2/in.m4a: Invalid data found when processing input",
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_input",
+            ),
+            (
+                "[ogg @ 0x7f00] Could not find tag for codec pcm_s16le in stream #0, codec not currently supported in container",
+                StatusCode::BAD_REQUEST,
+                "unsupported_format",
+            ),
+            (
+                "Unknown encoder 'libfdk_aac'",
+                StatusCode::BAD_REQUEST,
+                "unsupported_format",
+            ),
+            (
+                "Killed",
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "processing_failed",
+            ),
+            ("", StatusCode::INTERNAL_SERVER_ERROR, "processing_failed"),
+        ];
+
+        for (stderr, status, code) in cases {
+            let err = ProcessingError::from_stderr(stderr.as_bytes());
+            assert_eq!(err.status_code(), status, "{}", stderr);
+            assert_eq!(err.code(), code, "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn test_excerpt_is_sanitized() {
+        let err = ProcessingError::from_stderr(
+            b"[mov,mp4,m4a,3gp,3g2,mj2 @ 0x55d5c0a0b1c0] moov atom not found\n\
+              /tmp/.tmpX9/in.m4a: \x1b[1;31mInvalid data\x1b[0m found when processing input\n\
+              Error opening input file '/srv/audio/in.m4a'.",
+        );
+
+        assert_eq!(
+            err,
+            ProcessingError::InvalidInput(
+                "[mov,mp4,m4a,3gp,3g2,mj2] moov atom not found\n\
+                 <path>: Invalid data found when processing input\n\
+                 Error opening input file '<path>'."
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_excerpt_is_bounded() {
+        let log = "Error while decoding stream #0:0: Invalid data\n".repeat(20);
+        let ProcessingError::InvalidInput(excerpt) = ProcessingError::from_stderr(log.as_bytes())
+        else {
+            panic!("expected invalid input");
+        };
+        assert_eq!(excerpt.lines().count(), MAX_EXCERPT_LINES);
+
+        let log = "x".repeat(2 * MAX_EXCERPT_LEN);
+        let ProcessingError::Internal(excerpt) = ProcessingError::from_stderr(log.as_bytes())
+        else {
+            panic!("expected internal error");
+        };
+        assert!(excerpt.len() <= MAX_EXCERPT_LEN + '…'.len_utf8());
+    }
+
+    #[test]
+    fn test_busy_response_has_retry_after() {
+        let response = ProcessingError::Busy { retry_after: 5 }.into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "5");
+    }
+
+    #[test]
+    fn test_survives_io_errors() {
+        let err = ProcessingError::InvalidInput("bad".to_string());
+        let io_err: std::io::Error = err.clone().into();
+        assert_eq!(ProcessingError::from_io(&io_err), err);
+
+        let other = std::io::Error::other("pipe closed");
+        assert_eq!(
+            ProcessingError::from(other),
+            ProcessingError::Internal("pipe closed".to_string())
+        );
+    }
+}
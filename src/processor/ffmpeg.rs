@@ -1,37 +1,105 @@
-use std::collections::HashMap;
-use tracing::debug;
-use color_eyre::Result;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use std::{collections::HashMap, io, path::PathBuf, process::Stdio, time::Duration};
 use tempfile::TempDir;
-use tokio::process::Command;
-use tracing::instrument;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader},
+    process::{Child, ChildStdout, Command},
+    sync::watch,
+    task::JoinHandle,
+    time::{timeout_at, Instant},
+};
+use tokio_util::io::ReaderStream;
+use tracing::{debug, field, instrument, warn, Span};
 
 use crate::{
     blob::{AudioBuffer, AudioFormat},
+    config::ProcessorSettings,
     cyberpunkpath::params::Params,
+    processor::{error::ProcessingError, queue::QueuePermit},
+    stream::AudioStream,
 };
 
-#[instrument(skip(input, params, temp_dir))]
+/// Bounds on a single FFmpeg run.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FfmpegLimits {
+    /// Wall-clock time for the whole run, including the probe
+    pub timeout: Option<Duration>,
+    /// Longest input accepted, checked with ffprobe before processing
+    pub max_input_duration: Option<Duration>,
+    /// CPU seconds the process may use
+    pub max_cpu_time: Option<u64>,
+    /// Address space of the process in bytes
+    pub max_address_space: Option<u64>,
+}
+
+impl From<&ProcessorSettings> for FfmpegLimits {
+    fn from(settings: &ProcessorSettings) -> Self {
+        let nonzero = |value: u64| (value > 0).then_some(value);
+        Self {
+            timeout: nonzero(settings.timeout).map(Duration::from_secs),
+            max_input_duration: nonzero(settings.max_input_duration).map(Duration::from_secs),
+            max_cpu_time: nonzero(settings.max_cpu_time),
+            max_address_space: nonzero(settings.max_address_space).map(|mb| mb * 1024 * 1024),
+        }
+    }
+}
+
+/// Runs FFmpeg with the input on stdin and streams the encoded output from
+/// stdout. `permit` is held until the output ends or is dropped; dropping the
+/// stream early kills FFmpeg. A failure is classified from what FFmpeg
+/// logged, whether it happens before or during the output. With `progress`,
+/// the input is probed for its duration, which FFmpeg's progress is reported
+/// against.
+#[instrument(skip(input, params, limits, permit), fields(error = field::Empty))]
 pub async fn process_audio(
     input: &AudioBuffer,
     params: &Params,
-    temp_dir: TempDir,
     additional_tags: &HashMap<String, String>,
-) -> Result<AudioBuffer> {
+    limits: &FfmpegLimits,
+    progress: bool,
+    permit: QueuePermit,
+) -> Result<AudioStream, ProcessingError> {
     let output_format = params.format.unwrap_or(AudioFormat::Mp3);
+    let deadline = limits.timeout.map(|timeout| Instant::now() + timeout);
+    let input = Input::stage(input).await?;
 
-    let input_path = temp_dir
-        .path()
-        .join(format!("in.{}", input.format().extension()));
-    let output_path = temp_dir
-        .path()
-        .join(format!("out.{}", output_format.extension()));
-
-    // Write input file
-    tokio::fs::write(&input_path, input.as_ref()).await?;
+    let mut duration = None;
+    if limits.max_input_duration.is_some() || progress {
+        let probed = with_deadline(deadline, probe_duration(&input, limits)).await;
+        duration = match limits.max_input_duration {
+            Some(_) => probed?,
+            // Without a limit to check, failing to probe only costs progress
+            None => probed.ok().flatten(),
+        };
+    }
+    if let (Some(max), Some(duration)) = (limits.max_input_duration, duration) {
+        if duration > max {
+            return Err(ProcessingError::InputTooLong {
+                duration: duration.as_secs(),
+                max: max.as_secs(),
+            });
+        }
+    }
 
-    // Build FFmpeg command
     let mut cmd = Command::new("ffmpeg");
-    cmd.args(["-i", input_path.to_str().unwrap(), "-y"]);
+    cmd.args(["-hide_banner", "-loglevel", "error"]);
+
+    // Progress is written to stderr along with the log, and told apart by
+    // `read_log`
+    let output_duration = duration
+        .filter(|_| progress)
+        .map(|duration| expected_duration(duration, params))
+        .filter(|duration| !duration.is_zero());
+    let (progress, reporter) = match output_duration {
+        Some(duration) => {
+            cmd.args(["-progress", "pipe:2"]);
+            let (tx, rx) = watch::channel(0.0);
+            (Some(rx), Some((tx, duration)))
+        }
+        None => (None, None),
+    };
+    input.add_to(&mut cmd);
 
     // Add optional metadata
     if let Some(tags) = &params.tags {
@@ -45,22 +113,437 @@ pub async fn process_audio(
         cmd.args(["-metadata", &format!("{}={}", k, v)]);
     }
 
-    // Add encoding parameters and output path
-    cmd.args(params.to_ffmpeg_args())
-        .arg(output_path.to_str().unwrap());
+    // Add encoding parameters; the format can't be guessed from a pipe
+    cmd.args(params.to_ffmpeg_args());
+    if params.format.is_none() {
+        cmd.args(["-f", output_format.muxer()]);
+    }
+    if output_format == AudioFormat::M4a {
+        cmd.args(["-movflags", "frag_keyframe+empty_moov"]);
+    }
+    cmd.arg("pipe:1")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    limit_resources(&mut cmd, limits);
 
     debug!(?cmd, "Executing FFmpeg command");
+    let mut child = cmd.spawn()?;
+    input.feed(&mut child);
+
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let stderr = tokio::spawn(read_log(stderr, reporter));
+
+    let output = FfmpegOutput {
+        stdout: ReaderStream::new(child.stdout.take().expect("stdout is piped")),
+        child,
+        stderr,
+        span: Span::current(),
+        deadline,
+        _permit: permit,
+        _input: input,
+    };
+
+    let mut body = stream::unfold(Some(output), |output| async move {
+        let mut output = output?;
+        match with_deadline(output.deadline, async { Ok(output.stdout.next().await) }).await {
+            Ok(Some(chunk)) => Some((chunk, Some(output))),
+            Ok(None) => output.finish().await.err().map(|e| (Err(e), None)),
+            Err(e) => Some((Err(output.fail(e).into()), None)),
+        }
+    })
+    .boxed();
 
-    // Execute FFmpeg
-    let status = cmd.status().await?;
-    if !status.success() {
-        return Err(color_eyre::eyre::eyre!("FFmpeg failed"));
+    // Wait for the first chunk so that a failure to start is still an error
+    // response rather than an empty body
+    let first = match body.next().await {
+        Some(Ok(chunk)) => chunk,
+        Some(Err(e)) => return Err(ProcessingError::from_io(&e)),
+        None => {
+            return Err(ProcessingError::Internal(
+                "FFmpeg produced no output".to_string(),
+            ))
+        }
+    };
+
+    Ok(AudioStream {
+        format: output_format,
+        body: stream::once(async { Ok(first) }).chain(body).boxed(),
+        progress,
+    })
+}
+
+/// How long the output of `duration` of input plays for, once trimmed.
+fn expected_duration(duration: Duration, params: &Params) -> Duration {
+    // Times too long for a `Duration` are past the end of any input
+    let seconds = |secs: f64| Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX);
+    let start = params
+        .start_time
+        .filter(|secs| *secs > 0.0)
+        .map_or(Duration::ZERO, seconds);
+    let duration = duration.saturating_sub(start);
+    match params.duration.filter(|secs| *secs >= 0.0) {
+        Some(secs) => duration.min(seconds(secs)),
+        None => duration,
     }
+}
+
+/// Reads FFmpeg's log, reporting the share of `duration` it has output if it
+/// was asked to write its progress there too.
+async fn read_log(
+    stderr: impl AsyncRead + Unpin,
+    reporter: Option<(watch::Sender<f64>, Duration)>,
+) -> Vec<u8> {
+    let mut stderr = BufReader::new(stderr);
+    let mut log = Vec::new();
+    let mut line = Vec::new();
+    while stderr.read_until(b'\n', &mut line).await.unwrap_or(0) > 0 {
+        match reporter.as_ref().zip(progress_entry(&line)) {
+            Some(((tx, duration), (key, value))) => {
+                if let Some(done) = progress_done(key, value, *duration) {
+                    tx.send_replace(done);
+                }
+            }
+            None => log.extend_from_slice(&line),
+        }
+        line.clear();
+    }
+    log
+}
+
+/// The share of `duration` output so far, from FFmpeg's progress.
+fn progress_done(key: &str, value: &str, duration: Duration) -> Option<f64> {
+    match key {
+        "out_time_us" => {
+            let micros = value.parse::<u64>().ok()?;
+            Some((Duration::from_micros(micros).as_secs_f64() / duration.as_secs_f64()).min(1.0))
+        }
+        "progress" if value == "end" => Some(1.0),
+        _ => None,
+    }
+}
+
+/// Splits a line FFmpeg's `-progress` writes, like `out_time_us=1500000`.
+fn progress_entry(line: &[u8]) -> Option<(&str, &str)> {
+    let (key, value) = std::str::from_utf8(line).ok()?.trim_end().split_once('=')?;
+    let is_key = !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    is_key.then_some((key, value.trim()))
+}
+
+struct FfmpegOutput {
+    stdout: ReaderStream<ChildStdout>,
+    child: Child,
+    stderr: JoinHandle<Vec<u8>>,
+    span: Span,
+    deadline: Option<Instant>,
+    _permit: QueuePermit,
+    _input: Input,
+}
+
+impl FfmpegOutput {
+    /// Checks how FFmpeg exited once its output has ended.
+    async fn finish(&mut self) -> io::Result<()> {
+        let status = self.child.wait().await?;
+        if status.success() {
+            return Ok(());
+        }
+
+        let stderr = (&mut self.stderr).await.unwrap_or_default();
+        let err = if exceeded_cpu_time(&status) {
+            ProcessingError::Timeout
+        } else {
+            ProcessingError::from_stderr(&stderr)
+        };
+        debug!(parent: &self.span, %status, "FFmpeg exited unsuccessfully");
+        Err(self.fail(err).into())
+    }
+
+    /// Records why the run failed, stopping FFmpeg if it is still going.
+    fn fail(&mut self, err: ProcessingError) -> ProcessingError {
+        if let Err(e) = self.child.start_kill() {
+            debug!("Failed to kill FFmpeg: {}", e);
+        }
+        self.span.record("error", err.code());
+        warn!(parent: &self.span, error = %err, "FFmpeg failed");
+        err
+    }
+}
+
+/// Where FFmpeg and ffprobe read the input from: stdin, or a file for
+/// formats that have to be seeked in.
+struct Input {
+    data: Bytes,
+    path: Option<PathBuf>,
+    _temp_dir: Option<TempDir>,
+}
+
+impl Input {
+    async fn stage(input: &AudioBuffer) -> Result<Self, ProcessingError> {
+        let data = input.clone().into_bytes();
+
+        // MP4 keeps its index at the end of the file
+        if input.format() != AudioFormat::M4a {
+            return Ok(Self {
+                data,
+                path: None,
+                _temp_dir: None,
+            });
+        }
 
-    // Read and return output
-    let processed = tokio::fs::read(&output_path).await?;
-    Ok(AudioBuffer::from_bytes_with_format(
-        processed,
-        output_format,
-    ))
+        let temp_dir = TempDir::new()?;
+        let path = temp_dir
+            .path()
+            .join(format!("in.{}", input.format().extension()));
+        tokio::fs::write(&path, &data).await?;
+        Ok(Self {
+            data,
+            path: Some(path),
+            _temp_dir: Some(temp_dir),
+        })
+    }
+
+    fn add_to(&self, cmd: &mut Command) {
+        match &self.path {
+            Some(path) => cmd.arg("-i").arg(path).stdin(Stdio::null()),
+            None => cmd.args(["-i", "pipe:0"]).stdin(Stdio::piped()),
+        };
+    }
+
+    /// Writes the input to the child's stdin, if it reads from there.
+    fn feed(&self, child: &mut Child) {
+        if let Some(mut stdin) = child.stdin.take() {
+            let data = self.data.clone();
+            tokio::spawn(async move {
+                // FFmpeg stops reading early with `-t`, so a broken pipe is fine
+                if let Err(e) = stdin.write_all(&data).await {
+                    debug!("FFmpeg stopped reading input: {}", e);
+                }
+            });
+        }
+    }
+}
+
+/// How long the input plays for, if ffprobe can tell.
+async fn probe_duration(
+    input: &Input,
+    limits: &FfmpegLimits,
+) -> Result<Option<Duration>, ProcessingError> {
+    let mut cmd = Command::new("ffprobe");
+    cmd.args(["-v", "error"]);
+    input.add_to(&mut cmd);
+    cmd.args([
+        "-show_entries",
+        "format=duration",
+        "-of",
+        "default=noprint_wrappers=1:nokey=1",
+    ])
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+    limit_resources(&mut cmd, limits);
+
+    let mut child = cmd.spawn()?;
+    input.feed(&mut child);
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(ProcessingError::from_stderr(&output.stderr));
+    }
+
+    Ok(parse_duration(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses ffprobe's duration, which is `N/A` when it can't be known.
+fn parse_duration(output: &str) -> Option<Duration> {
+    output
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+async fn with_deadline<T>(
+    deadline: Option<Instant>,
+    future: impl std::future::Future<Output = Result<T, ProcessingError>>,
+) -> Result<T, ProcessingError> {
+    match deadline {
+        Some(deadline) => timeout_at(deadline, future)
+            .await
+            .unwrap_or(Err(ProcessingError::Timeout)),
+        None => future.await,
+    }
+}
+
+/// Caps the CPU time and memory of the child process.
+#[cfg(unix)]
+fn limit_resources(cmd: &mut Command, limits: &FfmpegLimits) {
+    let FfmpegLimits {
+        max_cpu_time,
+        max_address_space,
+        ..
+    } = *limits;
+    if max_cpu_time.is_none() && max_address_space.is_none() {
+        return;
+    }
+
+    let set = |resource, soft: u64, hard: u64| {
+        let limit = libc::rlimit {
+            rlim_cur: soft as libc::rlim_t,
+            rlim_max: hard as libc::rlim_t,
+        };
+        // SAFETY: `limit` outlives the call
+        match unsafe { libc::setrlimit(resource, &limit) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    };
+
+    // SAFETY: setrlimit is async-signal-safe, and nothing is allocated
+    // between fork and exec
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(secs) = max_cpu_time {
+                // SIGXCPU at the soft limit, SIGKILL a second later
+                set(libc::RLIMIT_CPU, secs, secs + 1)?;
+            }
+            if let Some(bytes) = max_address_space {
+                set(libc::RLIMIT_AS, bytes, bytes)?;
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn limit_resources(_cmd: &mut Command, _limits: &FfmpegLimits) {}
+
+#[cfg(unix)]
+fn exceeded_cpu_time(status: &std::process::ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+    matches!(status.signal(), Some(libc::SIGXCPU))
+}
+
+#[cfg(not(unix))]
+fn exceeded_cpu_time(_status: &std::process::ExitStatus) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits_from_settings() {
+        assert_eq!(
+            FfmpegLimits::from(&ProcessorSettings {
+                timeout: 30,
+                max_input_duration: 600,
+                max_cpu_time: 0,
+                max_address_space: 512,
+                ..Default::default()
+            }),
+            FfmpegLimits {
+                timeout: Some(Duration::from_secs(30)),
+                max_input_duration: Some(Duration::from_secs(600)),
+                max_cpu_time: None,
+                max_address_space: Some(512 * 1024 * 1024),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(
+            parse_duration("27.480816\n"),
+            Some(Duration::from_secs_f64(27.480816))
+        );
+        assert_eq!(parse_duration("N/A"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("1e300"), None);
+    }
+
+    #[tokio::test]
+    async fn test_deadline() {
+        let deadline = Some(Instant::now() + Duration::from_millis(10));
+        let slow = async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        };
+        assert_eq!(
+            with_deadline(deadline, slow).await,
+            Err(ProcessingError::Timeout)
+        );
+        assert_eq!(with_deadline(None, async { Ok(1) }).await, Ok(1));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_resource_limits_apply_to_child() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "ulimit -t; ulimit -v"])
+            .stdout(Stdio::piped());
+        limit_resources(
+            &mut cmd,
+            &FfmpegLimits {
+                max_cpu_time: Some(7),
+                max_address_space: Some(256 * 1024 * 1024),
+                ..Default::default()
+            },
+        );
+
+        let output = cmd.output().await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "7\n262144\n");
+    }
+
+    #[test]
+    fn test_expected_duration() {
+        let duration = Duration::from_secs(60);
+        let params = |query: &str| format!("song.mp3?{}", query).parse::<Params>().unwrap();
+
+        assert_eq!(expected_duration(duration, &params("")), duration);
+        assert_eq!(
+            expected_duration(duration, &params("start_time=50&duration=30")),
+            Duration::from_secs(10)
+        );
+        assert_eq!(
+            expected_duration(duration, &params("duration=2.5")),
+            Duration::from_millis(2500)
+        );
+        assert!(expected_duration(duration, &params("start_time=90")).is_zero());
+
+        // Too long for a `Duration`, but still valid params
+        assert!(expected_duration(duration, &params("start_time=1e300")).is_zero());
+        assert_eq!(
+            expected_duration(duration, &params("duration=1e300")),
+            duration
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_log() {
+        let stderr: &[u8] = b"frame=0\n\
+            out_time_us=N/A\n\
+            [mp3 @ 0x5581c0a0] Header missing\n\
+            out_time_us=1500000\n\
+            speed=   1x\n\
+            progress=continue\n";
+
+        // Without progress, all of it is log
+        assert_eq!(read_log(stderr, None).await, stderr);
+
+        let (tx, rx) = watch::channel(0.0);
+        let log = read_log(stderr, Some((tx, Duration::from_secs(6)))).await;
+        assert_eq!(log, b"[mp3 @ 0x5581c0a0] Header missing\n");
+        assert_eq!(*rx.borrow(), 0.25);
+
+        let (tx, rx) = watch::channel(0.0);
+        read_log(
+            &b"out_time_us=9000000\n"[..],
+            Some((tx, Duration::from_secs(6))),
+        )
+        .await;
+        assert_eq!(*rx.borrow(), 1.0);
+    }
 }
@@ -0,0 +1,274 @@
+//! A parser for the FFmpeg filtergraph syntax accepted by `-filter:a`, e.g.
+//! `[0:a]aecho=0.8:0.9:1000:0.3,afade=t=in:d=2[out]; [out]areverse`.
+//!
+//! Only the structure is parsed: filter arguments are kept verbatim so they can
+//! be handed back to FFmpeg unchanged.
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Filter {
+    pub name: String,
+    pub id: Option<String>,
+    pub args: Option<String>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum FiltergraphError {
+    #[error("expected a filter name at position {0}")]
+    MissingName(usize),
+
+    #[error("unterminated link label starting at position {0}")]
+    UnterminatedLabel(usize),
+
+    #[error("unterminated quote starting at position {0}")]
+    UnterminatedQuote(usize),
+
+    #[error("unexpected character '{1}' at position {0}")]
+    UnexpectedChar(usize, char),
+}
+
+/// Parses a filtergraph into its filters, in the order they appear.
+pub fn parse(graph: &str) -> Result<Vec<Filter>, FiltergraphError> {
+    let mut parser = Parser {
+        chars: graph.char_indices().collect(),
+        pos: 0,
+    };
+
+    let mut filters = Vec::new();
+    parser.skip_whitespace();
+    if parser.peek().is_none() {
+        return Ok(filters);
+    }
+
+    loop {
+        filters.push(parser.filter()?);
+        parser.skip_whitespace();
+        match parser.next() {
+            None => return Ok(filters),
+            Some((_, ',' | ';')) => continue,
+            Some((pos, c)) => return Err(FiltergraphError::UnexpectedChar(pos, c)),
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<(usize, char)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).map(|(_, c)| *c)
+    }
+
+    fn next(&mut self) -> Option<(usize, char)> {
+        let next = self.chars.get(self.pos).copied();
+        self.pos += 1;
+        next
+    }
+
+    fn offset(&self) -> usize {
+        self.chars
+            .get(self.pos)
+            .map(|(i, _)| *i)
+            .unwrap_or_else(|| self.chars.last().map_or(0, |(i, c)| i + c.len_utf8()))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn filter(&mut self) -> Result<Filter, FiltergraphError> {
+        let inputs = self.labels()?;
+        self.skip_whitespace();
+
+        let name = self.identifier();
+        if name.is_empty() {
+            return Err(FiltergraphError::MissingName(self.offset()));
+        }
+
+        let id = if self.peek() == Some('@') {
+            self.pos += 1;
+            Some(self.identifier())
+        } else {
+            None
+        };
+
+        let args = if self.peek() == Some('=') {
+            self.pos += 1;
+            Some(self.args()?)
+        } else {
+            None
+        };
+
+        self.skip_whitespace();
+        let outputs = self.labels()?;
+
+        Ok(Filter {
+            name,
+            id,
+            args,
+            inputs,
+            outputs,
+        })
+    }
+
+    fn labels(&mut self) -> Result<Vec<String>, FiltergraphError> {
+        let mut labels = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('[') {
+                return Ok(labels);
+            }
+
+            let start = self.offset();
+            self.pos += 1;
+            let mut label = String::new();
+            loop {
+                match self.next() {
+                    Some((_, ']')) => break,
+                    Some((_, c)) => label.push(c),
+                    None => return Err(FiltergraphError::UnterminatedLabel(start)),
+                }
+            }
+            labels.push(label);
+        }
+    }
+
+    fn identifier(&mut self) -> String {
+        let mut ident = String::new();
+        while let Some(c) = self.peek() {
+            if !(c.is_ascii_alphanumeric() || c == '_') {
+                break;
+            }
+            ident.push(c);
+            self.pos += 1;
+        }
+        ident
+    }
+
+    /// Reads filter arguments up to the next unquoted, unescaped `,`, `;`,
+    /// `[` or `]`, keeping quotes and escapes as written.
+    fn args(&mut self) -> Result<String, FiltergraphError> {
+        let mut args = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                ',' | ';' | '[' | ']' => break,
+                '\\' => {
+                    args.push(c);
+                    self.pos += 1;
+                    if let Some((_, escaped)) = self.next() {
+                        args.push(escaped);
+                    }
+                }
+                '\'' => {
+                    let start = self.offset();
+                    args.push(c);
+                    self.pos += 1;
+                    loop {
+                        match self.next() {
+                            Some((_, '\'')) => break,
+                            Some((_, quoted)) => args.push(quoted),
+                            None => return Err(FiltergraphError::UnterminatedQuote(start)),
+                        }
+                    }
+                    args.push('\'');
+                }
+                _ => {
+                    args.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        Ok(args.trim_end().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(graph: &str) -> Vec<String> {
+        parse(graph).unwrap().into_iter().map(|f| f.name).collect()
+    }
+
+    #[test]
+    fn test_parse_single_filter() {
+        let filters = parse("aecho=0.8:0.9:1000:0.3").unwrap();
+
+        assert_eq!(
+            filters,
+            vec![Filter {
+                name: "aecho".to_string(),
+                args: Some("0.8:0.9:1000:0.3".to_string()),
+                ..Default::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_chains_and_labels() {
+        let filters =
+            parse("[0:a]asplit [a][b]; [a]aecho@e1=0.8:0.9:1000:0.3 [c]; [b][c] amix").unwrap();
+
+        assert_eq!(filters.len(), 3);
+        assert_eq!(filters[0].inputs, vec!["0:a"]);
+        assert_eq!(filters[0].outputs, vec!["a", "b"]);
+        assert_eq!(filters[1].id.as_deref(), Some("e1"));
+        assert_eq!(filters[2].inputs, vec!["b", "c"]);
+        assert_eq!(filters[2].name, "amix");
+    }
+
+    #[test]
+    fn test_quoted_and_escaped_separators_stay_in_args() {
+        assert_eq!(
+            names("volume='0.5,amovie=x',areverse"),
+            vec!["volume", "areverse"]
+        );
+        assert_eq!(
+            names(r"volume=0.5\,amovie,areverse"),
+            vec!["volume", "areverse"]
+        );
+        assert_eq!(
+            parse("volume='0.5,amovie=x'").unwrap()[0].args.as_deref(),
+            Some("'0.5,amovie=x'")
+        );
+    }
+
+    #[test]
+    fn test_separators_outside_quotes_split_filters() {
+        assert_eq!(
+            names("volume=0.5,amovie=/etc/passwd"),
+            vec!["volume", "amovie"]
+        );
+        assert_eq!(
+            names("volume=0.5;amovie=/etc/passwd"),
+            vec!["volume", "amovie"]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            parse("volume=1,,areverse"),
+            Err(FiltergraphError::MissingName(9))
+        );
+        assert_eq!(
+            parse("[in volume"),
+            Err(FiltergraphError::UnterminatedLabel(0))
+        );
+        assert_eq!(
+            parse("volume='1"),
+            Err(FiltergraphError::UnterminatedQuote(7))
+        );
+        assert_eq!(
+            parse("volume=1]"),
+            Err(FiltergraphError::UnexpectedChar(8, ']'))
+        );
+        assert!(parse("").unwrap().is_empty());
+    }
+}
@@ -1,2 +1,8 @@
+pub mod error;
 pub mod ffmpeg;
+pub mod filtergraph;
+pub mod native;
+pub mod policy;
+#[allow(clippy::module_inception)]
 pub mod processor;
+pub mod queue;
@@ -0,0 +1,153 @@
+use std::{io::Cursor, time::Duration};
+
+use bytes::Bytes;
+use symphonia::core::{
+    audio::SampleBuffer,
+    codecs::{DecoderOptions, CODEC_TYPE_NULL},
+    errors::Error,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    dsp::Pcm,
+    native::{check_cancelled, NativeError},
+};
+use crate::blob::AudioFormat;
+
+/// Audio that has been opened but not decoded yet.
+pub struct Source {
+    reader: Box<dyn FormatReader>,
+    track_id: u32,
+}
+
+impl Source {
+    pub fn open(data: Bytes, format: AudioFormat) -> Result<Self, NativeError> {
+        let stream = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+        let mut hint = Hint::new();
+        if format != AudioFormat::Unknown {
+            hint.with_extension(format.extension());
+        }
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+
+        let probed = symphonia::default::get_probe()
+            .format(&hint, stream, &options, &MetadataOptions::default())
+            .map_err(unsupported)?;
+        let track_id = probed
+            .format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or_else(|| NativeError::Unsupported("no audio track".to_string()))?
+            .id;
+
+        Ok(Self {
+            reader: probed.format,
+            track_id,
+        })
+    }
+
+    /// How long the audio plays for, if the container says.
+    pub fn duration(&self) -> Option<Duration> {
+        let params = &self.track()?.codec_params;
+        let frames = params.n_frames?;
+        let rate = params.sample_rate?;
+        Some(Duration::from_secs_f64(frames as f64 / rate as f64))
+    }
+
+    /// Decodes the audio, giving up once it plays for longer than `limit` or
+    /// `cancel` is cancelled.
+    pub fn decode(
+        mut self,
+        limit: Option<Duration>,
+        cancel: &CancellationToken,
+    ) -> Result<Pcm, NativeError> {
+        let params = self
+            .track()
+            .expect("the track was found when opening")
+            .codec_params
+            .clone();
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&params, &DecoderOptions::default())
+            .map_err(unsupported)?;
+
+        let mut pcm = Pcm {
+            sample_rate: params.sample_rate.unwrap_or_default(),
+            channels: vec![Vec::new(); params.channels.map_or(0, |c| c.count())],
+        };
+
+        loop {
+            check_cancelled(cancel)?;
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(unsupported(e)),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            let decoded = match decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                // Like FFmpeg, skip over packets that are corrupt
+                Err(Error::DecodeError(_)) => continue,
+                Err(e) => return Err(unsupported(e)),
+            };
+            if decoded.frames() == 0 {
+                continue;
+            }
+
+            let spec = *decoded.spec();
+            pcm.sample_rate = spec.rate;
+            pcm.channels.resize(spec.channels.count(), Vec::new());
+
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            buffer.copy_planar_ref(decoded);
+            let frames = buffer.len() / spec.channels.count();
+            for (channel, samples) in pcm
+                .channels
+                .iter_mut()
+                .zip(buffer.samples().chunks_exact(frames))
+            {
+                channel.extend_from_slice(samples);
+            }
+
+            if let Some(limit) = limit.filter(|limit| pcm.duration() > *limit) {
+                return Err(NativeError::Unsupported(format!(
+                    "longer than {}s",
+                    limit.as_secs()
+                )));
+            }
+        }
+
+        if pcm.channels.is_empty() || pcm.sample_rate == 0 {
+            return Err(NativeError::Unsupported("no decodable audio".to_string()));
+        }
+        Ok(pcm)
+    }
+
+    fn track(&self) -> Option<&symphonia::core::formats::Track> {
+        self.reader
+            .tracks()
+            .iter()
+            .find(|track| track.id == self.track_id)
+    }
+}
+
+/// Decodes all of `data`.
+pub fn decode(data: Bytes, format: AudioFormat) -> Result<Pcm, NativeError> {
+    Source::open(data, format)?.decode(None, &CancellationToken::new())
+}
+
+/// Anything symphonia can't read is left to FFmpeg, which may do better and
+/// explains failures in more detail.
+fn unsupported(err: Error) -> NativeError {
+    NativeError::Unsupported(format!("symphonia: {}", err))
+}
@@ -0,0 +1,297 @@
+use std::{iter, time::Duration};
+
+use rubato::{
+    Resampler, SincFixedIn, SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+
+use tokio_util::sync::CancellationToken;
+
+use super::native::{check_cancelled, NativeError};
+
+/// Frames handed to the resampler at a time.
+const RESAMPLE_CHUNK: usize = 1024;
+
+/// Decoded audio, with one buffer of samples per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pcm {
+    pub sample_rate: u32,
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl Pcm {
+    pub fn frames(&self) -> usize {
+        self.channels.first().map_or(0, Vec::len)
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.frames() as f64 / self.sample_rate.max(1) as f64)
+    }
+
+    /// Frames in `secs`, at the millisecond precision FFmpeg is given.
+    fn frames_in(&self, secs: f64) -> usize {
+        let secs = (secs * 1000.0).round() / 1000.0;
+        (secs.max(0.0) * self.sample_rate as f64).round() as usize
+    }
+
+    /// Keeps `duration` seconds from `start`, like FFmpeg's `-ss` and `-t`.
+    pub fn trim(&mut self, start: Option<f64>, duration: Option<f64>) {
+        let frames = self.frames();
+        let start = start.map_or(0, |start| self.frames_in(start)).min(frames);
+        let end = duration.map_or(frames, |duration| {
+            (start + self.frames_in(duration)).min(frames)
+        });
+        for channel in &mut self.channels {
+            channel.truncate(end);
+            channel.drain(..start);
+        }
+    }
+
+    pub fn gain(&mut self, gain: f32) {
+        for sample in self.channels.iter_mut().flatten() {
+            *sample *= gain;
+        }
+    }
+
+    /// Fades in linearly over the first `secs` seconds.
+    pub fn fade_in(&mut self, secs: f64) {
+        let length = self.frames_in(secs).min(self.frames());
+        for channel in &mut self.channels {
+            for (i, sample) in channel[..length].iter_mut().enumerate() {
+                *sample *= i as f32 / length as f32;
+            }
+        }
+    }
+
+    /// Fades out linearly over the first `secs` seconds and silences the
+    /// rest, which is what FFmpeg's `afade=t=out` does without a start time.
+    pub fn fade_out(&mut self, secs: f64) {
+        let length = self.frames_in(secs).min(self.frames());
+        for channel in &mut self.channels {
+            for (i, sample) in channel.iter_mut().enumerate() {
+                *sample *= length.saturating_sub(i) as f32 / length.max(1) as f32;
+            }
+        }
+    }
+
+    pub fn reverse(&mut self) {
+        for channel in &mut self.channels {
+            channel.reverse();
+        }
+    }
+
+    /// Downmixes to mono by averaging, or upmixes mono by duplicating it.
+    pub fn mix_to(&mut self, channels: usize) -> Result<(), NativeError> {
+        let current = self.channels.len();
+        match (current, channels) {
+            (from, to) if from == to => {}
+            (_, 1) => {
+                let mut mono = vec![0.0; self.frames()];
+                for channel in &self.channels {
+                    for (mixed, sample) in mono.iter_mut().zip(channel) {
+                        *mixed += sample / current as f32;
+                    }
+                }
+                self.channels = vec![mono];
+            }
+            (1, to) => self.channels = vec![self.channels[0].clone(); to],
+            (from, to) => {
+                return Err(NativeError::Unsupported(format!(
+                    "mixing {} channels to {}",
+                    from, to
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts to `sample_rate` with a windowed sinc resampler, a chunk at a
+    /// time until `cancel` is cancelled.
+    pub fn resample(
+        &mut self,
+        sample_rate: u32,
+        cancel: &CancellationToken,
+    ) -> Result<(), NativeError> {
+        if sample_rate == self.sample_rate || self.frames() == 0 {
+            self.sample_rate = sample_rate;
+            return Ok(());
+        }
+
+        let ratio = sample_rate as f64 / self.sample_rate as f64;
+        let parameters = SincInterpolationParameters {
+            sinc_len: 256,
+            f_cutoff: 0.95,
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Linear,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let mut resampler =
+            SincFixedIn::<f32>::new(ratio, 1.0, parameters, RESAMPLE_CHUNK, self.channels.len())
+                .map_err(NativeError::failed)?;
+
+        // Output frame `k` is taken at input frame `(k + 1) / ratio - 1`
+        // rather than `k / ratio`, so the input is padded with silence and
+        // any early outputs are dropped to line the two up
+        let skip = ratio.ceil() as usize - 1;
+        let pad = ((skip + 1) as f64 / ratio - 1.0).round() as usize;
+        let input: Vec<Vec<f32>> = self
+            .channels
+            .iter()
+            .map(|channel| {
+                iter::repeat_n(0.0, pad)
+                    .chain(channel.iter().copied())
+                    .collect()
+            })
+            .collect();
+        let frames = input[0].len();
+
+        let expected = (self.frames() as f64 * ratio).round() as usize;
+        let mut output = vec![Vec::with_capacity(expected + skip); self.channels.len()];
+
+        let mut position = 0;
+        while position + RESAMPLE_CHUNK <= frames {
+            check_cancelled(cancel)?;
+            let chunk: Vec<_> = input
+                .iter()
+                .map(|channel| &channel[position..position + RESAMPLE_CHUNK])
+                .collect();
+            append(
+                &mut output,
+                resampler
+                    .process(&chunk, None)
+                    .map_err(NativeError::failed)?,
+            );
+            position += RESAMPLE_CHUNK;
+        }
+        let rest: Vec<_> = input.iter().map(|channel| &channel[position..]).collect();
+        append(
+            &mut output,
+            resampler
+                .process_partial(Some(&rest), None)
+                .map_err(NativeError::failed)?,
+        );
+        while output[0].len() < expected + skip {
+            append(
+                &mut output,
+                resampler
+                    .process_partial::<&[f32]>(None, None)
+                    .map_err(NativeError::failed)?,
+            );
+        }
+
+        for channel in &mut output {
+            channel.drain(..skip);
+            channel.truncate(expected);
+        }
+        self.channels = output;
+        self.sample_rate = sample_rate;
+        Ok(())
+    }
+}
+
+fn append(output: &mut [Vec<f32>], chunk: Vec<Vec<f32>>) {
+    for (output, chunk) in output.iter_mut().zip(chunk) {
+        output.extend(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm(channels: Vec<Vec<f32>>) -> Pcm {
+        Pcm {
+            sample_rate: 10,
+            channels,
+        }
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut audio = pcm(vec![(0..30).map(|i| i as f32).collect()]);
+        audio.trim(Some(1.0), Some(0.5));
+        assert_eq!(audio.channels[0], vec![10.0, 11.0, 12.0, 13.0, 14.0]);
+
+        audio.trim(Some(0.3), None);
+        assert_eq!(audio.channels[0], vec![13.0, 14.0]);
+
+        audio.trim(Some(60.0), Some(1.0));
+        assert_eq!(audio.frames(), 0);
+    }
+
+    #[test]
+    fn test_fades() {
+        let mut audio = pcm(vec![vec![1.0; 10], vec![2.0; 10]]);
+        audio.fade_in(0.4);
+        assert_eq!(
+            audio.channels[0],
+            vec![0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]
+        );
+        assert_eq!(audio.channels[1][1], 0.5);
+
+        let mut audio = pcm(vec![vec![1.0; 6]]);
+        audio.fade_out(0.4);
+        assert_eq!(audio.channels[0], vec![1.0, 0.75, 0.5, 0.25, 0.0, 0.0]);
+
+        // Fades longer than the audio cover all of it
+        let mut audio = pcm(vec![vec![1.0; 2]]);
+        audio.fade_in(10.0);
+        assert_eq!(audio.channels[0], vec![0.0, 0.5]);
+    }
+
+    #[test]
+    fn test_gain_and_reverse() {
+        let mut audio = pcm(vec![vec![0.1, 0.2, 0.3]]);
+        audio.gain(2.0);
+        audio.reverse();
+        assert_eq!(audio.channels[0], vec![0.6, 0.4, 0.2]);
+    }
+
+    #[test]
+    fn test_mix() {
+        let mut audio = pcm(vec![vec![1.0, 0.0], vec![0.0, 0.5]]);
+        audio.mix_to(1).unwrap();
+        assert_eq!(audio.channels, vec![vec![0.5, 0.25]]);
+
+        audio.mix_to(2).unwrap();
+        assert_eq!(audio.channels, vec![vec![0.5, 0.25], vec![0.5, 0.25]]);
+
+        let mut surround = pcm(vec![vec![0.0]; 6]);
+        assert!(matches!(
+            surround.mix_to(2),
+            Err(NativeError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_resample() {
+        let sine = |rate: u32, frames: usize| -> Vec<f32> {
+            (0..frames)
+                .map(|i| (2.0 * std::f32::consts::PI * 440.0 * i as f32 / rate as f32).sin())
+                .collect()
+        };
+        let mut audio = Pcm {
+            sample_rate: 44_100,
+            channels: vec![sine(44_100, 44_100); 2],
+        };
+
+        audio.resample(22_050, &CancellationToken::new()).unwrap();
+        assert_eq!(audio.sample_rate, 22_050);
+        assert_eq!(audio.frames(), 22_050);
+
+        // Away from the edges the tone is unchanged
+        let assert_tone = |audio: &Pcm, edge: usize| {
+            let expected = sine(audio.sample_rate, audio.frames());
+            let frames = audio.frames() - 2 * edge;
+            for (i, (sample, expected)) in audio.channels[0].iter().zip(expected).enumerate() {
+                if (edge..edge + frames).contains(&i) {
+                    assert!((sample - expected).abs() < 0.01, "frame {}", i);
+                }
+            }
+        };
+        assert_tone(&audio, 1000);
+
+        audio.resample(44_100, &CancellationToken::new()).unwrap();
+        assert_eq!(audio.frames(), 44_100);
+        assert_tone(&audio, 2000);
+    }
+}
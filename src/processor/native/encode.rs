@@ -0,0 +1,128 @@
+use std::io::Cursor;
+
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use super::{dsp::Pcm, native::NativeError};
+
+/// RIFF INFO chunks FFmpeg writes tags to, by tag name. Other tags are
+/// dropped, as FFmpeg does.
+const RIFF_INFO: [(&str, &[u8; 4]); 12] = [
+    ("artist", b"IART"),
+    ("comment", b"ICMT"),
+    ("copyright", b"ICOP"),
+    ("date", b"ICRD"),
+    ("genre", b"IGNR"),
+    ("language", b"ILNG"),
+    ("title", b"INAM"),
+    ("album", b"IPRD"),
+    ("track", b"IPRT"),
+    ("encoder", b"ISFT"),
+    ("timecode", b"ISMP"),
+    ("encoded_by", b"ITCH"),
+];
+
+/// 16-bit PCM, which is what FFmpeg writes WAV as by default.
+pub fn wav(pcm: &Pcm, tags: &[(String, String)]) -> Result<Vec<u8>, NativeError> {
+    let spec = WavSpec {
+        channels: pcm.channels.len() as u16,
+        sample_rate: pcm.sample_rate,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = WavWriter::new(&mut cursor, spec).map_err(NativeError::failed)?;
+    for frame in 0..pcm.frames() {
+        for channel in &pcm.channels {
+            writer
+                .write_sample(quantize(channel[frame], 16) as i16)
+                .map_err(NativeError::failed)?;
+        }
+    }
+    writer.finalize().map_err(NativeError::failed)?;
+
+    let mut data = cursor.into_inner();
+    append_info(&mut data, tags);
+    Ok(data)
+}
+
+fn quantize(sample: f32, bits: u32) -> i32 {
+    let max = ((1i64 << (bits - 1)) - 1) as f32;
+    (sample * (max + 1.0)).round().clamp(-(max + 1.0), max) as i32
+}
+
+/// Appends a `LIST` chunk of INFO tags, and updates the RIFF size to match.
+fn append_info(data: &mut Vec<u8>, tags: &[(String, String)]) {
+    let mut info = b"INFO".to_vec();
+    for (key, value) in tags {
+        let Some((_, id)) = RIFF_INFO
+            .iter()
+            .find(|(name, _)| key.eq_ignore_ascii_case(name))
+        else {
+            continue;
+        };
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        info.extend(*id);
+        info.extend((value.len() as u32).to_le_bytes());
+        if value.len() % 2 == 1 {
+            value.push(0);
+        }
+        info.extend(value);
+    }
+    if info.len() == 4 {
+        return;
+    }
+
+    data.extend(b"LIST");
+    data.extend((info.len() as u32).to_le_bytes());
+    data.extend(info);
+    let riff_size = (data.len() - 8) as u32;
+    data[4..8].copy_from_slice(&riff_size.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pcm() -> Pcm {
+        Pcm {
+            sample_rate: 8000,
+            channels: vec![vec![0.0, 0.5, -1.0, 1.0], vec![0.25; 4]],
+        }
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(quantize(0.5, 16), 16_384);
+        assert_eq!(quantize(1.0, 16), 32_767);
+        assert_eq!(quantize(-1.5, 16), -32_768);
+        assert_eq!(quantize(-1.0, 24), -8_388_608);
+    }
+
+    #[test]
+    fn test_wav() {
+        let tags = [
+            ("title".to_string(), "Song".to_string()),
+            ("mood".to_string(), "dropped".to_string()),
+        ];
+        let data = wav(&pcm(), &tags).unwrap();
+
+        let mut reader = hound::WavReader::new(Cursor::new(&data)).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, 8000);
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(
+            samples,
+            vec![0, 8192, 16_384, 8192, -32_768, 8192, 32_767, 8192]
+        );
+
+        assert_eq!(
+            u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize,
+            data.len() - 8
+        );
+        let info = &data[data.len() - 26..];
+        assert_eq!(&info[..12], b"LIST\x12\x00\x00\x00INFO");
+        assert_eq!(&info[12..], b"INAM\x05\x00\x00\x00Song\x00\x00");
+    }
+}
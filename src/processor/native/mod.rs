@@ -0,0 +1,5 @@
+pub mod decode;
+pub mod dsp;
+pub mod encode;
+#[allow(clippy::module_inception)]
+pub mod native;
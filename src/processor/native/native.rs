@@ -0,0 +1,468 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    time::Duration,
+};
+
+use axum::async_trait;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, instrument};
+
+use super::{decode::Source, encode};
+use crate::{
+    blob::{AudioBuffer, AudioFormat},
+    cyberpunkpath::{effect::Effect, params::Params},
+    processor::{
+        error::ProcessingError,
+        ffmpeg::FfmpegLimits,
+        processor::AudioProcessor,
+        queue::{Priority, ProcessingQueue},
+    },
+    stream::AudioStream,
+};
+
+/// Longest audio handled in-process. All of it is held in memory, so longer
+/// audio is left to FFmpeg, which streams it.
+const MAX_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Sources that can be decoded. Unknown ones are probed, as the sniffed
+/// format misses e.g. MP3s that start with ID3 tags.
+const INPUT_FORMATS: [AudioFormat; 6] = [
+    AudioFormat::Mp3,
+    AudioFormat::Wav,
+    AudioFormat::Flac,
+    AudioFormat::Ogg,
+    AudioFormat::M4a,
+    AudioFormat::Unknown,
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum NativeError {
+    /// The request needs FFmpeg
+    #[error("not supported natively: {0}")]
+    Unsupported(String),
+    #[error(transparent)]
+    Failed(#[from] ProcessingError),
+    /// The output is no longer wanted
+    #[error("cancelled")]
+    Cancelled,
+}
+
+impl NativeError {
+    pub fn failed(err: impl Display) -> Self {
+        Self::Failed(ProcessingError::Internal(err.to_string()))
+    }
+}
+
+impl From<NativeError> for ProcessingError {
+    fn from(err: NativeError) -> Self {
+        match err {
+            NativeError::Unsupported(reason) => ProcessingError::Unsupported(reason),
+            NativeError::Failed(err) => err,
+            // Runs are only cancelled once they have timed out, or no one
+            // is waiting for them
+            NativeError::Cancelled => ProcessingError::Timeout,
+        }
+    }
+}
+
+/// Lets blocking work stop between packets and chunks once its output is no
+/// longer wanted.
+pub fn check_cancelled(cancel: &CancellationToken) -> Result<(), NativeError> {
+    match cancel.is_cancelled() {
+        true => Err(NativeError::Cancelled),
+        false => Ok(()),
+    }
+}
+
+/// Processes audio in-process with symphonia, for the formats and operations
+/// it covers: trimming, gain, fades, reversing, resampling and mixing to mono
+/// or stereo, encoded as WAV. Other formats, FLAC included, are left to
+/// FFmpeg's encoders.
+#[derive(Debug)]
+pub struct NativeProcessor {
+    queue: ProcessingQueue,
+    tags: HashMap<String, String>,
+    limits: FfmpegLimits,
+}
+
+#[async_trait]
+impl AudioProcessor for NativeProcessor {
+    async fn process_stream(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, ProcessingError> {
+        Ok(self.run(blob, params, priority).await?)
+    }
+}
+
+impl NativeProcessor {
+    /// Runs share `queue` with FFmpeg. Of `limits`, only the timeout and
+    /// the maximum input duration apply.
+    pub fn new(
+        queue: ProcessingQueue,
+        tags: HashMap<String, String>,
+        limits: FfmpegLimits,
+    ) -> Self {
+        Self {
+            queue,
+            tags,
+            limits,
+        }
+    }
+
+    /// Whether `params` can be applied to audio in `input` without FFmpeg.
+    /// Decoding can still turn out to be unsupported.
+    pub fn supports(params: &Params, input: AudioFormat) -> bool {
+        INPUT_FORMATS.contains(&input) && Plan::new(params, &HashMap::new()).is_ok()
+    }
+
+    #[instrument(skip(self, blob, params))]
+    pub async fn run(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, NativeError> {
+        if !INPUT_FORMATS.contains(&blob.format()) {
+            return Err(NativeError::Unsupported(format!(
+                "decoding {}",
+                blob.format().extension()
+            )));
+        }
+        let plan = Plan::new(params, &self.tags)?;
+
+        let permit = self.queue.acquire(priority).await?;
+        info!(params = ?params, "Processing natively");
+
+        let (data, input_format) = (blob.clone().into_bytes(), blob.format());
+        let max_input_duration = self.limits.max_input_duration;
+        // The permit goes with the work, which stops soon after this run
+        // times out or is dropped
+        let cancel = CancellationToken::new();
+        let _cancel_on_drop = cancel.clone().drop_guard();
+        let work = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            plan.apply(data, input_format, max_input_duration, &cancel)
+        });
+        let output = match self.limits.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, work)
+                .await
+                .map_err(|_| ProcessingError::Timeout)?,
+            None => work.await,
+        }
+        .map_err(NativeError::failed)??;
+
+        Ok(AudioStream {
+            format: AudioFormat::Wav,
+            body: stream::once(async { Ok(Bytes::from(output)) }).boxed(),
+            progress: None,
+        })
+    }
+}
+
+/// The params of a request, as steps that can run natively.
+struct Plan {
+    steps: Vec<Step>,
+    start_time: Option<f64>,
+    duration: Option<f64>,
+    channels: Option<usize>,
+    sample_rate: Option<u32>,
+    tags: Vec<(String, String)>,
+}
+
+enum Step {
+    Reverse,
+    Gain(f32),
+    FadeIn(f64),
+    FadeOut(f64),
+}
+
+impl Plan {
+    fn new(params: &Params, tags: &HashMap<String, String>) -> Result<Self, NativeError> {
+        let unsupported = |what: &str| Err(NativeError::Unsupported(what.to_string()));
+
+        match params.format {
+            Some(AudioFormat::Wav) => {}
+            // There is no native FLAC encoder, so FLAC always goes to FFmpeg
+            Some(AudioFormat::Flac) => return unsupported("FLAC output, which FFmpeg encodes"),
+            _ => return unsupported("output format"),
+        }
+        if params.codec.is_some()
+            || params.bit_rate.is_some()
+            || params.bit_depth.is_some()
+            || params.quality.is_some()
+            || params.compression_level.is_some()
+        {
+            return unsupported("encoder options");
+        }
+        if params.custom_filters.is_some() || params.custom_options.is_some() {
+            return unsupported("custom filters and options");
+        }
+        let channels = match params.channels {
+            None => None,
+            Some(channels @ (1 | 2)) => Some(channels as usize),
+            Some(_) => return unsupported("channel count"),
+        };
+
+        let steps = params
+            .effects()
+            .into_iter()
+            .map(|effect| match effect {
+                Effect::Reverse => Ok(Step::Reverse),
+                // FFmpeg is given the volume to two decimal places
+                Effect::Volume(volume) => Ok(Step::Gain(((volume * 100.0).round() / 100.0) as f32)),
+                Effect::FadeIn(secs) => Ok(Step::FadeIn(secs)),
+                Effect::FadeOut(secs) => Ok(Step::FadeOut(secs)),
+                other => Err(NativeError::Unsupported(other.name().to_string())),
+            })
+            .collect::<Result<_, _>>()?;
+
+        // Tags from the request, overridden by the configured ones, in a
+        // stable order
+        let tags: BTreeMap<_, _> = params
+            .tags
+            .iter()
+            .flatten()
+            .chain(tags)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+
+        Ok(Self {
+            steps,
+            start_time: params.start_time,
+            duration: params.duration,
+            channels,
+            sample_rate: params.sample_rate.map(|rate| rate as u32),
+            tags: tags.into_iter().collect(),
+        })
+    }
+
+    /// Effects are applied before trimming, as FFmpeg applies filters before
+    /// its output `-ss` and `-t`.
+    fn apply(
+        self,
+        data: Bytes,
+        input_format: AudioFormat,
+        max_input_duration: Option<Duration>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<u8>, NativeError> {
+        let source = Source::open(data, input_format)?;
+        let check_duration = |duration: Duration| match max_input_duration {
+            Some(max) if duration > max => Err(ProcessingError::InputTooLong {
+                duration: duration.as_secs(),
+                max: max.as_secs(),
+            }),
+            _ => Ok(()),
+        };
+        if let Some(duration) = source.duration() {
+            check_duration(duration)?;
+        }
+
+        let mut pcm = source.decode(Some(MAX_DURATION), cancel)?;
+        check_duration(pcm.duration())?;
+
+        for step in &self.steps {
+            check_cancelled(cancel)?;
+            match *step {
+                Step::Reverse => pcm.reverse(),
+                Step::Gain(gain) => pcm.gain(gain),
+                Step::FadeIn(secs) => pcm.fade_in(secs),
+                Step::FadeOut(secs) => pcm.fade_out(secs),
+            }
+        }
+        pcm.trim(self.start_time, self.duration);
+        if let Some(channels) = self.channels {
+            pcm.mix_to(channels)?;
+        }
+        if let Some(sample_rate) = self.sample_rate {
+            pcm.resample(sample_rate, cancel)?;
+        }
+
+        check_cancelled(cancel)?;
+        encode::wav(&pcm, &self.tags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn params(query: &str) -> Params {
+        format!("song.mp3?{}", query).parse().unwrap()
+    }
+
+    fn wav(rate: u32, channels: u16, samples: &[i16]) -> AudioBuffer {
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut cursor = Cursor::new(Vec::new());
+        let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        AudioBuffer::from_bytes_with_format(cursor.into_inner(), AudioFormat::Wav)
+    }
+
+    fn processor(limits: FfmpegLimits) -> NativeProcessor {
+        let tags = HashMap::from([("encoded_by".to_string(), "cyberpunk".to_string())]);
+        let queue = ProcessingQueue::new(1, &Default::default());
+        NativeProcessor::new(queue, tags, limits)
+    }
+
+    #[test]
+    fn test_supports() {
+        let supported = [
+            "format=wav",
+            "format=wav&start_time=1&duration=2&sample_rate=22050&channels=1",
+            "format=wav&reverse=true&volume=0.5&fade_in=1&fade_out=2",
+            "format=wav&chain=fade_in:1|reverse&tag_title=Song",
+        ];
+        for query in supported {
+            assert!(
+                NativeProcessor::supports(&params(query), AudioFormat::Mp3),
+                "{}",
+                query
+            );
+        }
+
+        let unsupported = [
+            "",
+            "format=mp3",
+            "format=flac",
+            "format=wav&codec=pcm_s24le",
+            "format=wav&bit_depth=24",
+            "format=wav&echo=0.8:0.9:1000:0.3",
+            "format=wav&chain=reverse|speed:2",
+            "format=wav&filter_0=aecho",
+        ];
+        for query in unsupported {
+            assert!(
+                !NativeProcessor::supports(&params(query), AudioFormat::Mp3),
+                "{}",
+                query
+            );
+        }
+        assert!(!NativeProcessor::supports(
+            &params("format=wav"),
+            AudioFormat::Opus
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let samples: Vec<i16> = (0..8000).flat_map(|i| [i, -i]).collect();
+        let input = wav(8000, 2, &samples);
+        let params = params("format=wav&reverse=true&start_time=0.5&channels=1&tag_title=Song");
+
+        let output = processor(FfmpegLimits::default())
+            .process(&input, &params, Priority::High)
+            .await
+            .unwrap();
+        assert_eq!(output.format(), AudioFormat::Wav);
+
+        let data = output.into_bytes();
+        let mut reader = hound::WavReader::new(Cursor::new(&data)).unwrap();
+        assert_eq!(reader.spec().channels, 1);
+        assert_eq!(reader.spec().sample_rate, 8000);
+        let output: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        // The channels cancel out
+        assert_eq!(output, vec![0; 4000]);
+        assert!(data.windows(4).any(|w| w == b"INAM"));
+        assert!(data.windows(4).any(|w| w == b"ITCH"));
+    }
+
+    #[tokio::test]
+    async fn test_flac_output_is_left_to_ffmpeg() {
+        let input = wav(8000, 1, &[0; 800]);
+        let err = processor(FfmpegLimits::default())
+            .run(&input, &params("format=flac&reverse=true"), Priority::High)
+            .await
+            .err()
+            .unwrap();
+
+        // Which the processor takes as its cue to run FFmpeg instead
+        match err {
+            NativeError::Unsupported(reason) => assert!(reason.contains("FLAC"), "{}", reason),
+            other => panic!("expected unsupported, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_run_limits() {
+        let input = wav(8000, 1, &[0; 16_000]);
+        let limits = FfmpegLimits {
+            max_input_duration: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+
+        let err = processor(limits)
+            .run(&input, &params("format=wav"), Priority::High)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err,
+            NativeError::Failed(ProcessingError::InputTooLong {
+                duration: 2,
+                max: 1
+            })
+        ));
+
+        let err = processor(FfmpegLimits::default())
+            .run(&input, &params("format=wav&speed=2"), Priority::High)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, NativeError::Unsupported(_)));
+
+        let corrupt = AudioBuffer::from_bytes_with_format(&b"not audio"[..], AudioFormat::Mp3);
+        let err = processor(FfmpegLimits::default())
+            .run(&corrupt, &params("format=wav"), Priority::High)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, NativeError::Unsupported(_)));
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_runs_release_their_slot() {
+        let long = wav(8000, 1, &vec![0; 8000 * 600]);
+        let limits = FfmpegLimits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let processor = processor(limits);
+
+        let err = processor
+            .run(
+                &long,
+                &params("format=wav&sample_rate=44100"),
+                Priority::High,
+            )
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(err, NativeError::Failed(ProcessingError::Timeout)));
+
+        // The queue has one slot, which the cancelled work gives back
+        let short = wav(8000, 1, &[0; 800]);
+        let output = tokio::time::timeout(
+            Duration::from_secs(2),
+            processor.run(&short, &params("format=wav"), Priority::High),
+        )
+        .await
+        .expect("the slot wasn't released");
+        assert!(output.is_ok());
+    }
+}
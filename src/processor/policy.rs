@@ -0,0 +1,619 @@
+use std::collections::{HashMap, HashSet};
+
+use axum::http::StatusCode;
+
+use crate::{
+    config::ProcessorSettings,
+    cyberpunkpath::params::Params,
+    processor::filtergraph::{self, FiltergraphError},
+};
+
+/// FFmpeg options whose value is a filtergraph.
+const FILTER_OPTIONS: &[&str] = &["-af", "-filter", "-filter:a", "-filter_complex", "-lavfi"];
+
+/// Filters that read or write files, talk to the network or load plugins.
+/// These are rejected even if they are added to `allowed_filters`.
+const FORBIDDEN_FILTERS: &[&str] = &[
+    "abuffer",
+    "abuffersink",
+    "aevalsrc",
+    "afirsrc",
+    "ametadata",
+    "amovie",
+    "anoisesrc",
+    "anullsink",
+    "anullsrc",
+    "arnndn",
+    "asendcmd",
+    "asr",
+    "azmq",
+    "buffer",
+    "buffersink",
+    "firequalizer",
+    "flite",
+    "ladspa",
+    "lv2",
+    "metadata",
+    "movie",
+    "nullsink",
+    "sendcmd",
+    "sine",
+    "sofalizer",
+    "zmq",
+];
+
+/// Filter options that name a file to read or write. Filters taking a file
+/// positionally, like `firequalizer`'s `dumpfile`, are forbidden outright.
+const FORBIDDEN_FILTER_OPTIONS: &[&str] = &["dumpfile", "file", "filename"];
+
+/// Audio filters allowed in `filter_*` values when `allowed_filters` is empty.
+/// Filters that can make output endless, like `aloop` and `apad`, are left
+/// out.
+pub const DEFAULT_ALLOWED_FILTERS: &[&str] = &[
+    "acompressor",
+    "acontrast",
+    "acopy",
+    "acrossfade",
+    "acrusher",
+    "adeclick",
+    "adeclip",
+    "adelay",
+    "aecho",
+    "aemphasis",
+    "aexciter",
+    "afade",
+    "afftdn",
+    "aformat",
+    "agate",
+    "alimiter",
+    "allpass",
+    "amerge",
+    "amix",
+    "anlmdn",
+    "anull",
+    "aphaser",
+    "apulsator",
+    "aresample",
+    "areverse",
+    "asetrate",
+    "asoftclip",
+    "asplit",
+    "asubboost",
+    "atempo",
+    "atrim",
+    "bandpass",
+    "bandreject",
+    "bass",
+    "biquad",
+    "channelmap",
+    "chorus",
+    "compand",
+    "crystalizer",
+    "dcshift",
+    "deesser",
+    "dynaudnorm",
+    "earwax",
+    "equalizer",
+    "extrastereo",
+    "flanger",
+    "haas",
+    "highpass",
+    "highshelf",
+    "loudnorm",
+    "lowpass",
+    "lowshelf",
+    "pan",
+    "silenceremove",
+    "speechnorm",
+    "stereotools",
+    "stereowiden",
+    "superequalizer",
+    "treble",
+    "tremolo",
+    "vibrato",
+    "volume",
+];
+
+/// Encoder and muxer flags allowed in `option_*` values when `allowed_options`
+/// is empty, with the number of values each one takes.
+pub const DEFAULT_ALLOWED_OPTIONS: &[(&str, usize)] = &[
+    ("-application", 1),
+    ("-compression_level", 1),
+    ("-cutoff", 1),
+    ("-dn", 0),
+    ("-frame_duration", 1),
+    ("-id3v2_version", 1),
+    ("-joint_stereo", 1),
+    ("-map_chapters", 1),
+    ("-map_metadata", 1),
+    ("-movflags", 1),
+    ("-packet_loss", 1),
+    ("-reservoir", 1),
+    ("-sample_fmt", 1),
+    ("-sn", 0),
+    ("-vbr", 1),
+    ("-vn", 0),
+    ("-write_xing", 1),
+];
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PolicyError {
+    #[error("Filter is disabled: {0}")]
+    DisabledFilter(String),
+
+    #[error("Too many filter operations: {count} (max {max})")]
+    TooManyOps { count: usize, max: usize },
+
+    #[error("Invalid filtergraph `{graph}`: {source}")]
+    InvalidFiltergraph {
+        graph: String,
+        #[source]
+        source: FiltergraphError,
+    },
+
+    #[error("Invalid arguments for {param}: {filter}")]
+    InvalidEffectArgs { param: String, filter: String },
+
+    #[error("Filter is not allowed: {0}")]
+    FilterNotAllowed(String),
+
+    #[error("Filter reads or writes outside the pipeline and is never allowed: {0}")]
+    ForbiddenFilter(String),
+
+    #[error("Filter option reads or writes files and is never allowed: {filter} {option}")]
+    ForbiddenFilterOption { filter: String, option: String },
+
+    #[error("Option is not allowed: {0}")]
+    OptionNotAllowed(String),
+
+    #[error("Invalid value for option {option}: {value}")]
+    InvalidOptionValue { option: String, value: String },
+}
+
+impl PolicyError {
+    pub fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+impl From<PolicyError> for (StatusCode, String) {
+    fn from(e: PolicyError) -> Self {
+        (e.status_code(), e.to_string())
+    }
+}
+
+/// Enforces the processor's filter and option policy on the filter graph and
+/// FFmpeg arguments a set of params would produce.
+#[derive(Debug, Clone)]
+pub struct FilterPolicy {
+    disabled_filters: HashSet<String>,
+    max_filter_ops: usize,
+    allowed_filters: HashSet<String>,
+    allowed_options: HashMap<String, usize>,
+}
+
+impl Default for FilterPolicy {
+    fn default() -> Self {
+        Self::new(&ProcessorSettings::default())
+    }
+}
+
+impl FilterPolicy {
+    pub fn new(config: &ProcessorSettings) -> Self {
+        let allowed_filters = if config.allowed_filters.is_empty() {
+            DEFAULT_ALLOWED_FILTERS
+                .iter()
+                .map(|f| f.to_string())
+                .collect()
+        } else {
+            normalize_names(&config.allowed_filters)
+        };
+
+        let allowed_options = if config.allowed_options.is_empty() {
+            DEFAULT_ALLOWED_OPTIONS
+                .iter()
+                .map(|(o, arity)| (o.to_string(), *arity))
+                .collect()
+        } else {
+            config
+                .allowed_options
+                .iter()
+                .map(|o| o.trim().to_string())
+                .filter(|o| !o.is_empty())
+                .map(|o| {
+                    let arity = DEFAULT_ALLOWED_OPTIONS
+                        .iter()
+                        .find(|(known, _)| *known == o)
+                        .map_or(1, |(_, arity)| *arity);
+                    (o, arity)
+                })
+                .collect()
+        };
+
+        Self {
+            disabled_filters: normalize_names(&config.disabled_filters),
+            max_filter_ops: config.max_filter_ops,
+            allowed_filters,
+            allowed_options,
+        }
+    }
+
+    /// Checks the built-in effects as well as the raw `custom_filters` and
+    /// `custom_options`.
+    pub fn check(&self, params: &Params) -> Result<(), PolicyError> {
+        let mut count = 0;
+
+        for op in params.collect_filter_ops() {
+            self.check_name(op.param)?;
+
+            // Effect arguments are user supplied, so make sure they don't
+            // break out into a second filter.
+            let filters = parse(&op.filter)?;
+            if filters.len() != 1 || filters[0].name != op.name() {
+                return Err(PolicyError::InvalidEffectArgs {
+                    param: op.param.to_string(),
+                    filter: op.filter,
+                });
+            }
+            self.check_filter(&filters[0])?;
+            count += 1;
+        }
+
+        for graph in params.custom_filters.iter().flatten() {
+            for filter in parse(graph)? {
+                self.check_filter(&filter)?;
+                count += 1;
+            }
+        }
+
+        count += self.check_options(params.custom_options.as_deref().unwrap_or_default())?;
+
+        if self.max_filter_ops > 0 && count > self.max_filter_ops {
+            return Err(PolicyError::TooManyOps {
+                count,
+                max: self.max_filter_ops,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns the number of filters found in filtergraph options.
+    fn check_options(&self, options: &[String]) -> Result<usize, PolicyError> {
+        let mut count = 0;
+        let mut options = options.iter();
+
+        while let Some(option) = options.next() {
+            let arity = *self
+                .allowed_options
+                .get(option.as_str())
+                .ok_or_else(|| PolicyError::OptionNotAllowed(option.clone()))?;
+
+            for _ in 0..arity {
+                let value = options
+                    .next()
+                    .ok_or_else(|| PolicyError::InvalidOptionValue {
+                        option: option.clone(),
+                        value: String::new(),
+                    })?;
+
+                if FILTER_OPTIONS.contains(&option.as_str()) {
+                    for filter in parse(value)? {
+                        self.check_filter(&filter)?;
+                        count += 1;
+                    }
+                } else if !is_safe_option_value(value) {
+                    return Err(PolicyError::InvalidOptionValue {
+                        option: option.clone(),
+                        value: value.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn check_filter(&self, filter: &filtergraph::Filter) -> Result<(), PolicyError> {
+        let name = &filter.name;
+        let lower = name.to_lowercase();
+        if FORBIDDEN_FILTERS.contains(&lower.as_str()) {
+            return Err(PolicyError::ForbiddenFilter(name.to_string()));
+        }
+        if !self.allowed_filters.contains(&lower) {
+            return Err(PolicyError::FilterNotAllowed(name.to_string()));
+        }
+
+        // Named options are `key=value` pairs separated by `:`
+        let options = filter.args.as_deref().unwrap_or_default().split(':');
+        for option in options.filter_map(|arg| arg.split_once('=').map(|(key, _)| key)) {
+            if FORBIDDEN_FILTER_OPTIONS.contains(&option.trim().to_lowercase().as_str()) {
+                return Err(PolicyError::ForbiddenFilterOption {
+                    filter: name.to_string(),
+                    option: option.trim().to_string(),
+                });
+            }
+        }
+
+        self.check_name(name)
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), PolicyError> {
+        if self.disabled_filters.contains(&name.to_lowercase()) {
+            return Err(PolicyError::DisabledFilter(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+fn parse(graph: &str) -> Result<Vec<filtergraph::Filter>, PolicyError> {
+    filtergraph::parse(graph).map_err(|source| PolicyError::InvalidFiltergraph {
+        graph: graph.to_string(),
+        source,
+    })
+}
+
+fn normalize_names(names: &[String]) -> HashSet<String> {
+    names
+        .iter()
+        .map(|f| f.trim().to_lowercase())
+        .filter(|f| !f.is_empty())
+        .collect()
+}
+
+/// Option values may be numbers, identifiers or flag lists like
+/// `+faststart`, but never paths, URLs or protocol specifiers.
+fn is_safe_option_value(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cyberpunkpath::effect::Effect;
+
+    fn policy(disabled: &[&str], max_filter_ops: usize) -> FilterPolicy {
+        FilterPolicy::new(&ProcessorSettings {
+            disabled_filters: disabled.iter().map(|s| s.to_string()).collect(),
+            max_filter_ops,
+            ..Default::default()
+        })
+    }
+
+    fn with_filters(filters: &[&str]) -> Params {
+        Params {
+            key: "test.mp3".to_string(),
+            custom_filters: Some(filters.iter().map(|s| s.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    fn with_options(options: &[&str]) -> Params {
+        Params {
+            key: "test.mp3".to_string(),
+            custom_options: Some(options.iter().map(|s| s.to_string()).collect()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_allows_safe_params_by_default() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            reverse: Some(true),
+            echo: Some("0.8:0.9:1000:0.3".to_string()),
+            custom_filters: Some(vec!["vibrato=f=5".to_string()]),
+            custom_options: Some(vec!["-map_metadata".to_string(), "-1".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(FilterPolicy::default().check(&params), Ok(()));
+    }
+
+    #[test]
+    fn test_disabled_builtin_effect() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            echo: Some("0.8:0.9:1000:0.3".to_string()),
+            ..Default::default()
+        };
+
+        // Both the query parameter and the FFmpeg filter name can be disabled
+        assert_eq!(
+            policy(&["echo"], 0).check(&params),
+            Err(PolicyError::DisabledFilter("echo".to_string()))
+        );
+        assert_eq!(
+            policy(&["AECHO"], 0).check(&params),
+            Err(PolicyError::DisabledFilter("aecho".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_chain_steps_are_checked() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            reverse: Some(true),
+            chain: Some(vec![
+                Effect::Echo("0.8:0.9:1000:0.3".to_string()),
+                Effect::Reverse,
+            ]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy(&["echo"], 0).check(&params),
+            Err(PolicyError::DisabledFilter("echo".to_string()))
+        );
+        assert_eq!(
+            policy(&[], 2).check(&params),
+            Err(PolicyError::TooManyOps { count: 3, max: 2 })
+        );
+    }
+
+    #[test]
+    fn test_builtin_effect_args_cannot_inject_filters() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            echo: Some("0.8:0.9:1000:0.3,amovie=/etc/passwd".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            FilterPolicy::default().check(&params),
+            Err(PolicyError::InvalidEffectArgs {
+                param: "echo".to_string(),
+                filter: "aecho=0.8:0.9:1000:0.3,amovie=/etc/passwd".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_disabled_custom_filter() {
+        assert_eq!(
+            policy(&["areverse"], 0).check(&with_filters(&["[0:a]volume=2,areverse[out]"])),
+            Err(PolicyError::DisabledFilter("areverse".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_source_and_sink_filters_are_forbidden() {
+        let policy = FilterPolicy::new(&ProcessorSettings {
+            allowed_filters: vec!["amovie".to_string(), "volume".to_string()],
+            ..Default::default()
+        });
+
+        for graph in [
+            "amovie=/etc/passwd",
+            "volume=1;sendcmd=f=cmds.txt",
+            "movie=x.mp4",
+        ] {
+            assert!(matches!(
+                policy.check(&with_filters(&[graph])),
+                Err(PolicyError::ForbiddenFilter(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_file_options_are_forbidden() {
+        assert!(matches!(
+            FilterPolicy::default().check(&with_filters(&["firequalizer=dumpfile=/tmp/x"])),
+            Err(PolicyError::ForbiddenFilter(_))
+        ));
+
+        let policy = FilterPolicy::new(&ProcessorSettings {
+            allowed_filters: vec!["firequalizer".to_string(), "volume".to_string()],
+            ..Default::default()
+        });
+        assert!(matches!(
+            policy.check(&with_filters(&["firequalizer=gain=0"])),
+            Err(PolicyError::ForbiddenFilter(_))
+        ));
+        for graph in [
+            "volume=1:dumpfile=/tmp/x",
+            "volume=file=/tmp/x",
+            "volume=1: FileName=x",
+        ] {
+            assert!(matches!(
+                policy.check(&with_filters(&[graph])),
+                Err(PolicyError::ForbiddenFilterOption { .. })
+            ));
+        }
+        assert_eq!(policy.check(&with_filters(&["volume=volume=0.5"])), Ok(()));
+    }
+
+    #[test]
+    fn test_filters_outside_allowlist_are_rejected() {
+        assert_eq!(
+            FilterPolicy::default().check(&with_filters(&["volume=2,showwavespic"])),
+            Err(PolicyError::FilterNotAllowed("showwavespic".to_string()))
+        );
+        for graph in ["aloop=loop=-1:size=44100", "apad"] {
+            assert!(matches!(
+                FilterPolicy::default().check(&with_filters(&[graph])),
+                Err(PolicyError::FilterNotAllowed(_))
+            ));
+        }
+
+        let policy = FilterPolicy::new(&ProcessorSettings {
+            allowed_filters: vec!["volume".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(
+            policy.check(&with_filters(&["vibrato=f=5"])),
+            Err(PolicyError::FilterNotAllowed("vibrato".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_filtergraph() {
+        assert!(matches!(
+            FilterPolicy::default().check(&with_filters(&["volume='2"])),
+            Err(PolicyError::InvalidFiltergraph { .. })
+        ));
+    }
+
+    #[test]
+    fn test_custom_options() {
+        let policy = FilterPolicy::default();
+
+        assert_eq!(
+            policy.check(&with_options(&["-vn", "-write_xing", "0"])),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&with_options(&["-f", "tee"])),
+            Err(PolicyError::OptionNotAllowed("-f".to_string()))
+        );
+        assert_eq!(
+            policy.check(&with_options(&["/tmp/out.mp3"])),
+            Err(PolicyError::OptionNotAllowed("/tmp/out.mp3".to_string()))
+        );
+        assert_eq!(
+            policy.check(&with_options(&["-movflags", "file:/tmp/x"])),
+            Err(PolicyError::InvalidOptionValue {
+                option: "-movflags".to_string(),
+                value: "file:/tmp/x".to_string(),
+            })
+        );
+        assert!(matches!(
+            policy.check(&with_options(&["-map_metadata"])),
+            Err(PolicyError::InvalidOptionValue { .. })
+        ));
+    }
+
+    #[test]
+    fn test_filtergraph_options_are_checked_when_allowed() {
+        let policy = FilterPolicy::new(&ProcessorSettings {
+            allowed_options: vec!["-af".to_string()],
+            ..Default::default()
+        });
+
+        assert_eq!(policy.check(&with_options(&["-af", "volume=2"])), Ok(()));
+        assert!(matches!(
+            policy.check(&with_options(&["-af", "amovie=/etc/passwd"])),
+            Err(PolicyError::ForbiddenFilter(_))
+        ));
+    }
+
+    #[test]
+    fn test_max_filter_ops() {
+        let params = Params {
+            key: "test.mp3".to_string(),
+            reverse: Some(true),
+            fade_in: Some(1.0),
+            custom_filters: Some(vec!["volume=2,vibrato=f=5".to_string()]),
+            ..Default::default()
+        };
+
+        assert_eq!(policy(&[], 4).check(&params), Ok(()));
+        assert_eq!(
+            policy(&[], 3).check(&params),
+            Err(PolicyError::TooManyOps { count: 4, max: 3 })
+        );
+    }
+}
@@ -1,40 +1,96 @@
 use std::{collections::HashMap, num::NonZeroUsize};
 
 use axum::async_trait;
-use color_eyre::Result;
-use tempfile::TempDir;
-use tokio::sync::Semaphore;
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument};
 
 use crate::{
-    blob::AudioBuffer, config::ProcessorSettings, cyberpunkpath::params::Params,
-    processor::ffmpeg::process_audio,
+    blob::AudioBuffer,
+    config::ProcessorSettings,
+    cyberpunkpath::params::Params,
+    processor::{
+        error::ProcessingError,
+        ffmpeg::{process_audio, FfmpegLimits},
+        native::native::{NativeError, NativeProcessor},
+        policy::{FilterPolicy, PolicyError},
+        queue::{Priority, ProcessingQueue},
+    },
+    stream::AudioStream,
 };
 
 #[async_trait]
 pub trait AudioProcessor: Send + Sync {
-    async fn process(&self, blob: &AudioBuffer, params: &Params) -> Result<AudioBuffer>;
+    /// Starts processing, returning the output as it is produced. Requests
+    /// of a higher `priority` are started first when the processor is busy.
+    async fn process_stream(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, ProcessingError>;
+
+    /// Like `process_stream`, also reporting how far along the output is
+    /// where that can be told. It can take probing the input first.
+    async fn process_stream_with_progress(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, ProcessingError> {
+        self.process_stream(blob, params, priority).await
+    }
+
+    async fn process(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioBuffer, ProcessingError> {
+        let stream = self.process_stream(blob, params, priority).await?;
+        Ok(stream.into_buffer().await?)
+    }
+
+    /// Rejects params the processor is not willing to run, before any audio
+    /// is fetched.
+    fn validate(&self, _params: &Params) -> Result<(), PolicyError> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
 pub struct Processor {
-    semaphore: Semaphore,
+    queue: ProcessingQueue,
     tags: HashMap<String, String>,
+    policy: FilterPolicy,
+    limits: FfmpegLimits,
+    /// Bytes of output allowed per run
+    max_output_size: Option<u64>,
+    native: Option<NativeProcessor>,
 }
 
 #[async_trait]
 impl AudioProcessor for Processor {
     #[tracing::instrument(skip(self, blob, params))]
-    async fn process(&self, blob: &AudioBuffer, params: &Params) -> Result<AudioBuffer> {
-        let _permit = self.semaphore.acquire().await?;
-        info!(params = ?params, "Processing with FFmpeg");
-
-        let temp_dir = TempDir::new()?;
+    async fn process_stream(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, ProcessingError> {
+        self.run(blob, params, priority, false).await
+    }
 
-        let processed_audio = process_audio(blob, params, temp_dir, &self.tags).await?;
-        info!("Audio processing completed successfully");
+    #[tracing::instrument(skip(self, blob, params))]
+    async fn process_stream_with_progress(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+    ) -> Result<AudioStream, ProcessingError> {
+        self.run(blob, params, priority, true).await
+    }
 
-        Ok(processed_audio)
+    fn validate(&self, params: &Params) -> Result<(), PolicyError> {
+        self.policy.check(params)
     }
 }
 
@@ -56,9 +112,67 @@ impl Processor {
             "Initializing processor"
         );
 
+        let queue = ProcessingQueue::new(max_concurrent.get(), &config);
+        let limits = FfmpegLimits::from(&config);
+        let native = config
+            .native
+            .then(|| NativeProcessor::new(queue.clone(), tags.clone(), limits));
+
         Self {
-            semaphore: Semaphore::new(max_concurrent.get()),
+            queue,
             tags,
+            policy: FilterPolicy::new(&config),
+            limits,
+            max_output_size: (config.max_output_size > 0)
+                .then(|| config.max_output_size * 1024 * 1024),
+            native,
         }
     }
+
+    async fn run(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+        progress: bool,
+    ) -> Result<AudioStream, ProcessingError> {
+        self.validate(params)?;
+
+        let stream = self.start(blob, params, priority, progress).await?;
+        Ok(match self.max_output_size {
+            Some(max) => stream.limit(max),
+            None => stream,
+        })
+    }
+
+    /// Runs natively where possible, and with FFmpeg otherwise. Only FFmpeg
+    /// reports `progress`.
+    async fn start(
+        &self,
+        blob: &AudioBuffer,
+        params: &Params,
+        priority: Priority,
+        progress: bool,
+    ) -> Result<AudioStream, ProcessingError> {
+        if let Some(native) = &self.native {
+            if NativeProcessor::supports(params, blob.format()) {
+                match native.run(blob, params, priority).await {
+                    Err(NativeError::Unsupported(reason)) => {
+                        debug!(%reason, "Falling back to FFmpeg");
+                    }
+                    result => return Ok(result?),
+                }
+            }
+        }
+
+        // Held by the stream until FFmpeg is done
+        let permit = self.queue.acquire(priority).await?;
+        info!(params = ?params, "Processing with FFmpeg");
+
+        let stream =
+            process_audio(blob, params, &self.tags, &self.limits, progress, permit).await?;
+        info!("Audio processing started");
+
+        Ok(stream)
+    }
 }
@@ -0,0 +1,383 @@
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use tokio::{sync::oneshot, time::Instant};
+use tracing::debug;
+
+use crate::{config::ProcessorSettings, processor::error::ProcessingError};
+
+/// Who gets a processing slot first. Signed requests go ahead of `unsafe`
+/// ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low = 0,
+    High = 1,
+}
+
+impl Priority {
+    const ALL: [Priority; 2] = [Priority::Low, Priority::High];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::High => "high",
+        }
+    }
+
+    /// The priority of a request for `/{signature}/...`.
+    pub fn for_path(path: &str) -> Self {
+        if path.starts_with("/unsafe/") {
+            Priority::Low
+        } else {
+            Priority::High
+        }
+    }
+}
+
+/// Signatures are checked before any handler runs, so a request that isn't
+/// `unsafe` has a valid one.
+#[async_trait]
+impl<S> FromRequestParts<S> for Priority
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Priority::for_path(
+            parts.uri.path().trim_start_matches("/meta"),
+        ))
+    }
+}
+
+/// Limits processing to a number of concurrent runs. Requests beyond that
+/// wait in a queue, highest priority first and then in arrival order. A
+/// full queue turns requests away with `ProcessingError::Busy`, unless they
+/// can take the place of a lower priority request, which is turned away
+/// instead.
+#[derive(Debug, Clone)]
+pub struct ProcessingQueue {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: Mutex<State>,
+    max_depth: Option<usize>,
+    max_wait: Option<Duration>,
+    retry_after: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Runs that could start right away
+    available: usize,
+    next_id: u64,
+    /// Waiting requests by priority, oldest first. Dropping a sender turns
+    /// the request away.
+    waiting: [VecDeque<(u64, oneshot::Sender<()>)>; 2],
+}
+
+impl State {
+    fn depth(&self) -> usize {
+        self.waiting.iter().map(VecDeque::len).sum()
+    }
+
+    fn record_depth(&self, priority: Priority) {
+        metrics::gauge!("processing_queue_depth", "priority" => priority.as_str())
+            .set(self.waiting[priority as usize].len() as f64);
+    }
+}
+
+impl ProcessingQueue {
+    pub fn new(concurrency: usize, config: &ProcessorSettings) -> Self {
+        let nonzero = |value: u64| (value > 0).then_some(value);
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    available: concurrency,
+                    next_id: 0,
+                    waiting: Default::default(),
+                }),
+                max_depth: (config.max_queue_depth > 0).then_some(config.max_queue_depth),
+                max_wait: nonzero(config.max_queue_wait).map(Duration::from_secs),
+                retry_after: config.queue_retry_after,
+            }),
+        }
+    }
+
+    /// Waits for a processing slot, which is held until the permit is
+    /// dropped.
+    pub async fn acquire(&self, priority: Priority) -> Result<QueuePermit, ProcessingError> {
+        let started = Instant::now();
+        let mut waiter = {
+            let mut state = self.inner.state.lock().unwrap();
+            if state.available > 0 {
+                state.available -= 1;
+                return Ok(self.granted(priority, started));
+            }
+
+            if self.inner.max_depth.is_some_and(|max| state.depth() >= max) {
+                let lower = Priority::ALL
+                    .into_iter()
+                    .filter(|lower| *lower < priority)
+                    .find(|lower| !state.waiting[*lower as usize].is_empty());
+                let Some(lower) = lower else {
+                    return Err(self.reject(priority, "full"));
+                };
+                // The newest lower priority request gives up its place
+                state.waiting[lower as usize].pop_back();
+                state.record_depth(lower);
+            }
+
+            let id = state.next_id;
+            state.next_id += 1;
+            let (tx, rx) = oneshot::channel();
+            state.waiting[priority as usize].push_back((id, tx));
+            state.record_depth(priority);
+
+            Waiter {
+                inner: self.inner.clone(),
+                id,
+                priority,
+                rx,
+                done: false,
+            }
+        };
+
+        let result = match self.inner.max_wait {
+            Some(max_wait) => tokio::time::timeout(max_wait, &mut waiter.rx)
+                .await
+                .map_err(|_| "timeout"),
+            None => Ok((&mut waiter.rx).await),
+        };
+        match result {
+            Ok(Ok(())) => {
+                waiter.done = true;
+                Ok(self.granted(priority, started))
+            }
+            Ok(Err(_)) => {
+                waiter.done = true;
+                Err(self.reject(priority, "displaced"))
+            }
+            Err(reason) => Err(self.reject(priority, reason)),
+        }
+    }
+
+    fn granted(&self, priority: Priority, started: Instant) -> QueuePermit {
+        metrics::histogram!("processing_queue_wait_seconds", "priority" => priority.as_str())
+            .record(started.elapsed().as_secs_f64());
+        QueuePermit {
+            inner: self.inner.clone(),
+        }
+    }
+
+    fn reject(&self, priority: Priority, reason: &'static str) -> ProcessingError {
+        debug!(priority = priority.as_str(), reason, "Turning request away");
+        metrics::counter!(
+            "processing_queue_rejected_total",
+            "priority" => priority.as_str(),
+            "reason" => reason,
+        )
+        .increment(1);
+        ProcessingError::Busy {
+            retry_after: self.inner.retry_after,
+        }
+    }
+}
+
+impl Inner {
+    /// Hands a finished run's slot to the next request in line.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        for priority in Priority::ALL.into_iter().rev() {
+            while let Some((_, tx)) = state.waiting[priority as usize].pop_front() {
+                state.record_depth(priority);
+                if tx.send(()).is_ok() {
+                    return;
+                }
+            }
+        }
+        state.available += 1;
+    }
+}
+
+/// A processing slot, given back to the queue on drop.
+#[derive(Debug)]
+pub struct QueuePermit {
+    inner: Arc<Inner>,
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        self.inner.release();
+    }
+}
+
+/// A request in the queue. One that stops waiting, e.g. because the client
+/// went away, leaves the queue, or passes on the slot it was just given.
+struct Waiter {
+    inner: Arc<Inner>,
+    id: u64,
+    priority: Priority,
+    rx: oneshot::Receiver<()>,
+    done: bool,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut state = self.inner.state.lock().unwrap();
+        let waiting = &mut state.waiting[self.priority as usize];
+        if let Some(position) = waiting.iter().position(|(id, _)| *id == self.id) {
+            waiting.remove(position);
+            state.record_depth(self.priority);
+            return;
+        }
+        drop(state);
+
+        if self.rx.try_recv().is_ok() {
+            self.inner.release();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(concurrency: usize, max_depth: usize, max_wait: u64) -> ProcessingQueue {
+        ProcessingQueue::new(
+            concurrency,
+            &ProcessorSettings {
+                max_queue_depth: max_depth,
+                max_queue_wait: max_wait,
+                queue_retry_after: 7,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn depth(queue: &ProcessingQueue) -> usize {
+        queue.inner.state.lock().unwrap().depth()
+    }
+
+    /// Lets spawned waiters get in line.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    #[tokio::test]
+    async fn test_high_priority_goes_first() {
+        let queue = queue(1, 0, 0);
+        let running = queue.acquire(Priority::Low).await.unwrap();
+
+        let (order_tx, mut order) = tokio::sync::mpsc::unbounded_channel();
+        for (i, priority) in [Priority::Low, Priority::High, Priority::Low, Priority::High]
+            .into_iter()
+            .enumerate()
+        {
+            let (queue, order_tx) = (queue.clone(), order_tx.clone());
+            tokio::spawn(async move {
+                let _permit = queue.acquire(priority).await.unwrap();
+                order_tx.send(i).unwrap();
+            });
+            settle().await;
+        }
+        assert_eq!(depth(&queue), 4);
+
+        drop(running);
+        let mut served = Vec::new();
+        for _ in 0..4 {
+            served.push(order.recv().await.unwrap());
+        }
+        assert_eq!(served, vec![1, 3, 0, 2]);
+        assert_eq!(queue.inner.state.lock().unwrap().available, 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_turns_requests_away() {
+        let queue = queue(1, 1, 0);
+        let running = queue.acquire(Priority::High).await.unwrap();
+
+        let low = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(Priority::Low).await.map(|_| ()) }
+        });
+        settle().await;
+
+        // Another low priority request doesn't fit
+        let err = queue.acquire(Priority::Low).await.unwrap_err();
+        assert_eq!(err, ProcessingError::Busy { retry_after: 7 });
+
+        // A high priority one takes the low priority one's place
+        let high = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(Priority::High).await.map(|_| ()) }
+        });
+        assert_eq!(
+            low.await.unwrap(),
+            Err(ProcessingError::Busy { retry_after: 7 })
+        );
+        assert!(queue.acquire(Priority::High).await.is_err());
+
+        drop(running);
+        assert_eq!(high.await.unwrap(), Ok(()));
+        assert_eq!(depth(&queue), 0);
+    }
+
+    #[tokio::test]
+    async fn test_waiting_is_bounded() {
+        let queue = queue(1, 0, 1);
+        let running = queue.acquire(Priority::High).await.unwrap();
+
+        let err = queue.acquire(Priority::High).await.unwrap_err();
+        assert_eq!(err, ProcessingError::Busy { retry_after: 7 });
+        assert_eq!(depth(&queue), 0);
+
+        drop(running);
+        assert!(queue.acquire(Priority::Low).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_requests_leave_the_queue() {
+        let queue = queue(1, 0, 0);
+        let running = queue.acquire(Priority::High).await.unwrap();
+
+        let waiting = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.acquire(Priority::High).await.map(|_| ()) }
+        });
+        settle().await;
+        assert_eq!(depth(&queue), 1);
+
+        waiting.abort();
+        let _ = waiting.await;
+        assert_eq!(depth(&queue), 0);
+
+        drop(running);
+        assert_eq!(queue.inner.state.lock().unwrap().available, 1);
+    }
+
+    #[tokio::test]
+    async fn test_slot_given_to_a_cancelled_request_is_passed_on() {
+        let queue = queue(1, 0, 0);
+        let running = queue.acquire(Priority::High).await.unwrap();
+
+        let mut waiting = Box::pin(queue.acquire(Priority::High));
+        assert!(futures::poll!(&mut waiting).is_pending());
+
+        // The slot is handed over, but the request is gone before it runs
+        drop(running);
+        drop(waiting);
+        assert_eq!(queue.inner.state.lock().unwrap().available, 1);
+    }
+}
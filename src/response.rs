@@ -0,0 +1,534 @@
+//! Range, conditional and caching headers shared by every audio response,
+//! and the errors returned instead of one.
+
+use std::ops::RangeInclusive;
+
+use axum::{
+    body::Body,
+    http::{header, response::Builder, HeaderMap, HeaderValue, Response, StatusCode},
+    response::IntoResponse,
+};
+use bytes::{Bytes, BytesMut};
+use chrono::{DateTime, SubsecRound, Utc};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    loader::loader::LoaderError,
+    processor::{error::ProcessingError, policy::PolicyError},
+};
+
+/// Requests asking for more ranges than this get the whole body instead.
+const MAX_RANGES: usize = 16;
+
+/// A strong validator for processed audio, from the hash of its params and
+/// the validator of the source it is made from, when the loader has one. It
+/// is known before the audio is, so every path can send it.
+pub fn etag(params_hash: &str, source: Option<&str>) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(params_hash.as_bytes());
+    if let Some(source) = source {
+        hasher.update(b"\n");
+        hasher.update(source.as_bytes());
+    }
+    format!("\"{}\"", &hex::encode(hasher.finalize())[..20])
+}
+
+/// Why audio couldn't be served. Processing errors have a JSON body saying
+/// what went wrong; everything else is a plain message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorResponse {
+    Status(StatusCode, String),
+    Processing(ProcessingError),
+}
+
+impl From<(StatusCode, String)> for ErrorResponse {
+    fn from((status, message): (StatusCode, String)) -> Self {
+        ErrorResponse::Status(status, message)
+    }
+}
+
+impl From<PolicyError> for ErrorResponse {
+    fn from(err: PolicyError) -> Self {
+        <(StatusCode, String)>::from(err).into()
+    }
+}
+
+impl From<LoaderError> for ErrorResponse {
+    fn from(err: LoaderError) -> Self {
+        <(StatusCode, String)>::from(err).into()
+    }
+}
+
+impl From<ProcessingError> for ErrorResponse {
+    fn from(err: ProcessingError) -> Self {
+        ErrorResponse::Processing(err)
+    }
+}
+
+impl IntoResponse for ErrorResponse {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            ErrorResponse::Status(status, message) => (status, message).into_response(),
+            ErrorResponse::Processing(err) => err.into_response(),
+        }
+    }
+}
+
+/// The headers describing a response body.
+#[derive(Debug, Clone)]
+pub struct Representation {
+    pub content_type: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub cache_control: HeaderValue,
+}
+
+impl Representation {
+    /// Whether the client's copy is still current per `If-None-Match`, or
+    /// `If-Modified-Since` when there is no `If-None-Match`.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+            return if_none_match.trim() == "*"
+                || if_none_match.split(',').any(|tag| {
+                    self.etag
+                        .as_deref()
+                        .is_some_and(|etag| weak_eq(tag.trim(), etag))
+                });
+        }
+
+        match (
+            self.last_modified,
+            header_date(headers, header::IF_MODIFIED_SINCE),
+        ) {
+            (Some(last_modified), Some(since)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    pub fn not_modified(&self) -> Result<Response<Body>, (StatusCode, String)> {
+        build(
+            self.validators(Response::builder().status(StatusCode::NOT_MODIFIED)),
+            Body::empty(),
+        )
+    }
+
+    /// Responds with `data`, honouring conditional and range requests.
+    pub fn full(
+        &self,
+        headers: &HeaderMap,
+        data: Bytes,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        if self.is_not_modified(headers) {
+            return self.not_modified();
+        }
+
+        let total = data.len() as u64;
+        let ranges = if self.if_range_matches(headers) {
+            header_str(headers, header::RANGE).map_or(Ranges::Full, |r| parse_ranges(r, total))
+        } else {
+            Ranges::Full
+        };
+
+        match ranges {
+            Ranges::Full => build(
+                self.headers(StatusCode::OK)
+                    .header(header::CONTENT_TYPE, &self.content_type)
+                    .header(header::CONTENT_LENGTH, total),
+                Body::from(data),
+            ),
+            Ranges::Unsatisfiable => build(
+                self.headers(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(header::CONTENT_RANGE, format!("bytes */{}", total)),
+                Body::empty(),
+            ),
+            Ranges::Partial(ranges) if ranges.len() == 1 => {
+                let range = &ranges[0];
+                let part = data.slice(*range.start() as usize..=*range.end() as usize);
+                build(
+                    self.headers(StatusCode::PARTIAL_CONTENT)
+                        .header(header::CONTENT_TYPE, &self.content_type)
+                        .header(header::CONTENT_LENGTH, part.len())
+                        .header(header::CONTENT_RANGE, content_range(range, total)),
+                    Body::from(part),
+                )
+            }
+            Ranges::Partial(ranges) => {
+                let boundary = format!("cyberpunk_{:016x}", rand::random::<u64>());
+                let mut body = BytesMut::new();
+                for range in &ranges {
+                    body.extend_from_slice(
+                        format!(
+                            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                            boundary,
+                            self.content_type,
+                            content_range(range, total)
+                        )
+                        .as_bytes(),
+                    );
+                    body.extend_from_slice(&data[*range.start() as usize..=*range.end() as usize]);
+                }
+                body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+                build(
+                    self.headers(StatusCode::PARTIAL_CONTENT)
+                        .header(
+                            header::CONTENT_TYPE,
+                            format!("multipart/byteranges; boundary={}", boundary),
+                        )
+                        .header(header::CONTENT_LENGTH, body.len()),
+                    Body::from(body.freeze()),
+                )
+            }
+        }
+    }
+
+    /// Responds with a body whose length isn't known yet. Ranges can't be
+    /// served from it, so callers wanting them should use `full`.
+    pub fn stream(
+        &self,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<Response<Body>, (StatusCode, String)> {
+        if self.is_not_modified(headers) {
+            return self.not_modified();
+        }
+
+        build(
+            self.headers(StatusCode::OK)
+                .header(header::CONTENT_TYPE, &self.content_type),
+            body,
+        )
+    }
+
+    fn headers(&self, status: StatusCode) -> Builder {
+        self.validators(Response::builder().status(status))
+            .header(header::ACCEPT_RANGES, "bytes")
+            .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+            .header(header::CONTENT_DISPOSITION, "inline")
+    }
+
+    fn validators(&self, builder: Builder) -> Builder {
+        let mut builder = builder.header(header::CACHE_CONTROL, self.cache_control.clone());
+        if let Some(etag) = &self.etag {
+            builder = builder.header(header::ETAG, etag);
+        }
+        match self.last_modified {
+            Some(last_modified) => builder.header(header::LAST_MODIFIED, http_date(last_modified)),
+            None => builder,
+        }
+    }
+
+    /// `If-Range` only lets a range through when the client's copy is
+    /// exactly this one.
+    fn if_range_matches(&self, headers: &HeaderMap) -> bool {
+        let Some(if_range) = header_str(headers, header::IF_RANGE) else {
+            return true;
+        };
+        let if_range = if_range.trim();
+        if if_range.starts_with('"') {
+            return self.etag.as_deref() == Some(if_range);
+        }
+        match (self.last_modified, parse_http_date(if_range)) {
+            (Some(last_modified), Some(date)) => last_modified == date,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Ranges {
+    /// No usable `Range` header, so the whole body is sent
+    Full,
+    /// Satisfiable ranges, sorted and with overlapping ones merged
+    Partial(Vec<RangeInclusive<u64>>),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a body of `total` bytes. Headers that are
+/// malformed or use a unit other than bytes are ignored, as RFC 9110 asks.
+pub fn parse_ranges(header: &str, total: u64) -> Ranges {
+    let Some((unit, specs)) = header.split_once('=') else {
+        return Ranges::Full;
+    };
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Ranges::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        let Some((first, last)) = spec.split_once('-') else {
+            return Ranges::Full;
+        };
+        let (first, last) = (first.trim(), last.trim());
+
+        let range = if first.is_empty() {
+            // Suffix range: the last N bytes
+            let Ok(length) = last.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            (length > 0 && total > 0).then(|| total.saturating_sub(length)..=total - 1)
+        } else {
+            let Ok(first) = first.parse::<u64>() else {
+                return Ranges::Full;
+            };
+            let last = match last {
+                "" => u64::MAX,
+                last => match last.parse::<u64>() {
+                    Ok(last) if last >= first => last,
+                    _ => return Ranges::Full,
+                },
+            };
+            (first < total).then(|| first..=last.min(total - 1))
+        };
+        ranges.extend(range);
+    }
+
+    if ranges.is_empty() {
+        return if specs.trim().is_empty() {
+            Ranges::Full
+        } else {
+            Ranges::Unsatisfiable
+        };
+    }
+    if ranges.len() > MAX_RANGES {
+        return Ranges::Full;
+    }
+
+    ranges.sort_by_key(|r| *r.start());
+    let mut merged: Vec<RangeInclusive<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if *range.start() <= last.end().saturating_add(1) => {
+                *last = *last.start()..=*last.end().max(range.end());
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ranges::Partial(merged)
+}
+
+fn content_range(range: &RangeInclusive<u64>, total: u64) -> String {
+    format!("bytes {}-{}/{}", range.start(), range.end(), total)
+}
+
+/// Compares entity tags ignoring the weak `W/` prefix.
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+fn header_date(headers: &HeaderMap, name: header::HeaderName) -> Option<DateTime<Utc>> {
+    header_str(headers, name).and_then(parse_http_date)
+}
+
+pub fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value.trim())
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Formats a date as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(date: DateTime<Utc>) -> String {
+    date.trunc_subsecs(0)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn build(builder: Builder, body: Body) -> Result<Response<Body>, (StatusCode, String)> {
+    builder.body(body).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to build response: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::to_bytes;
+
+    fn representation() -> Representation {
+        Representation {
+            content_type: "audio/ogg".to_string(),
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some(
+                DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc),
+            ),
+            cache_control: HeaderValue::from_static("public, max-age=60"),
+        }
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_ranges() {
+        assert_eq!(
+            parse_ranges("bytes=0-499", 1000),
+            Ranges::Partial(vec![0..=499])
+        );
+        assert_eq!(
+            parse_ranges("bytes=900-", 1000),
+            Ranges::Partial(vec![900..=999])
+        );
+        assert_eq!(
+            parse_ranges("bytes=-500", 1000),
+            Ranges::Partial(vec![500..=999])
+        );
+        assert_eq!(
+            parse_ranges("bytes=-5000", 1000),
+            Ranges::Partial(vec![0..=999])
+        );
+        assert_eq!(
+            parse_ranges("bytes=0-2000", 1000),
+            Ranges::Partial(vec![0..=999])
+        );
+        assert_eq!(
+            parse_ranges("bytes=500-599, 0-99, 50-149, 150-199", 1000),
+            Ranges::Partial(vec![0..=199, 500..=599])
+        );
+    }
+
+    #[test]
+    fn test_parse_ranges_unsatisfiable() {
+        assert_eq!(parse_ranges("bytes=1000-", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=-0", 1000), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=0-10", 0), Ranges::Unsatisfiable);
+        assert_eq!(parse_ranges("bytes=-10", 0), Ranges::Unsatisfiable);
+        // Satisfiable ranges are served even if others are not
+        assert_eq!(
+            parse_ranges("bytes=2000-3000, 0-9", 1000),
+            Ranges::Partial(vec![0..=9])
+        );
+    }
+
+    #[test]
+    fn test_parse_ranges_ignores_invalid_headers() {
+        for header in [
+            "items=0-10",
+            "bytes",
+            "bytes=a-b",
+            "bytes=10-5",
+            "bytes=5",
+            "bytes=",
+        ] {
+            assert_eq!(parse_ranges(header, 1000), Ranges::Full, "{}", header);
+        }
+
+        let many = (0..=MAX_RANGES)
+            .map(|i| format!("{}-{}", i * 10, i * 10))
+            .collect::<Vec<_>>()
+            .join(",");
+        assert_eq!(parse_ranges(&format!("bytes={}", many), 1000), Ranges::Full);
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let rep = representation();
+
+        assert!(rep.is_not_modified(&request(&[(header::IF_NONE_MATCH, "\"abc\"")])));
+        assert!(rep.is_not_modified(&request(&[(header::IF_NONE_MATCH, "\"x\", W/\"abc\"")])));
+        assert!(rep.is_not_modified(&request(&[(header::IF_NONE_MATCH, "*")])));
+        assert!(!rep.is_not_modified(&request(&[(header::IF_NONE_MATCH, "\"other\"")])));
+
+        let since = |date: &str| request(&[(header::IF_MODIFIED_SINCE, date)]);
+        assert!(rep.is_not_modified(&since("Mon, 01 Jan 2024 00:00:00 GMT")));
+        assert!(!rep.is_not_modified(&since("Sun, 31 Dec 2023 23:59:59 GMT")));
+
+        // If-None-Match takes precedence over If-Modified-Since
+        assert!(!rep.is_not_modified(&request(&[
+            (header::IF_NONE_MATCH, "\"other\""),
+            (header::IF_MODIFIED_SINCE, "Mon, 01 Jan 2024 00:00:00 GMT"),
+        ])));
+    }
+
+    #[tokio::test]
+    async fn test_full_responses() {
+        let rep = representation();
+        let data = Bytes::from_static(b"0123456789");
+
+        let res = rep.full(&HeaderMap::new(), data.clone()).unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ETAG], "\"abc\"");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=60");
+        assert_eq!(
+            res.headers()[header::LAST_MODIFIED],
+            "Mon, 01 Jan 2024 00:00:00 GMT"
+        );
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+
+        let res = rep
+            .full(&request(&[(header::RANGE, "bytes=-3")]), data.clone())
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 7-9/10");
+        assert_eq!(to_bytes(res.into_body(), usize::MAX).await.unwrap(), "789");
+
+        let res = rep
+            .full(&request(&[(header::RANGE, "bytes=20-")]), data.clone())
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        let res = rep
+            .full(
+                &request(&[(header::IF_NONE_MATCH, "\"abc\"")]),
+                data.clone(),
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], "\"abc\"");
+
+        // A stale If-Range gets the whole body
+        let res = rep
+            .full(
+                &request(&[(header::RANGE, "bytes=0-1"), (header::IF_RANGE, "\"old\"")]),
+                data.clone(),
+            )
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // An empty body never panics
+        let res = rep
+            .full(&request(&[(header::RANGE, "bytes=0-")]), Bytes::new())
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+    }
+
+    #[tokio::test]
+    async fn test_multipart_ranges() {
+        let rep = representation();
+        let data = Bytes::from_static(b"0123456789");
+
+        let res = rep
+            .full(&request(&[(header::RANGE, "bytes=0-1,8-")]), data)
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+
+        let content_type = res.headers()[header::CONTENT_TYPE].to_str().unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            format!(
+                "\r\n--{b}\r\nContent-Type: audio/ogg\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+                 \r\n--{b}\r\nContent-Type: audio/ogg\r\nContent-Range: bytes 8-9/10\r\n\r\n89\
+                 \r\n--{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+}
@@ -1,93 +1,192 @@
 use axum::{
     body::Body,
     extract::State,
-    http::{header, Response, StatusCode},
-    response::IntoResponse,
+    http::{header, HeaderMap},
+    response::Response,
 };
-use tracing::{info, instrument, warn};
+use futures::StreamExt;
+use std::time::{Duration, Instant};
+use tracing::{field, info, instrument, warn, Span};
 
 use crate::{
     blob::AudioBuffer,
-    cyberpunkpath::{hasher::suffix_result_storage_hasher, params::Params},
+    cyberpunkpath::params::Params,
+    processor::{error::ProcessingError, queue::Priority},
+    response::{etag, ErrorResponse, Representation},
+    singleflight::{Flight, LockGuard, RedisLock},
     state::AppStateDyn,
+    stream::{tee, AudioStream},
 };
 
-#[instrument(skip(state))]
+/// How often a replica waiting on another one's lock checks for the result.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[instrument(skip(state, headers), fields(error = field::Empty))]
 pub async fn cyberpunkpath_handler(
     State(state): State<AppStateDyn>,
+    headers: HeaderMap,
+    priority: Priority,
     params: Params,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let params_hash = suffix_result_storage_hasher(&params);
-    let result = state.storage.get(&params_hash).await.inspect_err(|_| {
-        info!("no audio in results storage: {}", &params);
-    });
+) -> Result<Response, ErrorResponse> {
+    state.processor.validate(&params)?;
+
+    let params_hash = state.result_keyer.key(&params);
+    let source = state
+        .loader
+        .validator(&params.key)
+        .await
+        .unwrap_or_else(|e| {
+            warn!("Failed to get source validator: {}", e);
+            None
+        });
+    let representation = Representation {
+        content_type: String::new(),
+        etag: Some(etag(&params_hash, source.as_deref())),
+        last_modified: None,
+        cache_control: state.cache_control.clone(),
+    };
+
+    // The client's copy is checked before anything is loaded or processed
+    if representation.is_not_modified(&headers) {
+        return Ok(representation.not_modified()?);
+    }
+
+    let result = state
+        .result_storage
+        .get(&params_hash)
+        .await
+        .inspect_err(|_| {
+            info!("no audio in results storage: {}", &params);
+        });
     if let Ok(blob) = result {
-        return Response::builder()
-            .header(header::CONTENT_TYPE, blob.mime_type())
-            .body(Body::from(blob.into_bytes()))
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to build response: {}", e),
-                )
-            });
+        return processed(representation, &headers, blob);
     }
 
-    let blob = if params.key.starts_with("https://") || params.key.starts_with("http://") {
-        let raw_bytes = reqwest::get(&params.key)
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::NOT_FOUND,
-                    format!("Failed to fetch audio: {}", e),
-                )
-            })?
-            .bytes()
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to fetch audio: {}", e),
-                )
-            })?
-            .to_vec();
-
-        AudioBuffer::from_bytes(raw_bytes)
-    } else {
-        state.storage.get(&params.key).await.map_err(|e| {
-            (
-                StatusCode::NOT_FOUND,
-                format!("Failed to fetch audio: {}", e),
-            )
-        })?
+    // Only one request per result does the work; the others wait for it
+    let leader = loop {
+        match state.flights.join(&params_hash) {
+            Flight::Leader(leader) => break leader,
+            Flight::Follower(follower) => {
+                if let Some(result) = follower.wait().await {
+                    return processed(representation, &headers, result?);
+                }
+            }
+        }
     };
 
-    let processed_blob = state.processor.process(&blob, &params).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to process audio: {}", e),
-        )
-    })?;
+    let lock = match &state.lock {
+        Some(lock) => match acquire_or_wait(&state, lock, &params_hash).await {
+            Lock::Held(lock) => lock,
+            Lock::Processed(blob) => {
+                leader.finish(Ok(blob.clone()));
+                return processed(representation, &headers, blob);
+            }
+        },
+        None => None,
+    };
 
-    state
-        .storage
-        .put(&params_hash, &processed_blob)
-        .await
-        .map_err(|e| {
-            warn!("Failed to save result audio [{}]: {}", &params_hash, e);
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to save result audio: {}", e),
-            )
-        })?;
-
-    Response::builder()
-        .header(header::CONTENT_TYPE, processed_blob.mime_type())
-        .body(Body::from(processed_blob.into_bytes()))
-        .map_err(|e| {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Failed to build response: {}", e),
-            )
-        })
+    let output = match load_and_process(&state, &params, priority).await {
+        Ok(output) => output,
+        Err(e) => {
+            if let ErrorResponse::Processing(err) = &e {
+                Span::current().record("error", err.code());
+            }
+            leader.finish(Err(e.clone()));
+            return Err(e);
+        }
+    };
+
+    // Save the result once it has been fully streamed; an interrupted or
+    // failed stream is never saved, and leaves the waiting requests to retry
+    let format = output.format;
+    let result_storage = state.result_storage.clone();
+    let body = tee(output.body, move |data| {
+        let blob = AudioBuffer::from_bytes_with_format(data, format);
+        leader.finish(Ok(blob.clone()));
+        tokio::spawn(async move {
+            if let Err(e) = result_storage.put(&params_hash, &blob).await {
+                warn!("Failed to save result audio [{}]: {}", &params_hash, e);
+            }
+            // Other replicas look for the result once the lock is gone
+            drop(lock);
+        });
+    });
+
+    let representation = Representation {
+        content_type: format.mime_type().to_string(),
+        ..representation
+    };
+
+    // Ranges need the length up front, so wait for the whole output
+    if headers.contains_key(header::RANGE) {
+        let output = AudioStream {
+            format,
+            body: body.boxed(),
+            progress: None,
+        };
+        let blob = output
+            .into_buffer()
+            .await
+            .map_err(|e| ProcessingError::from_io(&e))?;
+        return processed(representation, &headers, blob);
+    }
+
+    Ok(representation.stream(&headers, Body::from_stream(body))?)
+}
+
+/// Serves output that has been fully produced.
+fn processed(
+    mut representation: Representation,
+    headers: &HeaderMap,
+    blob: AudioBuffer,
+) -> Result<Response, ErrorResponse> {
+    representation.content_type = blob.mime_type().to_string();
+    Ok(representation.full(headers, blob.into_bytes())?)
+}
+
+async fn load_and_process(
+    state: &AppStateDyn,
+    params: &Params,
+    priority: Priority,
+) -> Result<AudioStream, ErrorResponse> {
+    let blob = state.loader.load(&params.key).await?;
+
+    Ok(state
+        .processor
+        .process_stream(&blob, params, priority)
+        .await?)
+}
+
+enum Lock {
+    /// This replica processes the result, holding the lock if it got one
+    Held(Option<LockGuard>),
+    /// Another replica has processed the result
+    Processed(AudioBuffer),
+}
+
+/// Takes the cross-replica lock for `params_hash`, or waits for the replica
+/// holding it to store the result. Redis being unavailable only costs the
+/// deduplication.
+async fn acquire_or_wait(state: &AppStateDyn, lock: &RedisLock, params_hash: &str) -> Lock {
+    let started = Instant::now();
+    loop {
+        match lock.try_acquire(params_hash).await {
+            Ok(Some(guard)) => return Lock::Held(Some(guard)),
+            Ok(None) => {}
+            Err(e) => {
+                warn!("Failed to take processing lock [{}]: {}", params_hash, e);
+                return Lock::Held(None);
+            }
+        }
+
+        // The lock expires by itself, but don't wait forever on a slow store
+        if started.elapsed() > lock.ttl() {
+            return Lock::Held(None);
+        }
+
+        tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+        if let Ok(blob) = state.result_storage.get(params_hash).await {
+            return Lock::Processed(blob);
+        }
+    }
 }
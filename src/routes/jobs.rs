@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use url::form_urlencoded;
+use utoipa::ToSchema;
+
+use crate::{
+    cyberpunkpath::params::Params,
+    jobs::{error::JobError, jobs::Job},
+    middleware::authorize,
+    state::AppStateDyn,
+};
+
+/// What to process: a path as it would be requested, or params signed the
+/// way their canonical form is.
+#[derive(Deserialize, Debug, ToSchema)]
+#[serde(untagged)]
+pub enum JobRequest {
+    /// e.g. `/unsafe/format:ogg/song.mp3`
+    Path { path: String },
+    Params {
+        params: Box<Params>,
+        #[serde(default = "unsafe_signature")]
+        signature: String,
+    },
+}
+
+/// A job just started, with the token that gives access to it.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct CreatedJob {
+    #[serde(flatten)]
+    pub job: Job,
+    /// Sent as `Authorization: Bearer <token>` to get or cancel the job. It
+    /// isn't shown again.
+    pub token: String,
+}
+
+fn unsafe_signature() -> String {
+    "unsafe".to_string()
+}
+
+impl JobRequest {
+    /// The path serving the output.
+    fn location(self) -> String {
+        match self {
+            JobRequest::Path { path } if path.starts_with('/') => path,
+            JobRequest::Path { path } => format!("/{}", path),
+            JobRequest::Params { params, signature } => format!("/{}/{}", signature, params)
+                .trim_end_matches('?')
+                .to_string(),
+        }
+    }
+}
+
+#[instrument(skip(state))]
+pub async fn create_job(
+    State(state): State<AppStateDyn>,
+    Json(request): Json<JobRequest>,
+) -> Result<impl IntoResponse, JobError> {
+    let location = request.location();
+    let (path, query) = match location.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (location.as_str(), None),
+    };
+    let query_params: HashMap<String, String> =
+        form_urlencoded::parse(query.unwrap_or("").as_bytes())
+            .into_owned()
+            .collect();
+    let params = Params::from_path_strict(path.to_string(), query_params)?;
+    authorize(&state, &params, path, query)?;
+
+    let (job, token) = state.jobs.submit(&state, params, location.clone()).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        [(header::LOCATION, format!("/jobs/{}", job.id))],
+        Json(CreatedJob { job, token }),
+    ))
+}
+
+#[instrument(skip(state, headers))]
+pub async fn get_job(
+    State(state): State<AppStateDyn>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Job>, JobError> {
+    Ok(Json(state.jobs.get(&id, bearer(&headers)).await?))
+}
+
+#[instrument(skip(state, headers))]
+pub async fn cancel_job(
+    State(state): State<AppStateDyn>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<Job>, JobError> {
+    Ok(Json(state.jobs.cancel(&id, bearer(&headers)).await?))
+}
+
+/// The token in `Authorization: Bearer <token>`, empty if there is none.
+fn bearer(headers: &HeaderMap) -> &str {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map_or("", str::trim)
+}
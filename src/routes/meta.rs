@@ -5,16 +5,15 @@ use axum::{
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tracing::{info, instrument};
+use tracing::{info, instrument, warn};
 use utoipa::ToSchema;
 
 use crate::{
-    blob::AudioBuffer,
-    cyberpunkpath::params::Params,
-    state::AppStateDyn,
+    blob::AudioBuffer, cache::metadata::fingerprint, cyberpunkpath::params::Params,
+    processor::queue::Priority, response::ErrorResponse, state::AppStateDyn,
 };
 
-#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
 pub struct AudioMetadata {
     pub format: String,
     pub duration: Option<f64>,
@@ -29,46 +28,38 @@ pub struct AudioMetadata {
 #[instrument(skip(state))]
 pub async fn meta_handler(
     State(state): State<AppStateDyn>,
+    priority: Priority,
     params: Params,
-) -> Result<Json<AudioMetadata>, (StatusCode, String)> {
+) -> Result<Json<AudioMetadata>, ErrorResponse> {
     info!("meta: {:?}", params);
 
-    let blob = if params.key.starts_with("https://") || params.key.starts_with("http://") {
-        let raw_bytes = reqwest::get(&params.key)
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::NOT_FOUND,
-                    format!("Failed to fetch audio: {}", e),
-                )
-            })?
-            .bytes()
-            .await
-            .map_err(|e| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Failed to fetch audio: {}", e),
-                )
-            })?
-            .to_vec();
-
-        AudioBuffer::from_bytes(raw_bytes)
-    } else {
-        state.storage.get(&params.key).await.map_err(|e| {
-            (
-                StatusCode::NOT_FOUND,
-                format!("Failed to fetch audio: {}", e),
-            )
-        })?
+    state.processor.validate(&params)?;
+
+    // Check the cache against a cheap validator so hits don't load the source,
+    // falling back to a hash of the source when the loader has none
+    let validator = state.loader.validator(&params.key).await.unwrap_or_else(|e| {
+        warn!("Failed to get source validator: {}", e);
+        None
+    });
+    let (source, loaded) = match validator {
+        Some(validator) => (validator, None),
+        None => {
+            let blob = state.loader.load(&params.key).await?;
+            (fingerprint(&blob), Some(blob))
+        }
     };
 
+    match state.metadata_cache.get(&params, &source).await {
+        Ok(Some(metadata)) => return Ok(Json(metadata)),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to get cached metadata: {}", e),
+    }
 
-    let processed_blob = state.processor.process(&blob, &params).await.map_err(|e| {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to process audio: {}", e),
-        )
-    })?;
+    let blob = match loaded {
+        Some(blob) => blob,
+        None => state.loader.load(&params.key).await?,
+    };
+    let processed_blob = state.processor.process(&blob, &params, priority).await?;
 
     let metadata = extract_metadata(&processed_blob).await.map_err(|e| {
         (
@@ -77,6 +68,10 @@ pub async fn meta_handler(
         )
     })?;
 
+    if let Err(e) = state.metadata_cache.set(&params, &source, &metadata).await {
+        warn!("Failed to cache metadata: {}", e);
+    }
+
     Ok(Json(metadata))
 }
 
@@ -123,13 +118,12 @@ async fn extract_metadata(audio: &AudioBuffer) -> Result<AudioMetadata, color_ey
         .unwrap_or(&empty_streams);
     
     // Find the first audio stream
-    let audio_stream = streams
-        .iter()
-        .find(|stream| {
-            stream.get("codec_type")
-                .and_then(|ct| ct.as_str())
-                .map_or(false, |ct| ct == "audio")
-        });
+    let audio_stream = streams.iter().find(|stream| {
+        stream
+            .get("codec_type")
+            .and_then(|ct| ct.as_str())
+            .is_some_and(|ct| ct == "audio")
+    });
 
     let mut metadata = AudioMetadata {
         format: format_info
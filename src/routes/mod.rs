@@ -1,6 +1,7 @@
 pub mod cyberpunkpath;
 pub mod openapi;
 pub mod health;
+pub mod jobs;
 pub mod meta;
 pub mod params;
 pub mod root;
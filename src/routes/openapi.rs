@@ -2,18 +2,30 @@ use axum::{response::IntoResponse, Json};
 use serde_json::json;
 use utoipa::OpenApi;
 
-use crate::cyberpunkpath::params::Params;
+use crate::cyberpunkpath::{
+    params::Params,
+    validation::{FieldError, ParamsError},
+};
+use crate::jobs::jobs::{Job, JobFailure, JobResult, JobStatus, Progress, Stage};
+use crate::routes::jobs::{CreatedJob, JobRequest};
 
 #[derive(OpenApi)]
 #[openapi(
     paths(
         process_audio,
         preview_params,
-        get_health
+        get_health,
+        submit_job,
+        get_job_status,
+        cancel_job
     ),
-    components(schemas(Params)),
+    components(schemas(
+        Params, ParamsError, FieldError, JobRequest, CreatedJob, Job, JobStatus, Progress, Stage,
+        JobResult, JobFailure
+    )),
     tags(
-        (name = "audio", description = "Audio processing endpoints")
+        (name = "audio", description = "Audio processing endpoints"),
+        (name = "jobs", description = "Processing in the background")
     ),
     info(
         title = "Cyberpunk Audio Processing API",
@@ -52,9 +64,13 @@ pub struct ApiDoc;
     ),
     responses(
         (status = 200, description = "Processed audio file", content_type = "audio/*"),
-        (status = 400, description = "Invalid parameters"),
+        (status = 400, description = "Invalid parameters", body = ParamsError),
         (status = 404, description = "Audio file not found"),
-        (status = 500, description = "Processing error")
+        (status = 413, description = "Audio is longer than allowed"),
+        (status = 422, description = "Audio could not be decoded"),
+        (status = 429, description = "Too many requests waiting to be processed; see Retry-After"),
+        (status = 500, description = "Processing error"),
+        (status = 504, description = "Processing timed out")
     ),
     tag = "audio"
 )]
@@ -84,7 +100,7 @@ pub async fn process_audio() {
     ),
     responses(
         (status = 200, description = "Parameter preview", body = Params),
-        (status = 400, description = "Invalid parameters")
+        (status = 400, description = "Invalid parameters", body = ParamsError)
     ),
     tag = "audio"
 )]
@@ -105,6 +121,63 @@ pub async fn get_health() {
     // This is just for documentation - actual implementation is in health handler
 }
 
+/// Start processing audio in the background
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "Job started; see Location", body = CreatedJob),
+        (status = 400, description = "Invalid parameters", body = ParamsError),
+        (status = 401, description = "Malformed or expired signature"),
+        (status = 403, description = "Invalid signature"),
+        (status = 503, description = "Job store unavailable")
+    ),
+    tag = "jobs"
+)]
+pub async fn submit_job() {
+    // This is just for documentation - actual implementation is in create_job
+}
+
+/// Get a job's status, progress and result
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    params(
+        ("id" = String, Path, description = "Job ID"),
+        ("Authorization" = String, Header, description = "`Bearer` and the token the job was created with"),
+    ),
+    responses(
+        (status = 200, description = "The job", body = Job),
+        (status = 401, description = "Missing or invalid job token"),
+        (status = 404, description = "No such job")
+    ),
+    tag = "jobs"
+)]
+pub async fn get_job_status() {
+    // This is just for documentation - actual implementation is in get_job
+}
+
+/// Cancel a job
+#[utoipa::path(
+    delete,
+    path = "/jobs/{id}",
+    params(
+        ("id" = String, Path, description = "Job ID"),
+        ("Authorization" = String, Header, description = "`Bearer` and the token the job was created with"),
+    ),
+    responses(
+        (status = 200, description = "The cancelled job", body = Job),
+        (status = 401, description = "Missing or invalid job token"),
+        (status = 404, description = "No such job"),
+        (status = 409, description = "The job has already finished")
+    ),
+    tag = "jobs"
+)]
+pub async fn cancel_job() {
+    // This is just for documentation - actual implementation is in cancel_job
+}
+
 pub async fn openapi_json() -> impl IntoResponse {
     Json(ApiDoc::openapi())
 }
@@ -268,8 +341,20 @@ pub async fn get_openapi_schema() -> impl IntoResponse {
                         "404": {
                             "description": "Audio file not found"
                         },
+                        "413": {
+                            "description": "Audio is longer than allowed"
+                        },
+                        "422": {
+                            "description": "Audio could not be decoded"
+                        },
+                        "429": {
+                            "description": "Too many requests waiting to be processed; see Retry-After"
+                        },
                         "500": {
                             "description": "Processing error"
+                        },
+                        "504": {
+                            "description": "Processing timed out"
                         }
                     }
                 }
@@ -314,6 +399,90 @@ pub async fn get_openapi_schema() -> impl IntoResponse {
                         }
                     }
                 }
+            },
+            "/jobs": {
+                "post": {
+                    "summary": "Start a job",
+                    "description": "Process audio in the background. The body is either a path, e.g. {\"path\": \"/unsafe/song.mp3?format=ogg\"}, or params with an optional signature, e.g. {\"params\": {\"key\": \"song.mp3\", \"format\": \"ogg\"}}",
+                    "operationId": "submitJob",
+                    "responses": {
+                        "202": {
+                            "description": "Job started; its URL is in Location, and the token to access it in the body"
+                        },
+                        "400": {
+                            "description": "Invalid parameters"
+                        },
+                        "403": {
+                            "description": "Invalid signature"
+                        }
+                    }
+                }
+            },
+            "/jobs/{id}": {
+                "get": {
+                    "summary": "Get a job",
+                    "description": "A job's status, progress and, once it has succeeded, where its output is",
+                    "operationId": "getJob",
+                    "parameters": [
+                        {
+                            "name": "id",
+                            "in": "path",
+                            "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "Authorization",
+                            "in": "header",
+                            "required": true,
+                            "description": "Bearer and the token the job was created with",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The job"
+                        },
+                        "401": {
+                            "description": "Missing or invalid job token"
+                        },
+                        "404": {
+                            "description": "No such job"
+                        }
+                    }
+                },
+                "delete": {
+                    "summary": "Cancel a job",
+                    "operationId": "cancelJob",
+                    "parameters": [
+                        {
+                            "name": "id",
+                            "in": "path",
+                            "required": true,
+                            "schema": { "type": "string" }
+                        },
+                        {
+                            "name": "Authorization",
+                            "in": "header",
+                            "required": true,
+                            "description": "Bearer and the token the job was created with",
+                            "schema": { "type": "string" }
+                        }
+                    ],
+                    "responses": {
+                        "200": {
+                            "description": "The cancelled job"
+                        },
+                        "401": {
+                            "description": "Missing or invalid job token"
+                        },
+                        "404": {
+                            "description": "No such job"
+                        },
+                        "409": {
+                            "description": "The job has already finished"
+                        }
+                    }
+                }
             }
         }
     });
@@ -0,0 +1,201 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{blob::AudioBuffer, cache::redis::RedisCache, response::ErrorResponse};
+
+const LOCK_KEY_PREFIX: &str = "lock:";
+
+/// What the leader of a flight hands to its followers.
+pub type FlightResult = Result<AudioBuffer, ErrorResponse>;
+
+type Flights = Arc<Mutex<HashMap<String, watch::Receiver<Option<FlightResult>>>>>;
+
+/// Coalesces concurrent requests for the same result, so that only the first
+/// one does the work and the rest wait for what it produces.
+#[derive(Clone, Default)]
+pub struct SingleFlight {
+    flights: Flights,
+}
+
+pub enum Flight {
+    Leader(Leader),
+    Follower(Follower),
+}
+
+impl SingleFlight {
+    /// Leads the flight for `key` if there isn't one yet, or follows it.
+    pub fn join(&self, key: &str) -> Flight {
+        let mut flights = self.flights.lock().unwrap();
+        if let Some(rx) = flights.get(key) {
+            return Flight::Follower(Follower { rx: rx.clone() });
+        }
+
+        let (tx, rx) = watch::channel(None);
+        flights.insert(key.to_string(), rx);
+        Flight::Leader(Leader {
+            key: key.to_string(),
+            flights: self.flights.clone(),
+            tx,
+        })
+    }
+}
+
+/// Does the work for a flight. Dropping it without calling `finish`, e.g.
+/// because its client went away, lets the followers try again.
+pub struct Leader {
+    key: String,
+    flights: Flights,
+    tx: watch::Sender<Option<FlightResult>>,
+}
+
+impl Leader {
+    pub fn finish(self, result: FlightResult) {
+        self.tx.send_replace(Some(result));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.flights.lock().unwrap().remove(&self.key);
+    }
+}
+
+pub struct Follower {
+    rx: watch::Receiver<Option<FlightResult>>,
+}
+
+impl Follower {
+    /// Waits for the leader's result, or `None` if it gave up.
+    pub async fn wait(mut self) -> Option<FlightResult> {
+        let result = self.rx.wait_for(Option::is_some).await.ok()?;
+        result.clone()
+    }
+}
+
+/// A lock in Redis that lets one replica at a time process a result.
+#[derive(Debug, Clone)]
+pub struct RedisLock {
+    redis: RedisCache,
+    ttl: Duration,
+}
+
+impl RedisLock {
+    pub fn new(redis: RedisCache, ttl: Duration) -> Self {
+        Self { redis, ttl }
+    }
+
+    /// How long the lock is held at most, in case its holder dies.
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Takes the lock for `key` unless another replica holds it.
+    pub async fn try_acquire(&self, key: &str) -> color_eyre::Result<Option<LockGuard>> {
+        let key = format!("{}{}", LOCK_KEY_PREFIX, key);
+        let token = format!("{:016x}", rand::random::<u64>());
+        if !self.redis.try_lock(&key, &token, self.ttl).await? {
+            return Ok(None);
+        }
+
+        Ok(Some(LockGuard {
+            redis: self.redis.clone(),
+            key,
+            token,
+        }))
+    }
+}
+
+/// Releases the lock when dropped.
+pub struct LockGuard {
+    redis: RedisCache,
+    key: String,
+    token: String,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        let redis = self.redis.clone();
+        let key = std::mem::take(&mut self.key);
+        let token = std::mem::take(&mut self.token);
+        tokio::spawn(async move {
+            if let Err(e) = redis.unlock(&key, &token).await {
+                warn!("Failed to release lock [{}]: {}", key, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+
+    fn buffer() -> AudioBuffer {
+        AudioBuffer::from_bytes(b"audio".to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_followers_get_the_leaders_result() {
+        let flights = SingleFlight::default();
+
+        let Flight::Leader(leader) = flights.join("key") else {
+            panic!("first request should lead");
+        };
+        let followers: Vec<_> = (0..3)
+            .map(|_| match flights.join("key") {
+                Flight::Follower(follower) => tokio::spawn(follower.wait()),
+                Flight::Leader(_) => panic!("later requests should follow"),
+            })
+            .collect();
+        assert!(matches!(flights.join("other"), Flight::Leader(_)));
+
+        leader.finish(Ok(buffer()));
+        for follower in followers {
+            let result = follower.await.unwrap().unwrap().unwrap();
+            assert_eq!(result.as_ref(), b"audio");
+        }
+
+        // Once finished, the next request leads a new flight
+        assert!(matches!(flights.join("key"), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_followers_get_the_leaders_error() {
+        let flights = SingleFlight::default();
+
+        let Flight::Leader(leader) = flights.join("key") else {
+            panic!("first request should lead");
+        };
+        let Flight::Follower(follower) = flights.join("key") else {
+            panic!("second request should follow");
+        };
+
+        leader.finish(Err((StatusCode::NOT_FOUND, "missing".to_string()).into()));
+        assert_eq!(
+            follower.wait().await.unwrap().unwrap_err(),
+            ErrorResponse::Status(StatusCode::NOT_FOUND, "missing".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_abandoned_flight() {
+        let flights = SingleFlight::default();
+
+        let Flight::Leader(leader) = flights.join("key") else {
+            panic!("first request should lead");
+        };
+        let Flight::Follower(follower) = flights.join("key") else {
+            panic!("second request should follow");
+        };
+
+        drop(leader);
+        assert!(follower.wait().await.is_none());
+        assert!(matches!(flights.join("key"), Flight::Leader(_)));
+    }
+}
@@ -1,15 +1,29 @@
 use crate::cache::cache::AudioCache;
 use crate::cache::cache::Cache;
-use crate::config::{Settings, StorageClient};
+use crate::cache::fs::CacheLimits;
+use crate::cache::metadata::MetadataCache;
+use crate::cache::redis::RedisCache;
+use crate::config::{
+    JobSettings, LoaderSettings, MetadataCacheSettings, Settings, StorageClient, StorageSettings,
+};
+use crate::cyberpunkpath::hasher::ResultKeyer;
+use crate::cyberpunkpath::signer::KeyRing;
+use crate::jobs::jobs::Jobs;
+use crate::jobs::memory::MemoryJobStore;
+use crate::jobs::redis::RedisJobStore;
+use crate::jobs::store::JobStore;
+use crate::loader::loader::LoaderChain;
 use crate::metrics::{setup_metrics_recorder, track_metrics};
 use crate::middleware::auth_middleware;
 use crate::middleware::cache_middleware;
 use crate::processor::processor::{AudioProcessor, Processor};
 use crate::routes::cyberpunkpath::cyberpunkpath_handler;
 use crate::routes::health::health_check;
+use crate::routes::jobs::{cancel_job, create_job, get_job};
 use crate::routes::meta::meta_handler;
 use crate::routes::params::params;
 use crate::routes::root::root_handler;
+use crate::singleflight::{RedisLock, SingleFlight};
 use crate::state::AppStateDyn;
 use crate::storage::file::FileStorage;
 use crate::storage::gcs::GCloudStorage;
@@ -17,15 +31,17 @@ use crate::storage::s3::S3Storage;
 use crate::storage::storage::AudioStorage;
 use crate::tags::create_tags;
 use axum::extract::{MatchedPath, Request};
+use axum::http::HeaderValue;
 use axum::middleware;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{serve::Serve, Router};
-use color_eyre::eyre::WrapErr;
+use color_eyre::eyre::{eyre, WrapErr};
 use color_eyre::Result;
 use secrecy::ExposeSecret;
 use std::future::ready;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
 use tracing::{debug, info, info_span};
@@ -46,53 +62,66 @@ impl Application {
 
         let additional_tags = create_tags(config.custom_tags)?;
 
-        let processor = Processor::new(config.processor, additional_tags);
-        let cache = Cache::new(config.cache)?;
-
-        let server = match config.storage.client {
-            Some(StorageClient::S3(s3_settings)) => {
-                info!("Using S3 storage");
-                let storage = S3Storage::new(
-                    config.storage.base_dir,
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                    s3_settings.endpoint,
-                    s3_settings.region,
-                    s3_settings.bucket,
-                    s3_settings.access_key.expose_secret(),
-                    s3_settings.secret_key.expose_secret(),
-                )
-                .await?;
+        let cache_limits = CacheLimits::from(&config.processor);
+        let cache_ttl = match config.processor.cache_ttl {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let sweep_interval = config.cache.sweep_interval();
 
-                // Ensure bucket exists
-                storage.ensure_bucket_exists().await?;
+        let lock = config
+            .coalescing
+            .redis_uri
+            .as_deref()
+            .map(|uri| {
+                let ttl = Duration::from_secs(config.coalescing.lock_ttl);
+                RedisCache::new(uri).map(|redis| RedisLock::new(redis, ttl))
+            })
+            .transpose()?;
 
-                run(listener, storage, processor, cache).await?
-            }
-            Some(StorageClient::GCS(gcs_settings)) => {
-                info!("using GCS storage");
-                let storage = GCloudStorage::new(
-                    config.storage.base_dir,
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                    gcs_settings.bucket,
-                )
-                .await;
+        let jobs = build_jobs(config.jobs)?;
 
-                run(listener, storage, processor, cache).await?
-            }
-            None => {
-                info!("using filesystem storage");
-                let storage = FileStorage::new(
-                    PathBuf::from(config.storage.base_dir),
-                    config.storage.path_prefix,
-                    config.storage.safe_chars,
-                );
-
-                run(listener, storage, processor, cache).await?
+        let processor = Processor::new(config.processor, additional_tags);
+        let cache = Cache::new(config.cache, cache_limits)?;
+        cache.spawn_sweeper(sweep_interval);
+        let metadata_cache = config.metadata_cache;
+        let signer = KeyRing::new(&config.application);
+        let allow_unsafe = config.application.allow_unsafe;
+        let cache_control = HeaderValue::from_str(&config.application.cache_control)
+            .map_err(|e| eyre!("invalid cache_control: {}", e))?;
+        let loader = config.loader;
+        let result_keyer = config.result_key;
+        result_keyer
+            .validate()
+            .map_err(|e| eyre!("invalid result_key: {}", e))?;
+
+        let storage = build_storage(config.storage).await?;
+        let result_storage = match config.result_storage {
+            Some(result_storage) => {
+                info!("using separate result storage");
+                build_storage(result_storage).await?
             }
+            None => storage.clone(),
         };
 
+        let server = run(
+            listener,
+            storage,
+            result_storage,
+            result_keyer,
+            processor,
+            cache,
+            cache_ttl,
+            metadata_cache,
+            signer,
+            lock,
+            allow_unsafe,
+            cache_control,
+            loader,
+            jobs,
+        )
+        .await?;
+
         Ok(Self { port, server })
     }
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
@@ -110,23 +139,107 @@ impl Application {
     }
 }
 
-async fn run<S, P, C>(
+async fn build_storage(config: StorageSettings) -> Result<Arc<dyn AudioStorage>> {
+    let storage: Arc<dyn AudioStorage> = match config.client {
+        Some(StorageClient::S3(s3_settings)) => {
+            info!("Using S3 storage");
+            let storage = S3Storage::new(
+                config.base_dir,
+                config.path_prefix,
+                config.safe_chars,
+                s3_settings.endpoint,
+                s3_settings.bucket,
+                s3_settings.region,
+                s3_settings.access_key.expose_secret(),
+                s3_settings.secret_key.expose_secret(),
+            )
+            .await?;
+
+            // Ensure bucket exists
+            storage.ensure_bucket_exists().await?;
+
+            Arc::new(storage)
+        }
+        Some(StorageClient::GCS(gcs_settings)) => {
+            info!("using GCS storage");
+            let storage = GCloudStorage::new(
+                config.base_dir,
+                config.path_prefix,
+                config.safe_chars,
+                gcs_settings.bucket,
+            )
+            .await;
+
+            Arc::new(storage)
+        }
+        None => {
+            info!("using filesystem storage");
+            Arc::new(FileStorage::new(
+                PathBuf::from(config.base_dir),
+                config.path_prefix,
+                config.safe_chars,
+            ))
+        }
+    };
+
+    Ok(storage)
+}
+
+fn build_jobs(config: JobSettings) -> Result<Jobs> {
+    let ttl = Duration::from_secs(config.ttl);
+    let store: Arc<dyn JobStore> = match config.redis_uri {
+        Some(uri) => {
+            info!("keeping jobs in Redis");
+            Arc::new(RedisJobStore::new(RedisCache::new(&uri)?, ttl))
+        }
+        None => Arc::new(MemoryJobStore::new(ttl)),
+    };
+
+    Ok(Jobs::new(store))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run<P, C>(
     listener: TcpListener,
-    storage: S,
+    storage: Arc<dyn AudioStorage>,
+    result_storage: Arc<dyn AudioStorage>,
+    result_keyer: ResultKeyer,
     processor: P,
     cache: C,
+    cache_ttl: Option<Duration>,
+    metadata_cache: MetadataCacheSettings,
+    signer: KeyRing,
+    lock: Option<RedisLock>,
+    allow_unsafe: bool,
+    cache_control: HeaderValue,
+    loader: LoaderSettings,
+    jobs: Jobs,
 ) -> Result<Serve<Router, Router>>
 where
-    S: AudioStorage + Clone + Send + Sync + 'static,
     P: AudioProcessor + Send + Sync + 'static,
     C: AudioCache + Clone + Send + Sync + 'static,
 {
     let recorder_handle = setup_metrics_recorder();
 
+    let cache: Arc<dyn AudioCache> = Arc::new(cache);
+    let metadata_cache = MetadataCache::new(cache.clone(), Duration::from_secs(metadata_cache.ttl));
+    let loader = LoaderChain::from_config(&loader, storage.clone(), cache.clone()).await?;
+
     let state = AppStateDyn {
-        storage: Arc::new(storage.clone()),
+        storage,
+        result_storage,
+        result_keyer,
         processor: Arc::new(processor),
-        cache: Arc::new(cache.clone()),
+        cache,
+        cache_ttl,
+        metadata_cache,
+        signer: Arc::new(signer),
+        allow_unsafe,
+        cache_control,
+        loader: Arc::new(loader),
+        flights: SingleFlight::default(),
+        lock,
+        jobs,
     };
 
     let app = Router::new()
@@ -136,19 +249,22 @@ where
         .route("/api-schema", get(crate::routes::openapi::get_openapi_schema))
         .route("/", get(root_handler))
         .route("/params/*cyberpunkpath", get(params))
+        .route("/jobs", post(create_job))
+        .route("/jobs/:id", get(get_job).delete(cancel_job))
         .route_layer(middleware::from_fn(track_metrics))
         .nest(
             "/",
             Router::new()
                 .route("/meta/*cyberpunkpath", get(meta_handler))
                 .route("/*cyberpunkpath", get(cyberpunkpath_handler))
+                // The last layer added runs first, so auth is checked before the cache
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
-                    auth_middleware,
+                    cache_middleware,
                 ))
                 .route_layer(middleware::from_fn_with_state(
                     state.clone(),
-                    cache_middleware,
+                    auth_middleware,
                 )),
         )
         .layer(
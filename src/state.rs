@@ -1,11 +1,33 @@
 use crate::{
-    cache::cache::AudioCache, processor::processor::AudioProcessor, storage::storage::AudioStorage,
+    cache::{cache::AudioCache, metadata::MetadataCache},
+    cyberpunkpath::{hasher::ResultKeyer, signer::KeyRing},
+    jobs::jobs::Jobs,
+    loader::loader::AudioLoader,
+    processor::processor::AudioProcessor,
+    singleflight::{RedisLock, SingleFlight},
+    storage::storage::AudioStorage,
 };
-use std::sync::Arc;
+use axum::http::HeaderValue;
+use std::{sync::Arc, time::Duration};
 
 #[derive(Clone)]
 pub struct AppStateDyn {
     pub storage: Arc<dyn AudioStorage>,
+    /// Processed audio, kept apart from the originals in `storage`
+    pub result_storage: Arc<dyn AudioStorage>,
+    pub result_keyer: ResultKeyer,
     pub processor: Arc<dyn AudioProcessor>,
     pub cache: Arc<dyn AudioCache>,
+    /// How long processed audio is cached; `None` keeps it until evicted
+    pub cache_ttl: Option<Duration>,
+    pub metadata_cache: MetadataCache,
+    pub signer: Arc<KeyRing>,
+    pub loader: Arc<dyn AudioLoader>,
+    pub flights: SingleFlight,
+    /// Coalesces processing across replicas when set
+    pub lock: Option<RedisLock>,
+    pub allow_unsafe: bool,
+    /// `Cache-Control` for audio responses
+    pub cache_control: HeaderValue,
+    pub jobs: Jobs,
 }
@@ -4,7 +4,8 @@ use crate::storage::storage::AudioStorage;
 use axum::async_trait;
 use color_eyre::Result;
 use std::fs;
-use std::path::{Path, PathBuf};
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::debug;
@@ -20,7 +21,7 @@ pub struct FileStorage {
 impl AudioStorage for FileStorage {
     #[tracing::instrument(skip(self))]
     async fn get(&self, key: &str) -> Result<AudioBuffer> {
-        let full_path = self.get_full_path(key);
+        let full_path = self.get_full_path(key)?;
         if let Some(p) = full_path.to_str() {
             debug!("full path {}", p);
         }
@@ -33,7 +34,7 @@ impl AudioStorage for FileStorage {
 
     #[tracing::instrument(skip(self, blob))]
     async fn put(&self, key: &str, blob: &AudioBuffer) -> Result<()> {
-        let full_path = self.get_full_path(key);
+        let full_path = self.get_full_path(key)?;
         if let Some(p) = full_path.to_str() {
             debug!("full path {}", p);
         }
@@ -53,7 +54,7 @@ impl AudioStorage for FileStorage {
 
     #[tracing::instrument(skip(self))]
     async fn delete(&self, key: &str) -> Result<()> {
-        let full_path = self.get_full_path(key);
+        let full_path = self.get_full_path(key)?;
         if let Some(p) = full_path.to_str() {
             debug!("full path {}", p);
         }
@@ -72,11 +73,25 @@ impl FileStorage {
         }
     }
 
-    pub fn get_full_path(&self, key: &str) -> PathBuf {
+    /// Where `key` is stored. Keys that would leave the base dir, like
+    /// `../secret.mp3`, are refused with a permission denied error.
+    pub fn get_full_path(&self, key: &str) -> Result<PathBuf> {
         let safe_key = normalize(key, &self.safe_chars);
-        self.base_dir
+        let contained = Path::new(&safe_key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+        if !contained {
+            return Err(io::Error::new(
+                ErrorKind::PermissionDenied,
+                format!("key escapes the storage base dir: {}", key),
+            )
+            .into());
+        }
+
+        Ok(self
+            .base_dir
             .join(Path::new(&self.path_prefix))
-            .join(safe_key)
+            .join(safe_key))
     }
 }
 #[cfg(test)]
@@ -117,7 +132,7 @@ mod tests {
         );
 
         let key = "test/my-audio.mp3";
-        let full_path = storage.get_full_path(key);
+        let full_path = storage.get_full_path(key).unwrap();
 
         assert_eq!(
             full_path,
@@ -136,7 +151,7 @@ mod tests {
         );
 
         let key = "test/my audio!@#.mp3";
-        let full_path = storage.get_full_path(key);
+        let full_path = storage.get_full_path(key).unwrap();
 
         // Special characters should be normalized
         assert_ne!(
@@ -185,7 +200,7 @@ mod tests {
         storage.put(key, &audio_buffer).await?;
 
         // Verify file exists
-        let full_path = storage.get_full_path(key);
+        let full_path = storage.get_full_path(key).unwrap();
         assert!(tokio_fs::try_exists(&full_path).await?);
 
         // Delete the file
@@ -223,6 +238,23 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_keys_may_not_leave_base_dir() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let base_dir = temp_dir.path().join("storage");
+        let storage = FileStorage::new(base_dir, "audio_files".to_string(), SafeCharsType::Noop);
+        tokio_fs::write(temp_dir.path().join("secret.mp3"), b"secret").await?;
+
+        for key in ["../../secret.mp3", "test/../../../secret.mp3"] {
+            let err = storage.get(key).await.unwrap_err();
+            let io = err.downcast_ref::<io::Error>().unwrap();
+            assert_eq!(io.kind(), ErrorKind::PermissionDenied);
+            assert!(storage.get_full_path(key).is_err());
+        }
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_put_with_nested_directories() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -243,7 +275,7 @@ mod tests {
         assert!(tokio_fs::try_exists(&expected_dir).await?);
 
         // Verify file exists
-        let full_path = storage.get_full_path(key);
+        let full_path = storage.get_full_path(key).unwrap();
         assert!(tokio_fs::try_exists(&full_path).await?);
 
         Ok(())
@@ -268,8 +300,8 @@ mod tests {
         let key_with_spaces = "test/my audio file.mp3";
 
         // Get paths with different normalization
-        let strict_path = strict_storage.get_full_path(key_with_spaces);
-        let relaxed_path = relaxed_storage.get_full_path(key_with_spaces);
+        let strict_path = strict_storage.get_full_path(key_with_spaces)?;
+        let relaxed_path = relaxed_storage.get_full_path(key_with_spaces)?;
 
         // Paths should be different due to different normalization
         assert_ne!(strict_path, relaxed_path);
@@ -1,4 +1,5 @@
 pub mod file;
 pub mod gcs;
 pub mod s3;
+#[allow(clippy::module_inception)]
 pub mod storage;
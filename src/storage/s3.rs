@@ -71,6 +71,7 @@ impl AudioStorage for S3Storage {
 
 impl S3Storage {
     #[tracing::instrument]
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         base_dir: String,
         path_prefix: String,
@@ -0,0 +1,236 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream::BoxStream, Stream, StreamExt};
+use tokio::sync::watch;
+
+use crate::{
+    blob::{AudioBuffer, AudioFormat},
+    processor::error::ProcessingError,
+};
+
+/// Processed audio, produced as it is encoded.
+pub struct AudioStream {
+    pub format: AudioFormat,
+    pub body: BoxStream<'static, io::Result<Bytes>>,
+    /// Share of the output produced so far, from 0 to 1, if it is being
+    /// reported
+    pub progress: Option<watch::Receiver<f64>>,
+}
+
+impl AudioStream {
+    /// Waits for the whole output.
+    pub async fn into_buffer(mut self) -> io::Result<AudioBuffer> {
+        let mut data = BytesMut::new();
+        while let Some(chunk) = self.body.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        Ok(AudioBuffer::from_bytes_with_format(
+            data.freeze(),
+            self.format,
+        ))
+    }
+
+    /// Fails the output once it grows past `max` bytes. Whatever consumes it
+    /// stops there, dropping the processing that produces it.
+    pub fn limit(self, max: u64) -> Self {
+        let body = self.body.scan(Some(0u64), move |produced, chunk| {
+            let item = match (produced.as_mut(), chunk) {
+                (None, _) => return future::ready(None),
+                (Some(produced), Ok(chunk)) if *produced + chunk.len() as u64 <= max => {
+                    *produced += chunk.len() as u64;
+                    Ok(chunk)
+                }
+                (Some(_), Ok(_)) => {
+                    *produced = None;
+                    Err(ProcessingError::OutputTooLarge { max }.into())
+                }
+                (Some(_), Err(e)) => Err(e),
+            };
+            future::ready(Some(item))
+        });
+        Self {
+            body: body.boxed(),
+            ..self
+        }
+    }
+}
+
+/// Passes a body through while keeping a copy of it. `on_complete` gets the
+/// copy once the body has ended; if it fails or is dropped early, e.g.
+/// because the client went away, the copy is discarded instead.
+pub struct Tee<S, F> {
+    inner: S,
+    buffer: BytesMut,
+    on_complete: Option<F>,
+    length: Option<usize>,
+}
+
+pub fn tee<S, F: FnOnce(Bytes)>(inner: S, on_complete: F) -> Tee<S, F> {
+    Tee {
+        inner,
+        buffer: BytesMut::new(),
+        on_complete: Some(on_complete),
+        length: None,
+    }
+}
+
+impl<S, F: FnOnce(Bytes)> Tee<S, F> {
+    /// Treats the body as complete once `length` bytes have passed. hyper
+    /// stops polling a body with a `Content-Length` as soon as it has sent
+    /// that much, so the end of the stream is never seen.
+    pub fn with_length(mut self, length: Option<usize>) -> Self {
+        self.length = length;
+        self
+    }
+
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(std::mem::take(&mut self.buffer).freeze());
+        }
+    }
+}
+
+// `on_complete` is never pinned
+impl<S: Unpin, F> Unpin for Tee<S, F> {}
+
+impl<S, E, F> Stream for Tee<S, F>
+where
+    S: Stream<Item = Result<Bytes, E>> + Unpin,
+    F: FnOnce(Bytes),
+{
+    type Item = Result<Bytes, E>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = ready!(this.inner.poll_next_unpin(cx));
+        match &item {
+            Some(Ok(chunk)) => {
+                if this.on_complete.is_some() {
+                    this.buffer.extend_from_slice(chunk);
+                    if this
+                        .length
+                        .is_some_and(|length| this.buffer.len() >= length)
+                    {
+                        this.complete();
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                this.on_complete = None;
+                this.buffer = BytesMut::new();
+            }
+            None => this.complete(),
+        }
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use std::sync::{Arc, Mutex};
+
+    type Chunks = BoxStream<'static, Result<Bytes, &'static str>>;
+    type Copy = Arc<Mutex<Option<Bytes>>>;
+
+    fn teed(
+        items: Vec<Result<&'static str, &'static str>>,
+    ) -> (Tee<Chunks, impl FnOnce(Bytes)>, Copy) {
+        let chunks = stream::iter(items)
+            .map(|item| item.map(|s| Bytes::from_static(s.as_bytes())))
+            .boxed();
+
+        let copy = Arc::new(Mutex::new(None));
+        let sink = copy.clone();
+        let tee = tee(chunks, move |bytes| {
+            *sink.lock().unwrap() = Some(bytes);
+        });
+        (tee, copy)
+    }
+
+    #[tokio::test]
+    async fn test_tee_copies_complete_body() {
+        let (tee, copy) = teed(vec![Ok("ab"), Ok("cd")]);
+
+        let passed: Vec<_> = tee.collect().await;
+        assert_eq!(passed.len(), 2);
+        assert_eq!(copy.lock().unwrap().as_deref(), Some(&b"abcd"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_tee_discards_failed_body() {
+        let (tee, copy) = teed(vec![Ok("ab"), Err("broken"), Ok("cd")]);
+
+        let passed: Vec<_> = tee.collect().await;
+        assert_eq!(passed[1], Err("broken"));
+        assert!(copy.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tee_discards_dropped_body() {
+        let (mut tee, copy) = teed(vec![Ok("ab"), Ok("cd")]);
+
+        assert!(tee.next().await.is_some());
+        drop(tee);
+        assert!(copy.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tee_with_length() {
+        let (tee, copy) = teed(vec![Ok("ab"), Ok("cd"), Ok("ef")]);
+        let mut tee = tee.with_length(Some(4));
+
+        assert!(tee.next().await.is_some());
+        assert!(copy.lock().unwrap().is_none());
+        assert!(tee.next().await.is_some());
+        assert_eq!(copy.lock().unwrap().as_deref(), Some(&b"abcd"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_into_buffer() {
+        let stream = AudioStream {
+            format: AudioFormat::Ogg,
+            body: stream::iter(vec![
+                Ok(Bytes::from_static(b"Og")),
+                Ok(Bytes::from_static(b"gS")),
+            ])
+            .boxed(),
+            progress: None,
+        };
+
+        let buffer = stream.into_buffer().await.unwrap();
+        assert_eq!(buffer.as_ref(), b"OggS");
+        assert_eq!(buffer.format(), AudioFormat::Ogg);
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let stream = |chunks: Vec<&'static str>| AudioStream {
+            format: AudioFormat::Ogg,
+            body: stream::iter(chunks)
+                .map(|s| Ok(Bytes::from_static(s.as_bytes())))
+                .boxed(),
+            progress: None,
+        };
+
+        let buffer = stream(vec!["ab", "cd"]).limit(4).into_buffer().await;
+        assert_eq!(buffer.unwrap().as_ref(), b"abcd");
+
+        let chunks: Vec<_> = stream(vec!["ab", "cd", "ef", "gh"])
+            .limit(5)
+            .body
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            ProcessingError::from_io(chunks[2].as_ref().unwrap_err()),
+            ProcessingError::OutputTooLarge { max: 5 }
+        );
+    }
+}
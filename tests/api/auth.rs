@@ -0,0 +1,180 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use cyberpunk::config::HmacKeySettings;
+use cyberpunk::cyberpunkpath::signer::{HmacSigner, Signer, SignerType};
+use secrecy::SecretString;
+
+use crate::helpers::{spawn_app, spawn_app_with, TestApp};
+
+const SECRET: &str = "test-secret";
+
+async fn spawn_signing_app(allow_unsafe: bool) -> TestApp {
+    spawn_app_with(|c| {
+        c.application.hmac_secret = SecretString::from(SECRET.to_string());
+        c.application.signer_type = SignerType::Sha256;
+        c.application.allow_unsafe = allow_unsafe;
+    })
+    .await
+}
+
+fn signer() -> HmacSigner {
+    HmacSigner::new(
+        SignerType::Sha256,
+        SecretString::from(SECRET.to_string()),
+        0,
+    )
+}
+
+async fn get(app: &TestApp, path: &str) -> u16 {
+    get_response(app, path).await.status().as_u16()
+}
+
+async fn get_response(app: &TestApp, path: &str) -> reqwest::Response {
+    app.api_client
+        .get(format!("{}/{}", &app.address, path))
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[tokio::test]
+async fn signed_url_passes_auth() {
+    let app = spawn_signing_app(false).await;
+    let path = "trim:0:5/missing.mp3?format=ogg";
+
+    // The audio does not exist, so getting past auth means a 404
+    assert_eq!(
+        404,
+        get(&app, &format!("{}/{}", signer().sign(path), path)).await
+    );
+}
+
+#[tokio::test]
+async fn canonical_signature_accepts_equivalent_urls() {
+    let app = spawn_signing_app(false).await;
+    let signature = signer().sign("missing.mp3?format=ogg&volume=0.5");
+
+    assert_eq!(
+        404,
+        get(
+            &app,
+            &format!("{}/missing.mp3?volume=.5&format=ogg", signature)
+        )
+        .await
+    );
+    assert_eq!(
+        404,
+        get(
+            &app,
+            &format!("{}/format:ogg/missing.mp3?volume=0.5", signature)
+        )
+        .await
+    );
+}
+
+#[tokio::test]
+async fn invalid_signature_is_rejected() {
+    let app = spawn_signing_app(true).await;
+    let signature = signer().sign("missing.mp3?format=ogg");
+
+    let response = get_response(&app, &format!("{}/missing.mp3?format=wav", signature)).await;
+    assert_eq!(403, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_signature");
+
+    assert_eq!(403, get(&app, "not-a-signature/missing.mp3").await);
+}
+
+#[tokio::test]
+async fn malformed_signature_is_unauthorized() {
+    let app = spawn_signing_app(true).await;
+
+    let response = get_response(&app, "not+base64/missing.mp3").await;
+    assert_eq!(401, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "malformed_signature");
+
+    let path = "missing.mp3?expires=soon";
+    assert_eq!(
+        401,
+        get(&app, &format!("{}/{}", signer().sign(path), path)).await
+    );
+}
+
+#[tokio::test]
+async fn signed_url_expires() {
+    let app = spawn_signing_app(false).await;
+
+    let path = format!("missing.mp3?expires={}", now() + 3600);
+    assert_eq!(
+        404,
+        get(&app, &format!("{}/{}", signer().sign(&path), path)).await
+    );
+
+    // The canonical form covers the expiry too
+    let expires = now() + 3600;
+    let signature = signer().sign(&format!("missing.mp3?format=ogg&expires={}", expires));
+    assert_eq!(
+        404,
+        get(
+            &app,
+            &format!("{}/missing.mp3?expires={}&format=ogg", signature, expires)
+        )
+        .await
+    );
+
+    // Extending the expiry invalidates the signature
+    assert_eq!(
+        403,
+        get(
+            &app,
+            &format!(
+                "{}/missing.mp3?expires={}&format=ogg",
+                signature,
+                expires + 1
+            )
+        )
+        .await
+    );
+
+    let path = format!("missing.mp3?expires={}", now() - 1);
+    let response = get_response(&app, &format!("{}/{}", signer().sign(&path), path)).await;
+    assert_eq!(401, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "signature_expired");
+}
+
+#[tokio::test]
+async fn rotated_keys_keep_old_links_working() {
+    let key = |id: &str, secret: &str| HmacKeySettings {
+        id: id.to_string(),
+        secret: SecretString::from(secret.to_string()),
+    };
+    let app = spawn_app_with(|c| {
+        c.application.hmac_keys = vec![key("k2", "new-secret"), key("k1", SECRET)];
+        c.application.signer_type = SignerType::Sha256;
+    })
+    .await;
+
+    let path = "missing.mp3";
+    let old = signer().sign(path);
+    assert_eq!(404, get(&app, &format!("{}/{}", old, path)).await);
+    assert_eq!(404, get(&app, &format!("k1.{}/{}", old, path)).await);
+    assert_eq!(403, get(&app, &format!("k2.{}/{}", old, path)).await);
+}
+
+#[tokio::test]
+async fn unsafe_urls_can_be_disabled() {
+    let app = spawn_signing_app(false).await;
+    assert_eq!(403, get(&app, "unsafe/missing.mp3").await);
+
+    let app = spawn_app().await;
+    assert_eq!(404, get(&app, "unsafe/missing.mp3").await);
+}
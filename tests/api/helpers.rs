@@ -1,9 +1,13 @@
 use cyberpunk::{
-    config::get_configuration,
+    config::{
+        get_configuration, CacheSettings, FilesystemCacheSettings, Settings, StorageSettings,
+    },
+    cyberpunkpath::{hasher::ResultKeyer, params::Params},
     startup::Application,
     telemetry::{get_subscriber, init_subscriber},
 };
 use once_cell::sync::Lazy;
+use tempfile::TempDir;
 
 static TRACING: Lazy<()> = Lazy::new(|| {
     let default_filter_level = "info".to_string();
@@ -25,11 +29,16 @@ pub struct TestApp {
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn_app_with(|_| {}).await
+}
+
+pub async fn spawn_app_with(configure: impl FnOnce(&mut Settings)) -> TestApp {
     Lazy::force(&TRACING);
 
     let configuration = {
         let mut c = get_configuration().expect("Failed to read configuration");
         c.port = 0;
+        configure(&mut c);
 
         c
     };
@@ -41,18 +50,40 @@ pub async fn spawn_app() -> TestApp {
     let application_port = application.port;
     let address = format!("http://localhost:{}", application_port);
 
-    let _ = tokio::spawn(application.run_until_stopped());
+    tokio::spawn(application.run_until_stopped());
 
     let client = reqwest::Client::builder()
         .redirect(reqwest::redirect::Policy::none())
         .build()
         .unwrap();
 
-    let test_app = TestApp {
+    TestApp {
         address,
         port: application_port,
         api_client: client,
-    };
+    }
+}
+
+/// An app with `song.mp3?format=ogg` already processed to `0123456789`,
+/// storing audio in the first dir and caching in the second.
+pub async fn spawn_app_with_result(dirs: &[TempDir; 2]) -> TestApp {
+    let [storage_dir, cache_dir] = dirs;
+
+    let params: Params = "song.mp3?format=ogg".parse().unwrap();
+    let result_path = storage_dir.path().join(ResultKeyer::default().key(&params));
+    std::fs::create_dir_all(result_path.parent().unwrap()).unwrap();
+    std::fs::write(&result_path, b"0123456789").unwrap();
 
-    test_app
+    spawn_app_with(|c| {
+        c.storage = StorageSettings {
+            base_dir: storage_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        c.cache = CacheSettings::Filesystem(FilesystemCacheSettings {
+            base_dir: cache_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        });
+        c.application.cache_control = "public, max-age=3600".to_string();
+    })
+    .await
 }
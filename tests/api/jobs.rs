@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use cyberpunk::cyberpunkpath::hasher::ResultKeyer;
+use cyberpunk::cyberpunkpath::params::Params;
+use cyberpunk::cyberpunkpath::signer::{HmacSigner, Signer, SignerType};
+use secrecy::SecretString;
+use serde_json::{json, Value};
+use tempfile::TempDir;
+use wiremock::{matchers::method, Mock, MockServer, ResponseTemplate};
+
+use crate::helpers::{spawn_app_with, spawn_app_with_result, TestApp};
+
+async fn submit(app: &TestApp, body: Value) -> reqwest::Response {
+    app.api_client
+        .post(format!("{}/jobs", &app.address))
+        .json(&body)
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn get_job(app: &TestApp, job: &Value) -> reqwest::Response {
+    app.api_client
+        .get(format!(
+            "{}/jobs/{}",
+            &app.address,
+            job["id"].as_str().unwrap()
+        ))
+        .bearer_auth(job["token"].as_str().unwrap_or_default())
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn cancel(app: &TestApp, job: &Value) -> reqwest::Response {
+    app.api_client
+        .delete(format!(
+            "{}/jobs/{}",
+            &app.address,
+            job["id"].as_str().unwrap()
+        ))
+        .bearer_auth(job["token"].as_str().unwrap_or_default())
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+/// Polls the job, as returned when it was created, until it has finished.
+async fn finished(app: &TestApp, created: &Value) -> Value {
+    for _ in 0..50 {
+        let job: Value = get_job(app, created).await.json().await.unwrap();
+        if !matches!(job["status"].as_str(), Some("queued" | "running")) {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("job {} didn't finish", created["id"]);
+}
+
+#[tokio::test]
+async fn jobs_store_their_output_in_result_storage() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+    let key = ResultKeyer::Suffix.key(&"song.mp3?format=ogg".parse::<Params>().unwrap());
+
+    let response = submit(&app, json!({ "path": "/unsafe/song.mp3?format=ogg" })).await;
+    assert_eq!(202, response.status().as_u16());
+    let location = response.headers()["location"].to_str().unwrap().to_string();
+    let job: Value = response.json().await.unwrap();
+    let id = job["id"].as_str().unwrap();
+    assert_eq!(location, format!("/jobs/{}", id));
+    assert_eq!(job["status"], "queued");
+    assert_eq!(job["params"], "song.mp3?format=ogg");
+    assert!(job["token"].is_string());
+
+    let job = finished(&app, &job).await;
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["result"]["key"], key.as_str());
+    assert_eq!(job["result"]["location"], "/unsafe/song.mp3?format=ogg");
+    assert!(job.get("progress").unwrap().get("stage").is_none());
+    assert!(job.get("token").is_none());
+
+    // Params work as well as paths
+    let response = submit(
+        &app,
+        json!({ "params": { "key": "song.mp3", "format": "ogg" } }),
+    )
+    .await;
+    assert_eq!(202, response.status().as_u16());
+    let job: Value = response.json().await.unwrap();
+    let job = finished(&app, &job).await;
+    assert_eq!(job["status"], "succeeded");
+    assert_eq!(job["result"]["location"], "/unsafe/song.mp3?format=ogg");
+}
+
+#[tokio::test]
+async fn jobs_are_signed_like_audio_urls() {
+    let secret = "test-secret";
+    let app = spawn_app_with(|c| {
+        c.application.hmac_secret = SecretString::from(secret.to_string());
+        c.application.allow_unsafe = false;
+    })
+    .await;
+
+    let response = submit(&app, json!({ "path": "/unsafe/song.mp3" })).await;
+    assert_eq!(403, response.status().as_u16());
+    let response = submit(&app, json!({ "path": "/bad-signature/song.mp3" })).await;
+    assert_eq!(403, response.status().as_u16());
+    let response = submit(&app, json!({ "path": "/unsafe/song.mp3?volume=loud" })).await;
+    assert_eq!(400, response.status().as_u16());
+
+    let signer = HmacSigner::new(SignerType::Sha1, SecretString::from(secret.to_string()), 0);
+    let signature = signer.sign("song.mp3?format=ogg");
+    let response = submit(
+        &app,
+        json!({ "params": { "key": "song.mp3", "format": "ogg" }, "signature": signature }),
+    )
+    .await;
+    assert_eq!(202, response.status().as_u16());
+    let job: Value = response.json().await.unwrap();
+
+    // The source doesn't exist
+    let job = finished(&app, &job).await;
+    assert_eq!(job["status"], "failed");
+    assert_eq!(job["error"]["error"], "not_found");
+}
+
+#[tokio::test]
+async fn jobs_can_be_cancelled() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_bytes(vec![0u8; 16])
+                .set_delay(Duration::from_secs(30)),
+        )
+        .mount(&server)
+        .await;
+    let app = spawn_app_with(|c| c.loader.allow_private_addresses = true).await;
+
+    let source = format!("{}/song.mp3", server.uri());
+    let response = submit(&app, json!({ "params": { "key": source } })).await;
+    let created: Value = response.json().await.unwrap();
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let job: Value = get_job(&app, &created).await.json().await.unwrap();
+    assert_eq!(job["status"], "running");
+    assert_eq!(job["progress"]["stage"], "loading");
+
+    let response = cancel(&app, &created).await;
+    assert_eq!(200, response.status().as_u16());
+    let job: Value = response.json().await.unwrap();
+    assert_eq!(job["status"], "cancelled");
+
+    // Cancelling is idempotent, and the job stays cancelled
+    assert_eq!(200, cancel(&app, &created).await.status().as_u16());
+    let job: Value = get_job(&app, &created).await.json().await.unwrap();
+    assert_eq!(job["status"], "cancelled");
+}
+
+#[tokio::test]
+async fn unknown_and_finished_jobs() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    let missing = json!({ "id": "missing", "token": "token" });
+    let response = get_job(&app, &missing).await;
+    assert_eq!(404, response.status().as_u16());
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "job_not_found");
+    assert_eq!(404, cancel(&app, &missing).await.status().as_u16());
+
+    let response = submit(&app, json!({ "path": "/unsafe/song.mp3?format=ogg" })).await;
+    let job: Value = response.json().await.unwrap();
+    finished(&app, &job).await;
+    assert_eq!(409, cancel(&app, &job).await.status().as_u16());
+}
+
+#[tokio::test]
+async fn jobs_for_keys_escaping_storage_are_rejected() {
+    let app = spawn_app_with(|_| {}).await;
+
+    for body in [
+        json!({ "path": "/unsafe/..%2F..%2Fsecret.mp3" }),
+        json!({ "params": { "key": "../../secret.mp3" } }),
+    ] {
+        let response = submit(&app, body).await;
+        assert_eq!(400, response.status().as_u16());
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "invalid_params");
+    }
+}
+
+#[tokio::test]
+async fn jobs_need_their_token() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    let response = submit(&app, json!({ "path": "/unsafe/song.mp3?format=ogg" })).await;
+    let job: Value = response.json().await.unwrap();
+    let id = job["id"].as_str().unwrap();
+
+    let without_token = json!({ "id": id });
+    let wrong_token = json!({ "id": id, "token": "0".repeat(32) });
+    for other in [&without_token, &wrong_token] {
+        let response = get_job(&app, other).await;
+        assert_eq!(401, response.status().as_u16());
+        let body: Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "invalid_job_token");
+        assert_eq!(401, cancel(&app, other).await.status().as_u16());
+    }
+
+    assert_eq!(finished(&app, &job).await["status"], "succeeded");
+}
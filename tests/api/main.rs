@@ -1,3 +1,8 @@
-
+pub mod auth;
 pub mod helpers;
 pub mod health_check;
+pub mod jobs;
+pub mod meta;
+pub mod params;
+pub mod ranges;
+pub mod result_storage;
@@ -0,0 +1,29 @@
+use tempfile::TempDir;
+
+use crate::helpers::spawn_app_with_result;
+
+#[tokio::test]
+async fn meta_is_not_served_from_the_audio_cache() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    let audio = app
+        .api_client
+        .get(format!("{}/unsafe/song.mp3?format=ogg", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(200, audio.status().as_u16());
+    assert_eq!(audio.bytes().await.unwrap().as_ref(), b"0123456789");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // The same params under /meta must not get the cached audio; the source
+    // itself was never stored
+    let meta = app
+        .api_client
+        .get(format!("{}/meta/unsafe/song.mp3?format=ogg", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+    assert_eq!(404, meta.status().as_u16());
+}
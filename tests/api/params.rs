@@ -0,0 +1,89 @@
+use crate::helpers::spawn_app;
+
+#[tokio::test]
+async fn params_returns_parsed_params() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!(
+            "{}/params/unsafe/test.mp3?format=ogg&reverse=true",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["key"], "test.mp3");
+    assert_eq!(body["format"], "ogg");
+    assert_eq!(body["reverse"], true);
+}
+
+#[tokio::test]
+async fn params_returns_400_with_field_errors_for_invalid_values() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!(
+            "{}/params/unsafe/test.mp3?speed=fast",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(400, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["error"], "invalid_params");
+    assert_eq!(body["errors"][0]["key"], "speed");
+    assert_eq!(body["errors"][0]["value"], "fast");
+    assert_eq!(body["errors"][0]["allowed"], "0.5 to 100");
+}
+
+#[tokio::test]
+async fn params_accepts_path_segments() {
+    let app = spawn_app().await;
+
+    let response = app
+        .api_client
+        .get(format!(
+            "{}/params/unsafe/trim:0:10/filters:reverse():fade_in(2)/format:ogg/test.mp3",
+            &app.address
+        ))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["key"], "test.mp3");
+    assert_eq!(body["format"], "ogg");
+    assert_eq!(body["duration"], 10.0);
+    assert_eq!(body["chain"], serde_json::json!(["reverse", "fade_in:2"]));
+}
+
+#[tokio::test]
+async fn keys_escaping_storage_are_rejected() {
+    let app = spawn_app().await;
+
+    for path in [
+        "/unsafe/..%2F..%2Fsecret.mp3",
+        "/unsafe/%2Fetc%2Fsecret.mp3",
+        "/meta/unsafe/..%2Fsecret.mp3",
+    ] {
+        let response = app
+            .api_client
+            .get(format!("{}{}", &app.address, path))
+            .send()
+            .await
+            .expect("Failed to execute request");
+
+        assert_eq!(400, response.status().as_u16(), "{}", path);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["error"], "invalid_params");
+        assert_eq!(body["errors"][0]["key"], "path");
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::Cursor;
+
+use cyberpunk::cyberpunkpath::{hasher::ResultKeyer, params::Params};
+use cyberpunk::response::etag;
+use reqwest::header;
+use tempfile::TempDir;
+
+use crate::helpers::{spawn_app_with_result, TestApp};
+
+async fn get(app: &TestApp, headers: &[(header::HeaderName, &str)]) -> reqwest::Response {
+    get_path(app, "song.mp3?format=ogg", headers).await
+}
+
+async fn get_path(
+    app: &TestApp,
+    path: &str,
+    headers: &[(header::HeaderName, &str)],
+) -> reqwest::Response {
+    let mut request = app
+        .api_client
+        .get(format!("{}/unsafe/{}", &app.address, path));
+    for (name, value) in headers {
+        request = request.header(name, *value);
+    }
+    request.send().await.expect("Failed to execute request")
+}
+
+#[tokio::test]
+async fn range_requests_are_served_from_every_path() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    // The first request comes from result storage, the second from the cache
+    for _ in 0..2 {
+        let response = get(&app, &[(header::RANGE, "bytes=2-4")]).await;
+        assert_eq!(206, response.status().as_u16());
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(response.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"234");
+
+        let response = get(&app, &[(header::RANGE, "bytes=-3")]).await;
+        assert_eq!(206, response.status().as_u16());
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"789");
+
+        let response = get(&app, &[(header::RANGE, "bytes=10-")]).await;
+        assert_eq!(416, response.status().as_u16());
+        assert_eq!(response.headers()[header::CONTENT_RANGE], "bytes */10");
+
+        // Let the first full response be cached
+        let response = get(&app, &[]).await;
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!(response.bytes().await.unwrap().as_ref(), b"0123456789");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test]
+async fn matching_etag_is_not_modified() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    let response = get(&app, &[]).await;
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(
+        response.headers()[header::CACHE_CONTROL],
+        "public, max-age=3600"
+    );
+    let etag = response.headers()[header::ETAG]
+        .to_str()
+        .unwrap()
+        .to_string();
+    // Identifying the params, and the source if the loader can tell
+    let params: Params = "song.mp3?format=ogg".parse().unwrap();
+    assert_eq!(etag, self::etag(&ResultKeyer::default().key(&params), None));
+
+    let response = get(&app, &[(header::IF_NONE_MATCH, &etag)]).await;
+    assert_eq!(304, response.status().as_u16());
+    assert_eq!(response.headers()[header::ETAG], etag.as_str());
+    assert!(response.bytes().await.unwrap().is_empty());
+
+    let response = get(&app, &[(header::IF_NONE_MATCH, "\"stale\"")]).await;
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[tokio::test]
+async fn cache_hits_keep_response_headers() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    let app = spawn_app_with_result(&dirs).await;
+
+    let fresh = get(&app, &[]).await;
+    let fresh_headers = fresh.headers().clone();
+    assert_eq!(fresh.bytes().await.unwrap().as_ref(), b"0123456789");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    assert!(std::fs::read_dir(dirs[1].path()).unwrap().count() > 0);
+
+    let cached = get(&app, &[]).await;
+    for name in [
+        header::CONTENT_TYPE,
+        header::ETAG,
+        header::CACHE_CONTROL,
+        header::ACCEPT_RANGES,
+        header::CONTENT_DISPOSITION,
+    ] {
+        assert_eq!(
+            cached.headers().get(&name),
+            fresh_headers.get(&name),
+            "{}",
+            name
+        );
+    }
+    assert_eq!(cached.bytes().await.unwrap().as_ref(), b"0123456789");
+}
+
+/// A second of silence, which is processed natively without FFmpeg.
+fn silence() -> Vec<u8> {
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: 8000,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut cursor = Cursor::new(Vec::new());
+    let mut writer = hound::WavWriter::new(&mut cursor, spec).unwrap();
+    for _ in 0..8000 {
+        writer.write_sample(0i16).unwrap();
+    }
+    writer.finalize().unwrap();
+    cursor.into_inner()
+}
+
+#[tokio::test]
+async fn streamed_and_cached_audio_is_validated() {
+    let dirs = [TempDir::new().unwrap(), TempDir::new().unwrap()];
+    std::fs::write(dirs[0].path().join("silence.wav"), silence()).unwrap();
+    let app = spawn_app_with_result(&dirs).await;
+    let path = "silence.wav?format=wav&volume=0.5";
+    let params: Params = path.parse().unwrap();
+    let expected = etag(&ResultKeyer::default().key(&params), None);
+
+    // Known before the audio is processed
+    let response = get_path(&app, path, &[(header::IF_NONE_MATCH, &expected)]).await;
+    assert_eq!(304, response.status().as_u16());
+    assert_eq!(response.headers()[header::ETAG], expected.as_str());
+
+    // Streamed while it's processed
+    let streamed = get_path(&app, path, &[]).await;
+    assert_eq!(200, streamed.status().as_u16());
+    assert_eq!(streamed.headers()[header::ETAG], expected.as_str());
+    let last_modified = streamed.headers()[header::LAST_MODIFIED]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(!streamed.bytes().await.unwrap().is_empty());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    // Then cached, modified when it was cached
+    let response = get_path(&app, path, &[(header::IF_NONE_MATCH, &expected)]).await;
+    assert_eq!(304, response.status().as_u16());
+    assert_eq!(response.headers()[header::ETAG], expected.as_str());
+    assert_eq!(
+        response.headers()[header::LAST_MODIFIED],
+        last_modified.as_str()
+    );
+
+    let response = get_path(&app, path, &[(header::IF_MODIFIED_SINCE, &last_modified)]).await;
+    assert_eq!(304, response.status().as_u16());
+
+    let cached = get_path(&app, path, &[(header::IF_NONE_MATCH, "\"stale\"")]).await;
+    assert_eq!(200, cached.status().as_u16());
+    assert_eq!(cached.headers()[header::ETAG], expected.as_str());
+    assert_eq!(
+        cached.headers()[header::LAST_MODIFIED],
+        last_modified.as_str()
+    );
+}
@@ -0,0 +1,61 @@
+use cyberpunk::config::{CacheSettings, FilesystemCacheSettings, StorageSettings};
+use cyberpunk::cyberpunkpath::hasher::ResultKeyer;
+use cyberpunk::cyberpunkpath::params::Params;
+use tempfile::TempDir;
+
+use crate::helpers::spawn_app_with;
+
+/// Serves `song.mp3?format=ogg` with a processed result already in result
+/// storage under the key `keyer` picks, and checks it is returned as is.
+async fn assert_served_from_result_storage(keyer: ResultKeyer) {
+    let source_dir = TempDir::new().unwrap();
+    let result_dir = TempDir::new().unwrap();
+    let cache_dir = TempDir::new().unwrap();
+
+    let params: Params = "song.mp3?format=ogg".parse().unwrap();
+    let result_path = result_dir.path().join(keyer.key(&params));
+    std::fs::create_dir_all(result_path.parent().unwrap()).unwrap();
+    std::fs::write(&result_path, b"processed").unwrap();
+
+    let app = spawn_app_with(|c| {
+        c.storage = StorageSettings {
+            base_dir: source_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        c.result_storage = Some(StorageSettings {
+            base_dir: result_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        });
+        c.result_key = keyer;
+        c.cache = CacheSettings::Filesystem(FilesystemCacheSettings {
+            base_dir: cache_dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        });
+    })
+    .await;
+
+    let response = app
+        .api_client
+        .get(format!("{}/unsafe/song.mp3?format=ogg", &app.address))
+        .send()
+        .await
+        .expect("Failed to execute request");
+
+    assert_eq!(200, response.status().as_u16());
+    assert_eq!(response.bytes().await.unwrap().as_ref(), b"processed");
+    assert_eq!(std::fs::read_dir(source_dir.path()).unwrap().count(), 0);
+}
+
+#[tokio::test]
+async fn processed_audio_is_served_from_result_storage() {
+    assert_served_from_result_storage(ResultKeyer::Suffix).await;
+}
+
+#[tokio::test]
+async fn result_key_strategy_is_configurable() {
+    assert_served_from_result_storage(ResultKeyer::Digest).await;
+    assert_served_from_result_storage(ResultKeyer::Template(
+        "results/{source_name}/{hash}.{ext}".to_string(),
+    ))
+    .await;
+}